@@ -0,0 +1,284 @@
+use crate::database::DatabaseError;
+use crate::database::handler::HandlerError;
+use crate::database::json::JsonError;
+use crate::database::key::KeyError;
+use crate::database::page::{FileError, PAGESIZE};
+use crate::database::schema::{self, Violation};
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde_json::{Value, json};
+use thiserror::Error;
+
+// NOTE: errors are answered with a json body {"code", "message", "details"}. clients match on
+// the code, the message is for people and details hold what the error is about, like the field
+// of a unique violation. failing to read or write the file is always a 5xx, never a 404
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    Database(#[from] DatabaseError),
+
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{0}")]
+    Body(#[from] JsonRejection),
+
+    #[error("cursor is not valid for this collection")]
+    InvalidCursor,
+
+    #[error("stored records do not match the schema: {}", schema::describe(.0))]
+    SchemaConflict(Vec<Violation>),
+
+    #[error("the database could not be recovered")]
+    Unavailable,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        self.kind().0
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.kind().1
+    }
+
+    fn kind(&self) -> (StatusCode, &'static str) {
+        match self {
+            ApiError::Database(err) => database_kind(err),
+            ApiError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+            ApiError::Body(rejection) => (rejection.status(), "invalid_body"),
+            ApiError::InvalidCursor => (StatusCode::BAD_REQUEST, "invalid_cursor"),
+            ApiError::SchemaConflict(_) => (StatusCode::CONFLICT, "schema_conflict"),
+            ApiError::Unavailable => (StatusCode::SERVICE_UNAVAILABLE, "unavailable"),
+        }
+    }
+
+    // the message of the error, in terms of the request where the error doesn't say it itself
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::Database(DatabaseError::FileHandlerError(HandlerError::FileHandler(
+                FileError::BiggerBuffer(size),
+            ))) => {
+                format!("the value is too large, it takes {size} bytes and a page holds {PAGESIZE}")
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    pub fn details(&self) -> Value {
+        match self {
+            ApiError::Database(err) => database_details(err),
+            ApiError::SchemaConflict(violations) => violations_details(violations),
+            _ => Value::Null,
+        }
+    }
+
+    pub fn body(&self) -> Value {
+        json!({
+            "code": self.code(),
+            "message": self.message(),
+            "details": self.details(),
+        })
+    }
+}
+
+impl From<JsonError> for ApiError {
+    fn from(err: JsonError) -> ApiError {
+        ApiError::Database(err.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+
+        if status.is_server_error() {
+            log::error!("request failed: {self}");
+        }
+
+        (status, axum::Json(self.body())).into_response()
+    }
+}
+
+fn database_kind(err: &DatabaseError) -> (StatusCode, &'static str) {
+    match err {
+        DatabaseError::NotFound => (StatusCode::NOT_FOUND, "not_found"),
+        DatabaseError::UnknownCollection(_) => (StatusCode::NOT_FOUND, "unknown_collection"),
+        DatabaseError::UnknownIndex(_) => (StatusCode::NOT_FOUND, "unknown_index"),
+        DatabaseError::DuplicateKey => (StatusCode::CONFLICT, "duplicate_key"),
+        DatabaseError::UniqueViolation { .. } => (StatusCode::CONFLICT, "unique_violation"),
+        DatabaseError::CollectionExists(_) => (StatusCode::CONFLICT, "collection_exists"),
+        DatabaseError::IndexExists(_) => (StatusCode::CONFLICT, "index_exists"),
+        DatabaseError::InvalidData => (StatusCode::BAD_REQUEST, "invalid_data"),
+        DatabaseError::MissingKey(_) => (StatusCode::BAD_REQUEST, "missing_key"),
+        DatabaseError::WrongKeytype(_, _) => (StatusCode::BAD_REQUEST, "wrong_keytype"),
+        DatabaseError::KeyTooLong(_, _, _) => (StatusCode::BAD_REQUEST, "key_too_long"),
+        DatabaseError::KeyMismatch => (StatusCode::BAD_REQUEST, "key_mismatch"),
+        DatabaseError::Schema(_) => (StatusCode::BAD_REQUEST, "schema_violation"),
+        DatabaseError::InvalidSchema(_) => (StatusCode::BAD_REQUEST, "invalid_schema"),
+        DatabaseError::InvalidMigration(_) => (StatusCode::BAD_REQUEST, "invalid_migration"),
+        DatabaseError::InvalidCollection(_) => (StatusCode::BAD_REQUEST, "invalid_collection"),
+        DatabaseError::CatalogFull => (StatusCode::INSUFFICIENT_STORAGE, "catalog_full"),
+        DatabaseError::Json(err) => json_kind(err),
+        DatabaseError::Key(KeyError::TooLong(_)) => (StatusCode::BAD_REQUEST, "key_too_long"),
+        DatabaseError::Key(_) => (StatusCode::BAD_REQUEST, "invalid_key"),
+        DatabaseError::KeyGeneration => {
+            (StatusCode::INTERNAL_SERVER_ERROR, "key_generation_failed")
+        }
+        // a record or page that doesn't fit is a limit the client ran into, not corruption
+        DatabaseError::FileHandlerError(HandlerError::FileHandler(FileError::BiggerBuffer(_))) => {
+            (StatusCode::PAYLOAD_TOO_LARGE, "too_large")
+        }
+        DatabaseError::FileHandlerError(
+            HandlerError::Io(_) | HandlerError::FileHandler(FileError::Io(_)),
+        ) => (StatusCode::INTERNAL_SERVER_ERROR, "io_error"),
+        // anything else that goes wrong below the tree is a page that didn't parse
+        DatabaseError::FileHandlerError(_)
+        | DatabaseError::Integrity(_)
+        | DatabaseError::UnexpectedPagetype(_, _) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, "corrupted")
+        }
+    }
+}
+
+fn json_kind(err: &JsonError) -> (StatusCode, &'static str) {
+    match err {
+        JsonError::MissingKey(_) => (StatusCode::BAD_REQUEST, "missing_key"),
+        JsonError::WrongKeytype(_, _, _) => (StatusCode::BAD_REQUEST, "wrong_keytype"),
+        JsonError::KeyTooLong(_, _, _) => (StatusCode::BAD_REQUEST, "key_too_long"),
+        JsonError::KeyArity(_, _) | JsonError::InvalidKey(_) => {
+            (StatusCode::BAD_REQUEST, "invalid_key")
+        }
+        // records going out that can't be serialized
+        JsonError::Serde(_) => (StatusCode::INTERNAL_SERVER_ERROR, "serialization_failed"),
+        _ => (StatusCode::BAD_REQUEST, "invalid_json"),
+    }
+}
+
+fn database_details(err: &DatabaseError) -> Value {
+    match err {
+        DatabaseError::UnknownCollection(name) | DatabaseError::CollectionExists(name) => {
+            json!({"collection": name})
+        }
+        DatabaseError::UnknownIndex(field)
+        | DatabaseError::IndexExists(field)
+        | DatabaseError::MissingKey(field) => json!({"field": field}),
+        DatabaseError::UniqueViolation { field, value } => {
+            json!({"field": field, "value": value})
+        }
+        DatabaseError::WrongKeytype(expected, found) => json!({
+            "expected": schema::type_name(*expected),
+            "found": schema::type_name(*found),
+        }),
+        DatabaseError::KeyTooLong(field, max, length) => {
+            json!({"field": field, "max": max, "length": length})
+        }
+        DatabaseError::Schema(violations) => violations_details(violations),
+        DatabaseError::Key(KeyError::Arity(expected, found)) => {
+            json!({"expected": expected, "found": found})
+        }
+        DatabaseError::Key(KeyError::TooLong(length)) => json!({"length": length}),
+        DatabaseError::Json(err) => json_details(err),
+        DatabaseError::FileHandlerError(HandlerError::FileHandler(FileError::BiggerBuffer(
+            size,
+        ))) => json!({"size": size, "max": PAGESIZE}),
+        _ => Value::Null,
+    }
+}
+
+fn json_details(err: &JsonError) -> Value {
+    match err {
+        JsonError::NotAnObject(path, _)
+        | JsonError::Unsupported(path, _)
+        | JsonError::InvalidBinary(path)
+        | JsonError::InvalidTimestamp(path)
+        | JsonError::TooLarge(path)
+        | JsonError::TooManyFields(path, _)
+        | JsonError::MissingKey(path) => json!({"path": path}),
+        JsonError::WrongKeytype(path, expected, found) => json!({
+            "path": path,
+            "expected": schema::type_name(*expected),
+            "found": schema::type_name(*found),
+        }),
+        JsonError::KeyTooLong(path, max, length) => {
+            json!({"path": path, "max": max, "length": length})
+        }
+        JsonError::KeyArity(expected, found) => json!({"expected": expected, "found": found}),
+        JsonError::InvalidKey(key) => json!({"key": key}),
+        JsonError::Serde(_) => Value::Null,
+    }
+}
+
+fn violations_details(violations: &[Violation]) -> Value {
+    let violations: Vec<Value> = violations
+        .iter()
+        .map(|violation| json!({"field": violation.field, "problem": violation.problem}))
+        .collect();
+
+    json!({"violations": violations})
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn status() {
+        let io = DatabaseError::FileHandlerError(HandlerError::Io(io::Error::other("disk")));
+        let corrupt = DatabaseError::FileHandlerError(FileError::Pagetype(0xFF).into());
+
+        assert_eq!(
+            ApiError::from(io).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(ApiError::from(corrupt).code(), "corrupted");
+
+        let large = ApiError::from(DatabaseError::FileHandlerError(
+            FileError::BiggerBuffer(5000).into(),
+        ));
+        assert_eq!(large.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(large.body()["code"], "too_large");
+        assert_eq!(large.body()["details"], json!({"size": 5000, "max": 4096}));
+        assert!(large.message().contains("too large"));
+        assert_eq!(
+            ApiError::from(DatabaseError::NotFound).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            ApiError::from(JsonError::InvalidKey("x".to_string())).code(),
+            "invalid_key"
+        );
+    }
+
+    #[test]
+    fn body() {
+        let err = ApiError::from(DatabaseError::UniqueViolation {
+            field: "cpr".to_string(),
+            value: "0101009999".to_string(),
+        });
+
+        assert_eq!(
+            err.body(),
+            json!({
+                "code": "unique_violation",
+                "message": "another record already holds 0101009999 in the unique field cpr",
+                "details": {"field": "cpr", "value": "0101009999"},
+            })
+        );
+
+        let err = ApiError::from(JsonError::WrongKeytype(
+            "$.id".to_string(),
+            crate::database::page::KeyType::UInt64,
+            crate::database::page::KeyType::String,
+        ));
+
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            err.details(),
+            json!({"path": "$.id", "expected": "uint64", "found": "string"})
+        );
+    }
+}
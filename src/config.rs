@@ -0,0 +1,331 @@
+use crate::database::page::KeyGeneration;
+use crate::database::{KeyTypeSize, ULID_LEN};
+use crate::databasehandler;
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+// NOTE: every setting can come from a flag, the config file or the default, in that order. the
+// key settings only matter when the database file is created, an existing file keeps its keys.
+// there is no page size setting, the file format is built on pages of page::PAGESIZE bytes
+
+#[derive(Parser, Debug)]
+#[command(version, about = "a b+ tree database served over http")]
+pub struct Args {
+    #[arg(short, long, help = "toml file to read settings from")]
+    config: Option<PathBuf>,
+
+    #[arg(long, help = "address to listen on [default: localhost:8000]")]
+    bind: Option<String>,
+
+    #[arg(
+        long,
+        help = "database file, created if it doesn't exist [default: .db]"
+    )]
+    database: Option<PathBuf>,
+
+    #[arg(long, help = "name of the key field of a new database [default: id]")]
+    key_field: Option<String>,
+
+    #[arg(long, help = "uint64, timestamp or string [default: string]")]
+    key_type: Option<String>,
+
+    #[arg(long, help = "longest string key in bytes [default: 26]")]
+    key_size: Option<u16>,
+
+    #[arg(
+        long,
+        help = "autoincrement, ulid or none [default: ulid for string keys of 26 bytes or more, autoincrement for uint64, none otherwise]"
+    )]
+    generate: Option<String>,
+
+    #[arg(
+        long,
+        help = "pages kept in memory, 0 turns the cache off [default: 256]"
+    )]
+    cache_size: Option<usize>,
+
+    #[arg(
+        long,
+        help = "relaxed leaves flushing to the os, sync waits for the disk after every write [default: relaxed]"
+    )]
+    durability: Option<String>,
+
+    #[arg(
+        long,
+        help = "seconds to let running requests finish when shutting down [default: 30]"
+    )]
+    shutdown_timeout: Option<u64>,
+
+    #[arg(
+        long = "cors-origin",
+        help = "origin allowed to make requests, can be repeated [default: *]"
+    )]
+    cors_origins: Vec<String>,
+
+    #[arg(long, help = "error, warn, info, debug or trace [default: info]")]
+    log_level: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    database: Option<PathBuf>,
+    key_field: Option<String>,
+    key_type: Option<String>,
+    key_size: Option<u16>,
+    generate: Option<String>,
+    cache_size: Option<usize>,
+    durability: Option<String>,
+    shutdown_timeout: Option<u64>,
+    cors_origins: Option<Vec<String>>,
+    log_level: Option<String>,
+    index: Option<Vec<IndexConfig>>,
+}
+
+// an index the server creates when it opens the file, if the collection doesn't have it yet.
+// declared in the config file as
+// [[index]]
+// collection = "users"
+// field = "cpr"
+// unique = true
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IndexConfig {
+    pub collection: Option<String>,
+    pub field: String,
+    #[serde(default)]
+    pub unique: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    Relaxed,
+    Sync,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub bind: String,
+    pub database: PathBuf,
+    pub key_field: String,
+    pub keytype: KeyTypeSize,
+    pub generation: KeyGeneration,
+
+    // whether any key setting was given rather than defaulted, an existing file is only
+    // compared against the key settings when one was
+    pub keys_given: bool,
+    pub cache_size: usize,
+    pub durability: Durability,
+    pub shutdown_timeout: Duration,
+    pub cors_origins: Vec<String>,
+    pub log_level: LevelFilter,
+    pub indexes: Vec<IndexConfig>,
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::from_args(Args::parse())
+    }
+
+    fn from_args(args: Args) -> Result<Config, ConfigError> {
+        let file = match &args.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|err| ConfigError::Read(path.clone(), err))?;
+                toml::from_str(&text).map_err(|err| ConfigError::Parse(path.clone(), err))?
+            }
+            None => FileConfig::default(),
+        };
+
+        Config::merge(args, file)
+    }
+
+    fn merge(args: Args, file: FileConfig) -> Result<Config, ConfigError> {
+        let keys_given = args.key_field.is_some()
+            || file.key_field.is_some()
+            || args.key_type.is_some()
+            || file.key_type.is_some()
+            || args.key_size.is_some()
+            || file.key_size.is_some()
+            || args.generate.is_some()
+            || file.generate.is_some();
+
+        let key_type = args.key_type.or(file.key_type);
+        let key_size = args.key_size.or(file.key_size).unwrap_or(26);
+
+        let keytype =
+            databasehandler::keytype(key_type.as_deref().unwrap_or("string"), Some(key_size))
+                .map_err(ConfigError::Invalid)?;
+
+        let generation = match args.generate.or(file.generate).as_deref() {
+            // the default depends on what the key can hold
+            None => match keytype {
+                KeyTypeSize::String(size) if usize::from(size) >= ULID_LEN => KeyGeneration::Ulid,
+                KeyTypeSize::String(_) => KeyGeneration::None,
+                KeyTypeSize::UInt64 => KeyGeneration::AutoIncrement,
+                KeyTypeSize::Timestamp => KeyGeneration::None,
+            },
+            Some(generate) => {
+                databasehandler::generation(generate).map_err(ConfigError::Invalid)?
+            }
+        };
+
+        let durability = match args.durability.or(file.durability).as_deref() {
+            None | Some("relaxed") => Durability::Relaxed,
+            Some("sync") => Durability::Sync,
+            Some(_) => {
+                return Err(ConfigError::Invalid(
+                    "durability must be relaxed or sync".to_string(),
+                ));
+            }
+        };
+
+        let log_level = match args.log_level.or(file.log_level) {
+            Some(level) => level
+                .parse()
+                .map_err(|_| ConfigError::Invalid(format!("{level} is not a log level")))?,
+            None => LevelFilter::Info,
+        };
+
+        let cors_origins = match args.cors_origins {
+            origins if !origins.is_empty() => origins,
+            _ => file.cors_origins.unwrap_or_else(|| vec!["*".to_string()]),
+        };
+
+        Ok(Config {
+            bind: args
+                .bind
+                .or(file.bind)
+                .unwrap_or_else(|| "localhost:8000".to_string()),
+            database: args
+                .database
+                .or(file.database)
+                .unwrap_or_else(|| PathBuf::from(".db")),
+            key_field: args
+                .key_field
+                .or(file.key_field)
+                .unwrap_or_else(|| "id".to_string()),
+            keytype,
+            generation,
+            keys_given,
+            cache_size: args.cache_size.or(file.cache_size).unwrap_or(256),
+            durability,
+            shutdown_timeout: Duration::from_secs(
+                args.shutdown_timeout
+                    .or(file.shutdown_timeout)
+                    .unwrap_or(30),
+            ),
+            cors_origins,
+            log_level,
+            indexes: file.index.unwrap_or_default(),
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("couldnt read config file {path}: {1}", path = .0.display())]
+    Read(PathBuf, std::io::Error),
+
+    #[error("couldnt parse config file {path}: {1}", path = .0.display())]
+    Parse(PathBuf, toml::de::Error),
+
+    #[error("invalid config: {0}")]
+    Invalid(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge() {
+        let file: FileConfig = toml::from_str(
+            r#"
+            bind = "0.0.0.0:9000"
+            database = "users.db"
+            key_type = "uint64"
+            generate = "autoincrement"
+            durability = "sync"
+            shutdown_timeout = 5
+            cors_origins = ["https://example.com"]
+
+            [[index]]
+            field = "cpr"
+            unique = true
+
+            [[index]]
+            collection = "users"
+            field = "name"
+            "#,
+        )
+        .unwrap();
+
+        let args = Args::parse_from([
+            "btree_file",
+            "--bind",
+            "127.0.0.1:1234",
+            "--log-level",
+            "debug",
+        ]);
+
+        let config = Config::merge(args, file).unwrap();
+
+        // flags win over the file, the file over the defaults
+        assert_eq!(config.bind, "127.0.0.1:1234");
+        assert_eq!(config.database, PathBuf::from("users.db"));
+        assert_eq!(config.key_field, "id");
+        assert!(matches!(config.keytype, KeyTypeSize::UInt64));
+        assert_eq!(config.generation, KeyGeneration::AutoIncrement);
+        assert!(config.keys_given);
+        assert_eq!(config.durability, Durability::Sync);
+        assert_eq!(config.cors_origins, vec!["https://example.com"]);
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert_eq!(config.cache_size, 256);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(5));
+        assert_eq!(config.indexes.len(), 2);
+        assert!(config.indexes[0].unique && config.indexes[0].collection.is_none());
+        assert_eq!(config.indexes[1].collection.as_deref(), Some("users"));
+        assert!(!config.indexes[1].unique);
+    }
+
+    #[test]
+    fn invalid() {
+        let config = |flags: &[&str]| {
+            let args = Args::parse_from([&["btree_file"], flags].concat());
+            Config::merge(args, FileConfig::default())
+        };
+
+        assert!(!config(&[]).unwrap().keys_given);
+        assert_eq!(
+            config(&["--key-type", "uint64"]).unwrap().generation,
+            KeyGeneration::AutoIncrement
+        );
+        assert_eq!(
+            config(&["--key-type", "timestamp"]).unwrap().generation,
+            KeyGeneration::None
+        );
+        assert_eq!(
+            config(&["--key-size", "10"]).unwrap().generation,
+            KeyGeneration::None
+        );
+        assert!(Args::try_parse_from(["btree_file", "--page-size", "4096"]).is_err());
+        assert!(config(&["--durability", "sometimes"]).is_err());
+        assert!(config(&["--key-type", "float"]).is_err());
+        assert!(config(&["--generate", "sometimes"]).is_err());
+        assert_eq!(
+            config(&["--generate", "NONE"]).unwrap().generation,
+            KeyGeneration::None
+        );
+        assert!(config(&["--log-level", "loud"]).is_err());
+
+        assert!(toml::from_str::<FileConfig>("port = 80").is_err());
+        assert!(toml::from_str::<FileConfig>("page_size = 4096").is_err());
+        assert!(toml::from_str::<FileConfig>("[[index]]\nunique = true").is_err());
+    }
+}
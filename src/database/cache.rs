@@ -0,0 +1,277 @@
+use crate::database::page::PAGESIZE;
+use crate::metrics;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::Instant;
+
+// NOTE: the cache sits between a database and its file and keeps the most recently used pages,
+// the header included, in memory. writes go straight through to the file and update the pages
+// they touch, so what is cached is always what is on disk. a capacity of 0 caches nothing
+
+pub struct PageCache<T: Read + Write + Seek> {
+    inner: T,
+    position: u64,
+    capacity: usize,
+    pages: HashMap<u64, (Vec<u8>, u64)>,
+
+    // the cached blocks by when they were last used, so the one to evict is the first
+    recent: BTreeMap<u64, u64>,
+    uses: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<T: Read + Write + Seek> PageCache<T> {
+    pub fn new(inner: T, capacity: usize) -> PageCache<T> {
+        PageCache {
+            inner,
+            position: 0,
+            capacity,
+            pages: HashMap::new(),
+            recent: BTreeMap::new(),
+            uses: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn cached(&self) -> usize {
+        self.pages.len()
+    }
+
+    // reads answered from memory and reads that went to the file
+    pub fn hits(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    // copies from the page at the position, reading it in if it isn't cached
+    fn read_page(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let block = self.position / PAGESIZE;
+        let offset = (self.position % PAGESIZE) as usize;
+
+        self.uses += 1;
+
+        if self.pages.contains_key(&block) {
+            self.hits += 1;
+            metrics::CACHE_HITS.inc();
+        } else {
+            self.misses += 1;
+            metrics::CACHE_MISSES.inc();
+
+            let mut page = Vec::with_capacity(PAGESIZE as usize);
+            self.inner.seek(SeekFrom::Start(block * PAGESIZE))?;
+            (&mut self.inner).take(PAGESIZE).read_to_end(&mut page)?;
+
+            // the end of the file isn't cached, as it can still grow
+            if page.len() < PAGESIZE as usize {
+                let len = page.len().saturating_sub(offset).min(buf.len());
+                if len > 0 {
+                    buf[..len].copy_from_slice(&page[offset..offset + len]);
+                }
+                return Ok(len);
+            }
+
+            if self.pages.len() >= self.capacity {
+                self.evict();
+            }
+
+            self.pages.insert(block, (page, self.uses));
+        }
+
+        let (page, used) = self
+            .pages
+            .get_mut(&block)
+            .expect("couldnt find cached page");
+
+        self.recent.remove(used);
+        self.recent.insert(self.uses, block);
+        *used = self.uses;
+
+        let len = (page.len() - offset).min(buf.len());
+        buf[..len].copy_from_slice(&page[offset..offset + len]);
+
+        Ok(len)
+    }
+
+    // drops the page that was used the longest ago
+    fn evict(&mut self) {
+        if let Some((_, block)) = self.recent.pop_first() {
+            self.pages.remove(&block);
+        }
+    }
+}
+
+impl PageCache<File> {
+    // makes sure everything written so far has reached the disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+
+        self.inner.flush()?;
+        self.inner.sync_data()?;
+
+        metrics::FSYNC.observe(started.elapsed());
+
+        Ok(())
+    }
+}
+
+impl<T: Read + Write + Seek> Read for PageCache<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = if self.capacity == 0 {
+            self.misses += 1;
+            metrics::CACHE_MISSES.inc();
+            self.inner.seek(SeekFrom::Start(self.position))?;
+            self.inner.read(buf)?
+        } else {
+            self.read_page(buf)?
+        };
+
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<T: Read + Write + Seek> Write for PageCache<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.seek(SeekFrom::Start(self.position))?;
+        let len = self.inner.write(buf)?;
+
+        let start = self.position;
+        let end = start + len as u64;
+
+        // only the cached pages the write lands in are updated
+        for block in start / PAGESIZE..end.div_ceil(PAGESIZE) {
+            let Some((page, _)) = self.pages.get_mut(&block) else {
+                continue;
+            };
+
+            let page_start = block * PAGESIZE;
+            let page_end = page_start + PAGESIZE;
+
+            let from = start.max(page_start);
+            let to = end.min(page_end);
+
+            page[(from - page_start) as usize..(to - page_start) as usize]
+                .copy_from_slice(&buf[(from - start) as usize..(to - start) as usize]);
+        }
+
+        self.position = end;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Read + Write + Seek> Seek for PageCache<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(position) => position,
+            SeekFrom::Current(offset) => self
+                .position
+                .checked_add_signed(offset)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?,
+            SeekFrom::End(_) => self.inner.seek(pos)?,
+        };
+
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{Database, DatabaseBuilder, KeyTypeSize};
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn cache() {
+        // a cache smaller than the tree keeps evicting pages
+        let mut db = DatabaseBuilder::new(PageCache::new(Cursor::new(Vec::new()), 4))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        for id in 0..200_u64 {
+            db.insert_json(&json!({"id": id, "name": format!("record {id}")}))
+                .unwrap();
+        }
+
+        for id in (0..200_u64).step_by(3) {
+            db.update_json(&json!({"id": id, "name": "updated"}))
+                .unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+        assert!(db.source.cached() <= 4);
+
+        let (hits, misses) = db.source.hits();
+        assert!(hits > 0 && misses > 0);
+
+        // what is on disk is the same as what was read through the cache
+        let cached = serde_json::to_value(db.range_json(None, None, 1000).unwrap()).unwrap();
+
+        let mut db = Database::open(db.source.into_inner()).unwrap();
+        assert!(db.check().unwrap().is_clean());
+
+        assert_eq!(
+            serde_json::to_value(db.range_json(None, None, 1000).unwrap()).unwrap(),
+            cached
+        );
+    }
+
+    #[test]
+    fn evict() {
+        let mut cache = PageCache::new(Cursor::new(vec![0x01; 4 * PAGESIZE as usize]), 2);
+        let mut buf = [0x00; 1];
+
+        for block in [0, 1, 0, 2] {
+            cache.seek(SeekFrom::Start(block * PAGESIZE)).unwrap();
+            cache.read_exact(&mut buf).unwrap();
+        }
+
+        // the page used the longest ago goes first
+        assert!(cache.pages.contains_key(&0) && cache.pages.contains_key(&2));
+        assert_eq!(cache.recent.len(), 2);
+        assert_eq!(cache.hits(), (1, 3));
+
+        // a write across two pages updates the cached one
+        cache.seek(SeekFrom::Start(PAGESIZE - 1)).unwrap();
+        cache.write_all(&[0x02; 2]).unwrap();
+        cache.seek(SeekFrom::Start(PAGESIZE - 1)).unwrap();
+
+        let mut buf = [0x00; 2];
+        cache.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0x02, 0x02]);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut cache = PageCache::new(Cursor::new(vec![0x01; 3 * PAGESIZE as usize]), 0);
+
+        cache.seek(SeekFrom::Start(PAGESIZE - 2)).unwrap();
+        cache.write_all(&[0x02; 4]).unwrap();
+
+        let mut buf = [0x00; 6];
+        cache.seek(SeekFrom::Start(PAGESIZE - 3)).unwrap();
+        cache.read_exact(&mut buf).unwrap();
+
+        assert_eq!(buf, [0x01, 0x02, 0x02, 0x02, 0x02, 0x01]);
+        assert!(cache.pages.is_empty());
+        assert_eq!(cache.hits().0, 0);
+    }
+}
@@ -1,22 +1,23 @@
 use crate::database::page::*;
+use crate::metrics;
 use std::io::SeekFrom;
 use std::io::{Read, Seek, Write};
 use thiserror::Error;
 
-const PAGESIZE: u64 = 4096;
 type Id = u64;
 
 pub trait PageHandlerFuncs<T: Write + Read + Seek> {
     fn new_page(source: &mut T, pagetype: PageType) -> Result<Page, HandlerError>;
     fn get_page(source: &mut T, id: Id) -> Result<Page, HandlerError>;
     fn write(source: &mut T, page: Page) -> Result<(), HandlerError>;
-    fn split(source: &mut T, id: Id) -> Result<Page, HandlerError>;
+    fn split(source: &mut T, page: &mut Page) -> Result<(Vec<u8>, Page), HandlerError>;
+    fn free(source: &mut T, id: Id) -> Result<(), HandlerError>;
 }
 
 pub struct PageHandler;
 impl<T: Write + Read + Seek> PageHandlerFuncs<T> for PageHandler {
     fn new_page(source: &mut T, pagetype: PageType) -> Result<Page, HandlerError> {
-        let id = FileHandler::new_page(source)?;
+        let id = allocate(source)?;
 
         let page = Page { id, pagetype };
 
@@ -26,10 +27,6 @@ impl<T: Write + Read + Seek> PageHandlerFuncs<T> for PageHandler {
 
         FileHandler::write_page(source, id, &buf)?;
 
-        let mut new_header = HeaderHandler::get(source)?;
-        new_header.elements += 1;
-        HeaderHandler::write(source, new_header)?;
-
         Ok(page)
     }
 
@@ -43,15 +40,57 @@ impl<T: Write + Read + Seek> PageHandlerFuncs<T> for PageHandler {
         Ok(())
     }
 
-    fn split(source: &mut T, id: Id) -> Result<Page, HandlerError> {
-        let mut page = Self::get_page(source, id)?;
+    fn split(source: &mut T, page: &mut Page) -> Result<(Vec<u8>, Page), HandlerError> {
+        let new_page_id = allocate(source)?;
+
+        let (separator, split_page) = page.split(new_page_id)?;
+        metrics::SPLITS.inc();
+
+        Self::write(source, page.clone())?;
+        Self::write(source, split_page.clone())?;
+
+        Ok((separator, split_page))
+    }
+
+    // puts the page at the front of the free list, new pages are taken from there first. page 0
+    // is the catalog, which is written when the file is created and never freed, so 0 ends the
+    // list
+    fn free(source: &mut T, id: Id) -> Result<(), HandlerError> {
+        let mut header = HeaderHandler::get(source)?;
+
+        Self::write(
+            source,
+            Page {
+                id,
+                pagetype: PageType::Free(header.free),
+            },
+        )?;
+
+        header.free = id;
+        HeaderHandler::write(source, header)?;
+
+        Ok(())
+    }
+}
+
+// reuses a page from the free list, or grows the file by one page if it is empty
+fn allocate<T: Read + Write + Seek>(source: &mut T) -> Result<Id, HandlerError> {
+    let mut header = HeaderHandler::get(source)?;
 
-        let new_page_id = FileHandler::new_page(source)?;
+    if header.free == 0 {
+        return Ok(FileHandler::new_page(source)?);
+    }
 
-        let split_page = page.split(new_page_id)?;
+    let id = header.free;
 
-        Ok(split_page)
+    match PageHandler::get_page(source, id)?.pagetype {
+        PageType::Free(next) => header.free = next,
+        _ => return Err(FileError::Pagetype(0x04).into()),
     }
+
+    HeaderHandler::write(source, header)?;
+
+    Ok(id)
 }
 
 pub trait HeaderHandlerFuncs<T: Write + Read + Seek> {
@@ -91,6 +130,7 @@ impl<T: Write + Read + Seek> FileHandlerFuncs<T> for FileHandler {
     fn new_page(source: &mut T) -> Result<Id, FileError> {
         let id = source.seek(SeekFrom::End(0))?;
         let id_write = source.write(&[0x00; PAGESIZE as usize])?;
+        metrics::PAGE_WRITES.inc();
         if id_write != PAGESIZE as usize {
             return Err(FileError::WriteBytesExact(id_write));
         }
@@ -105,6 +145,7 @@ impl<T: Write + Read + Seek> FileHandlerFuncs<T> for FileHandler {
         let pos = PAGESIZE + (PAGESIZE * id);
         source.seek(SeekFrom::Start(pos))?;
         source.write_all(buf)?;
+        metrics::PAGE_WRITES.inc();
         Ok(())
     }
 
@@ -114,6 +155,7 @@ impl<T: Write + Read + Seek> FileHandlerFuncs<T> for FileHandler {
         }
         source.rewind()?;
         source.write_all(buf)?;
+        metrics::PAGE_WRITES.inc();
         Ok(())
     }
 
@@ -122,6 +164,7 @@ impl<T: Write + Read + Seek> FileHandlerFuncs<T> for FileHandler {
         let mut buf: [u8; PAGESIZE as usize] = [0x00; PAGESIZE as usize];
         source.seek(SeekFrom::Start(pos))?;
         source.read_exact(&mut buf)?;
+        metrics::PAGE_READS.inc();
         Ok(buf.to_vec())
     }
 
@@ -129,6 +172,7 @@ impl<T: Write + Read + Seek> FileHandlerFuncs<T> for FileHandler {
         let mut buf: [u8; PAGESIZE as usize] = [0x00; PAGESIZE as usize];
         source.rewind()?;
         source.read_exact(&mut buf)?;
+        metrics::PAGE_READS.inc();
         Ok(buf.to_vec())
     }
 }
@@ -231,12 +275,8 @@ mod test {
             let _ = HeaderHandler::write(
                 &mut file,
                 Header {
-                    root: 0,
-                    order: 4,
-                    key: b"test".to_vec(),
-                    keytype: KeyType::UInt64,
-                    elements: 0,
-                    keytype_size: 8,
+                    catalog: 0,
+                    free: 0,
                 },
             );
 
@@ -262,12 +302,8 @@ mod test {
             let _ = HeaderHandler::write(
                 &mut file,
                 Header {
-                    root: 0,
-                    order: 4,
-                    key: b"test".to_vec(),
-                    keytype: KeyType::UInt64,
-                    elements: 0,
-                    keytype_size: 8,
+                    catalog: 0,
+                    free: 0,
                 },
             );
 
@@ -296,12 +332,8 @@ mod test {
             let _ = HeaderHandler::write(
                 &mut file,
                 Header {
-                    root: 0,
-                    order: 4,
-                    key: b"test".to_vec(),
-                    keytype: KeyType::UInt64,
-                    elements: 0,
-                    keytype_size: 8,
+                    catalog: 0,
+                    free: 0,
                 },
             );
 
@@ -323,6 +355,37 @@ mod test {
             }
         }
 
+        #[test]
+        fn free_page() {
+            let mut file = init_file(2);
+            let _ = HeaderHandler::write(
+                &mut file,
+                Header {
+                    catalog: 0,
+                    free: 0,
+                },
+            );
+
+            let first = PageHandler::new_page(&mut file, PageType::Free(0)).unwrap();
+            let second = PageHandler::new_page(&mut file, PageType::Free(0)).unwrap();
+
+            PageHandler::free(&mut file, first.id).unwrap();
+            PageHandler::free(&mut file, second.id).unwrap();
+
+            assert_eq!(HeaderHandler::get(&mut file).unwrap().free, second.id);
+
+            let leaf = PageType::Leaf(Leaf::new(KeyType::UInt64));
+            assert_eq!(
+                PageHandler::new_page(&mut file, leaf.clone()).unwrap().id,
+                second.id
+            );
+            assert_eq!(
+                PageHandler::new_page(&mut file, leaf.clone()).unwrap().id,
+                first.id
+            );
+            assert_eq!(PageHandler::new_page(&mut file, leaf).unwrap().id, 3);
+        }
+
         #[test]
         fn write() {}
     }
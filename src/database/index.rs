@@ -0,0 +1,476 @@
+use crate::database::key::{self, KeyError, MAX_KEY_LEN};
+use crate::database::page::*;
+use crate::database::tree::Tree;
+use crate::database::{DEFAULT_ORDER, Database, DatabaseError};
+use std::collections::HashSet;
+use std::io::{Read, Seek, Write};
+use std::ops::Bound;
+
+// NOTE: an index key is the type tag and encoding of the field value followed by the primary
+// key, so records sharing a value sit next to each other ordered by their key. records without
+// the field, or where it holds a type that can't be encoded as a key, are left out of the index
+
+impl<T: Read + Write + Seek> Database<T> {
+    // indexes a field by building a tree over the records already stored. a unique index can't
+    // be created while records share a value
+    pub fn create_index(&mut self, field: &[u8], unique: bool) -> Result<(), DatabaseError> {
+        let collection = self.definition()?;
+
+        if collection.indexes.iter().any(|index| index.field == field) {
+            return Err(DatabaseError::IndexExists(
+                String::from_utf8_lossy(field).to_string(),
+            ));
+        }
+
+        let records = Tree::new(collection.root, self.keytype, self.order).scan(
+            &mut self.source,
+            None,
+            None,
+        )?;
+
+        let mut keys = Vec::new();
+
+        for (primary, pointer) in records {
+            let data = self.read_data(pointer)?;
+
+            if let Some(key) = index_key(field, &primary, &data)? {
+                keys.push((key, data));
+            }
+        }
+
+        if unique {
+            keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            for pair in keys.windows(2) {
+                if value(&pair[0].0) == value(&pair[1].0) {
+                    return Err(violation(field, &pair[1].1));
+                }
+            }
+        }
+
+        let mut tree = Tree::create(&mut self.source, KeyType::Bytes, DEFAULT_ORDER)?;
+
+        for (key, _) in keys {
+            tree.insert(&mut self.source, key, 0)?;
+        }
+
+        self.update_definition(|collection| {
+            collection.indexes.push(Index {
+                field: field.to_vec(),
+                root: tree.root,
+                unique,
+            })
+        })
+    }
+
+    // returns every record where the indexed field holds the value, in key order
+    pub fn find_by(&mut self, field: &[u8], value: &Field) -> Result<Vec<Data>, DatabaseError> {
+        self.find_range(field, Bound::Included(value), Bound::Included(value))
+    }
+
+    // returns every record where the indexed field lies between the two values. integers fall
+    // in the same range whatever their sign, values of other different types never do
+    pub fn find_range(
+        &mut self,
+        field: &[u8],
+        from: Bound<&Field>,
+        to: Bound<&Field>,
+    ) -> Result<Vec<Data>, DatabaseError> {
+        let encode = |value: &Field| key::encode_value(value.get_datatype(), &value.data);
+
+        let start = match from {
+            Bound::Included(value) => Some(encode(value)?),
+            Bound::Excluded(value) => match key::successor(&encode(value)?) {
+                Some(successor) => Some(successor),
+                None => return Ok(Vec::new()),
+            },
+            Bound::Unbounded => None,
+        };
+
+        let end = match to {
+            Bound::Included(value) => key::successor(&encode(value)?),
+            Bound::Excluded(value) => Some(encode(value)?),
+            Bound::Unbounded => None,
+        };
+
+        let tree = self.index(field)?;
+        let entries = tree.scan(&mut self.source, start.as_deref(), end.as_deref())?;
+
+        let mut found = Vec::new();
+
+        for (key, _) in entries {
+            let Some(primary) = key::value_len(&key).map(|len| &key[len..]) else {
+                return Err(DatabaseError::Integrity(
+                    "index key does not start with a value".to_string(),
+                ));
+            };
+
+            match self.get_encoded(primary)? {
+                Some(data) => found.push(data),
+                None => {
+                    return Err(DatabaseError::Integrity(format!(
+                        "index on {} points to a missing record",
+                        String::from_utf8_lossy(field)
+                    )));
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    // the indexes of the selected collection
+    pub fn indexes(&mut self) -> Result<Vec<Index>, DatabaseError> {
+        Ok(self.definition()?.indexes)
+    }
+
+    fn index(&mut self, field: &[u8]) -> Result<Tree, DatabaseError> {
+        match self
+            .definition()?
+            .indexes
+            .into_iter()
+            .find(|index| index.field == field)
+        {
+            Some(index) => Ok(Tree::new(index.root, KeyType::Bytes, DEFAULT_ORDER)),
+            None => Err(DatabaseError::UnknownIndex(
+                String::from_utf8_lossy(field).to_string(),
+            )),
+        }
+    }
+
+    // the key a record has in each index, by the position of the index in the collection
+    pub(super) fn index_entries(
+        &mut self,
+        primary: &[u8],
+        data: &Data,
+    ) -> Result<Vec<(usize, Vec<u8>)>, DatabaseError> {
+        let mut entries = Vec::new();
+
+        for (idx, index) in self.definition()?.indexes.iter().enumerate() {
+            if let Some(key) = index_key(&index.field, primary, data)? {
+                entries.push((idx, key));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // fails if a unique index already holds the value of an entry for a different record
+    pub(super) fn check_unique(
+        &mut self,
+        entries: &[(usize, Vec<u8>)],
+        data: &Data,
+    ) -> Result<(), DatabaseError> {
+        let indexes = self.definition()?.indexes;
+
+        for (idx, key) in entries {
+            let index = &indexes[*idx];
+
+            if !index.unique {
+                continue;
+            }
+
+            let prefix = value(key);
+            let tree = Tree::new(index.root, KeyType::Bytes, DEFAULT_ORDER);
+
+            let holders = tree.scan(
+                &mut self.source,
+                Some(prefix),
+                key::successor(prefix).as_deref(),
+            )?;
+
+            if holders.iter().any(|(holder, _)| holder != key) {
+                return Err(violation(&index.field, data));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn index_insert(
+        &mut self,
+        entries: Vec<(usize, Vec<u8>)>,
+    ) -> Result<(), DatabaseError> {
+        for (idx, key) in entries {
+            let root = self.definition()?.indexes[idx].root;
+            let mut tree = Tree::new(root, KeyType::Bytes, DEFAULT_ORDER);
+
+            // an entry that is already there is left as it is, which undoing an update that
+            // failed halfway through its index entries relies on
+            match tree.insert(&mut self.source, key, 0) {
+                Ok(()) | Err(DatabaseError::DuplicateKey) => {}
+                Err(err) => return Err(err),
+            }
+
+            self.update_definition(|collection| collection.indexes[idx].root = tree.root)?;
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn index_remove(
+        &mut self,
+        entries: Vec<(usize, Vec<u8>)>,
+    ) -> Result<(), DatabaseError> {
+        for (idx, key) in entries {
+            let root = self.definition()?.indexes[idx].root;
+            let mut tree = Tree::new(root, KeyType::Bytes, DEFAULT_ORDER);
+
+            tree.remove(&mut self.source, &key)?;
+
+            self.update_definition(|collection| collection.indexes[idx].root = tree.root)?;
+        }
+
+        Ok(())
+    }
+
+    // every index has to hold exactly the keys its records should have in it
+    pub(super) fn check_indexes(
+        &mut self,
+        indexes: &[Index],
+        records: &[(Vec<u8>, Data)],
+        visited: &mut HashSet<u64>,
+    ) -> Result<(), DatabaseError> {
+        for index in indexes {
+            let tree = Tree::new(index.root, KeyType::Bytes, DEFAULT_ORDER);
+
+            let found: Vec<Vec<u8>> = tree
+                .check(&mut self.source, visited)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+
+            let mut expected = Vec::new();
+
+            for (primary, data) in records {
+                if let Some(key) = index_key(&index.field, primary, data)? {
+                    expected.push(key);
+                }
+            }
+
+            expected.sort();
+
+            if found != expected {
+                return Err(DatabaseError::Integrity(format!(
+                    "index on {} holds {} keys, expected {}",
+                    String::from_utf8_lossy(&index.field),
+                    found.len(),
+                    expected.len()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// the value part of an index key, without the primary key behind it
+fn value(key: &[u8]) -> &[u8] {
+    &key[..key::value_len(key).unwrap_or(key.len())]
+}
+
+fn violation(field: &[u8], data: &Data) -> DatabaseError {
+    DatabaseError::UniqueViolation {
+        field: String::from_utf8_lossy(field).to_string(),
+        value: data
+            .get_field(field)
+            .map(|value| value.get_data())
+            .unwrap_or_default(),
+    }
+}
+
+fn index_key(field: &[u8], primary: &[u8], data: &Data) -> Result<Option<Vec<u8>>, DatabaseError> {
+    let Some(value) = data.get_field(field) else {
+        return Ok(None);
+    };
+
+    let mut key = match key::encode_value(value.get_datatype(), &value.data) {
+        Ok(key) => key,
+        Err(KeyError::Unsupported(_)) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    key.extend(primary);
+
+    if key.len() > MAX_KEY_LEN {
+        return Err(KeyError::TooLong(key.len()).into());
+    }
+
+    Ok(Some(key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{DatabaseBuilder, KeyTypeSize};
+    use std::io::Cursor;
+
+    fn age(age: u64) -> Field {
+        Field::new(Vec::new(), KeyType::UInt64, age.to_le_bytes().to_vec())
+    }
+
+    fn names(found: Vec<Data>) -> Vec<String> {
+        found
+            .iter()
+            .map(|data| data.get_field(b"name").unwrap().get_data())
+            .collect()
+    }
+
+    #[test]
+    fn find_by() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build_mock_u64();
+
+        db.create_index(b"age", false).unwrap();
+        db.create_index(b"name", false).unwrap();
+        assert!(db.check().unwrap().is_clean());
+
+        assert_eq!(
+            names(db.find_by(b"age", &age(55)).unwrap()),
+            vec!["lars", "john", "hans"]
+        );
+
+        let name = Field::new(Vec::new(), KeyType::String, b"dam".to_vec());
+        assert_eq!(names(db.find_by(b"name", &name).unwrap()), vec!["dam"]);
+
+        assert_eq!(
+            names(
+                db.find_range(b"age", Bound::Excluded(&age(22)), Bound::Included(&age(55)))
+                    .unwrap()
+            ),
+            vec!["johnny", "lars", "john", "hans"]
+        );
+
+        assert!(matches!(
+            db.create_index(b"age", false),
+            Err(DatabaseError::IndexExists(_))
+        ));
+        assert!(matches!(
+            db.find_by(b"weight", &age(87)),
+            Err(DatabaseError::UnknownIndex(_))
+        ));
+    }
+
+    #[test]
+    fn sync() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        db.create_index(b"age", false).unwrap();
+
+        for id in 0..50_u64 {
+            db.insert_json(&serde_json::json!({"id": id, "age": id % 5, "name": id.to_string()}))
+                .unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+        assert_eq!(db.find_by(b"age", &age(3)).unwrap().len(), 10);
+
+        db.update_json(&serde_json::json!({"id": 3, "age": 100, "name": "3"}))
+            .unwrap();
+        db.update_json(&serde_json::json!({"id": 8, "name": "8"}))
+            .unwrap();
+
+        for id in (0..50_u64).filter(|id| id % 4 == 0) {
+            assert!(db.delete(&[&id.to_le_bytes()]).unwrap().is_some());
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        assert_eq!(
+            names(db.find_by(b"age", &age(3)).unwrap()),
+            vec!["13", "18", "23", "33", "38", "43"]
+        );
+        assert_eq!(names(db.find_by(b"age", &age(100)).unwrap()), vec!["3"]);
+    }
+
+    #[test]
+    fn signed() {
+        let mut db = DatabaseBuilder::new(Cursor::new(Vec::new()))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        db.create_index(b"temp", false).unwrap();
+
+        for (id, temp) in [(0_u64, -10_i64), (1, -5), (2, -1), (3, 0), (4, 5), (5, 10)] {
+            db.insert_json(&serde_json::json!({"id": id, "temp": temp, "name": temp.to_string()}))
+                .unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        let from = Field::new(Vec::new(), KeyType::Int64, (-5_i64).to_le_bytes().to_vec());
+
+        assert_eq!(
+            names(
+                db.find_range(b"temp", Bound::Included(&from), Bound::Included(&age(5)))
+                    .unwrap()
+            ),
+            vec!["-5", "-1", "0", "5"]
+        );
+        assert_eq!(
+            names(
+                db.find_range(b"temp", Bound::Unbounded, Bound::Excluded(&age(0)))
+                    .unwrap()
+            ),
+            vec!["-10", "-5", "-1"]
+        );
+    }
+
+    #[test]
+    fn unique() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build_mock_u64();
+
+        // the mock data shares ages, but not cprs
+        assert!(matches!(
+            db.create_index(b"age", true),
+            Err(DatabaseError::UniqueViolation { field, value }) if field == "age" && value == "55"
+        ));
+
+        db.create_index(b"cpr", true).unwrap();
+
+        let err = db
+            .insert_json(&serde_json::json!({"id": 7, "cpr": "0101009999"}))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            DatabaseError::UniqueViolation { field, value } if field == "cpr" && value == "0101009999"
+        ));
+
+        // nothing was written by the failed insert
+        assert!(db.get(&7_u64.to_le_bytes()).unwrap().is_none());
+        assert!(db.check().unwrap().is_clean());
+
+        db.insert_json(&serde_json::json!({"id": 7, "cpr": "0202029999"}))
+            .unwrap();
+
+        assert!(matches!(
+            db.update_json(&serde_json::json!({"id": 1, "cpr": "0202029999"})),
+            Err(DatabaseError::UniqueViolation { .. })
+        ));
+
+        // a record keeps its own value, and frees it once it moves on
+        db.update_json(&serde_json::json!({"id": 7, "cpr": "0202029999", "name": "a"}))
+            .unwrap();
+        db.update_json(&serde_json::json!({"id": 7, "cpr": "0303039999"}))
+            .unwrap();
+        db.update_json(&serde_json::json!({"id": 1, "cpr": "0202029999"}))
+            .unwrap();
+
+        assert!(db.check().unwrap().is_clean());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::database::page::Data;
+use crate::database::{Database, DatabaseError, record_key};
+use std::io::{Read, Seek, Write};
+
+// NOTE: the journal keeps what it takes to undo each write since begin, by the collection it
+// was made in. rolling back replays it backwards through the regular operations, so indexes and
+// element counts are undone with the records. generated keys aren't handed out again. a write
+// is journaled before it touches the file, so one that fails halfway is undone as well, and
+// undoing it copes with the parts that never happened
+
+pub enum Undo {
+    Insert(Vec<u8>, Vec<u8>),
+    Update(Vec<u8>, Data),
+    Delete(Vec<u8>, Data),
+}
+
+impl<T: Read + Write + Seek> Database<T> {
+    // starts recording writes so they can be rolled back
+    pub fn begin(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    // keeps the writes since begin
+    pub fn commit(&mut self) {
+        self.journal = None;
+    }
+
+    // undoes the writes since begin, newest first, and selects the collection selected before
+    pub fn rollback(&mut self) -> Result<(), DatabaseError> {
+        let Some(journal) = self.journal.take() else {
+            return Ok(());
+        };
+
+        let selected = self.name.clone();
+
+        for undo in journal.into_iter().rev() {
+            match undo {
+                Undo::Insert(collection, key) => {
+                    self.use_collection(&collection)?;
+                    self.delete_encoded(&key)?;
+                }
+                Undo::Update(collection, data) => {
+                    self.use_collection(&collection)?;
+                    self.update(data)?;
+                }
+                Undo::Delete(collection, data) => {
+                    self.use_collection(&collection)?;
+
+                    // a delete that failed before the record left the tree
+                    match self.get_encoded(&record_key(&self.keys, &data)?)? {
+                        Some(_) => self.update(data)?,
+                        None => self.insert(data)?,
+                    };
+                }
+            }
+        }
+
+        self.use_collection(&selected)
+    }
+
+    pub(super) fn record(&mut self, undo: Undo) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(undo);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Undo;
+    use crate::database::page::KeyGeneration;
+    use crate::database::{DatabaseBuilder, KeyTypeSize};
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn rollback() {
+        let mut db = DatabaseBuilder::new(Cursor::new(vec![0x00; 4096]))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        db.create_collection(
+            b"other",
+            vec![(b"name".to_vec(), KeyTypeSize::String(10))],
+            KeyGeneration::None,
+        )
+        .unwrap();
+        db.create_index(b"email", true).unwrap();
+
+        for id in 1..=20_u64 {
+            db.insert_json(&json!({"id": id, "email": format!("{id}@example.com")}))
+                .unwrap();
+        }
+
+        let before = db.range_json(None, None, 100).unwrap();
+
+        db.begin();
+
+        db.insert_json(&json!({"id": 21, "email": "21@example.com"}))
+            .unwrap();
+        db.update_json(&json!({"id": 5, "email": "new@example.com"}))
+            .unwrap();
+        db.delete_json(&json!(7)).unwrap();
+
+        db.use_collection(b"other").unwrap();
+        db.insert_json(&json!({"name": "a"})).unwrap();
+        db.use_collection(b"default").unwrap();
+
+        // the email of 7 is free again until the rollback
+        db.update_json(&json!({"id": 8, "email": "7@example.com"}))
+            .unwrap();
+
+        db.rollback().unwrap();
+        assert!(db.check().unwrap().is_clean());
+
+        assert_eq!(
+            serde_json::to_value(db.range_json(None, None, 100).unwrap()).unwrap(),
+            serde_json::to_value(before).unwrap()
+        );
+
+        db.use_collection(b"other").unwrap();
+        assert!(db.range_json(None, None, 100).unwrap().is_empty());
+
+        // writes after a commit stay
+        db.begin();
+        db.insert_json(&json!({"name": "b"})).unwrap();
+        db.commit();
+        db.rollback().unwrap();
+
+        assert_eq!(db.range_json(None, None, 100).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn halfway() {
+        let mut db = DatabaseBuilder::new(Cursor::new(Vec::new()))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        db.create_index(b"email", true).unwrap();
+        db.insert_json(&json!({"id": 1, "email": "1@example.com"}))
+            .unwrap();
+
+        let one = db.get_json(&json!(1)).unwrap().unwrap();
+
+        // writes journaled right before they failed, without having changed anything
+        db.begin();
+        db.record(Undo::Delete(b"default".to_vec(), one.clone()));
+        db.record(Undo::Update(b"default".to_vec(), one));
+        db.record(Undo::Insert(
+            b"default".to_vec(),
+            2_u64.to_be_bytes().to_vec(),
+        ));
+        db.rollback().unwrap();
+
+        assert!(db.check().unwrap().is_clean());
+        assert_eq!(db.range_json(None, None, 100).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,626 @@
+use crate::database::page::{
+    BINARY_TAG, Data, Field, KeyField, KeyType, MAX_FIELD_LEN, TIMESTAMP_TAG, parse_timestamp,
+};
+use base64::prelude::*;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+// converts a json object into a record, inferring the type of every field from its value
+pub fn to_data(value: &Value) -> Result<Data, JsonError> {
+    let Value::Object(object) = value else {
+        return Err(JsonError::NotAnObject("$".to_string(), kind(value)));
+    };
+
+    let object = to_fields(object, "$")?;
+
+    Ok(Data { object, version: 0 })
+}
+
+// converts a json object into a record and checks that it holds every field of the primary key
+pub fn to_record(value: &Value, keys: &[KeyField]) -> Result<Data, JsonError> {
+    let mut data = to_data(value)?;
+
+    for key in keys {
+        let path = format!("$.{}", String::from_utf8_lossy(&key.name));
+
+        if let Some(field) = data
+            .object
+            .iter_mut()
+            .find(|field| field.get_key() == key.name)
+        {
+            *field = coerce_key(field, key.keytype, &path)?;
+        }
+
+        match data.get_field(&key.name) {
+            Some(field) => check_key(&path, key, field)?,
+            None => return Err(JsonError::MissingKey(path)),
+        }
+    }
+
+    Ok(data)
+}
+
+// converts a json value used to look up records into the values of the key fields. composite
+// keys are given as an array, which may leave out trailing fields to match a prefix
+pub fn to_key(value: &Value, keys: &[KeyField]) -> Result<Vec<Vec<u8>>, JsonError> {
+    let values = match value {
+        Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+
+    if values.len() > keys.len() {
+        return Err(JsonError::KeyArity(keys.len(), values.len()));
+    }
+
+    let mut key_values = Vec::new();
+
+    for (idx, (key, value)) in keys.iter().zip(values).enumerate() {
+        let path = match keys.len() {
+            1 => "key".to_string(),
+            _ => format!("key[{idx}]"),
+        };
+
+        let field = coerce_key(&to_field(Vec::new(), value, &path)?, key.keytype, &path)?;
+
+        check_key(&path, key, &field)?;
+
+        key_values.push(field.data);
+    }
+
+    Ok(key_values)
+}
+
+// reads a key given as text. single uint64 keys are numbers, other single keys are taken as
+// they are and composite keys are written as a json array
+pub fn parse_key(key: &str, keys: &[KeyField]) -> Result<Value, JsonError> {
+    match keys {
+        [field] if field.keytype == KeyType::UInt64 => key
+            .parse::<u64>()
+            .map(Value::from)
+            .map_err(|_| JsonError::InvalidKey(key.to_string())),
+        [_] => Ok(Value::from(key)),
+        _ => match serde_json::from_str(key) {
+            Ok(value @ Value::Array(_)) => Ok(value),
+            _ => Err(JsonError::InvalidKey(key.to_string())),
+        },
+    }
+}
+
+// fills in the key fields a json object leaves out from a key given like to to_key
+pub fn with_key(value: &Value, key: &Value, keys: &[KeyField]) -> Result<Value, JsonError> {
+    let Value::Object(object) = value else {
+        return Err(JsonError::NotAnObject("$".to_string(), kind(value)));
+    };
+
+    let values = match key {
+        Value::Array(values) => values.clone(),
+        value => vec![value.clone()],
+    };
+
+    let mut object = object.clone();
+
+    for (field, value) in keys.iter().zip(values) {
+        object
+            .entry(String::from_utf8_lossy(&field.name).to_string())
+            .or_insert(value);
+    }
+
+    Ok(Value::Object(object))
+}
+
+// applies a json merge patch (rfc 7396) to a record: objects are merged key by key, null
+// removes a key and anything else replaces the value. the patch is merged into the stored
+// fields, so the ones it leaves alone keep their type
+pub fn patch_data(data: &mut Data, patch: &Value) -> Result<(), JsonError> {
+    let Value::Object(patch) = patch else {
+        return Err(JsonError::NotAnObject("$".to_string(), kind(patch)));
+    };
+
+    patch_fields(&mut data.object, patch, "$")
+}
+
+fn patch_fields(
+    fields: &mut Vec<Field>,
+    patch: &Map<String, Value>,
+    path: &str,
+) -> Result<(), JsonError> {
+    for (key, value) in patch {
+        let path = format!("{path}.{key}");
+        let position = fields
+            .iter()
+            .position(|field| field.get_key() == key.as_bytes());
+
+        let field = match value {
+            Value::Null => {
+                if let Some(idx) = position {
+                    fields.remove(idx);
+                }
+                continue;
+            }
+            // objects are merged into an object field, any other field is replaced by one
+            Value::Object(object) if !is_tagged(object) => {
+                let mut field = match position {
+                    Some(idx) if fields[idx].get_datatype() == KeyType::Object => {
+                        fields[idx].clone()
+                    }
+                    _ => Field::object(key.as_bytes().to_vec(), Vec::new()),
+                };
+
+                patch_fields(&mut field.fields, object, &path)?;
+
+                if field.len() > MAX_FIELD_LEN {
+                    return Err(JsonError::TooLarge(path));
+                }
+
+                field
+            }
+            value => to_field(key.as_bytes().to_vec(), value, &path)?,
+        };
+
+        match position {
+            Some(idx) => fields[idx] = field,
+            None => fields.push(field),
+        }
+    }
+
+    if fields.len() > u8::MAX as usize {
+        return Err(JsonError::TooManyFields(path.to_string(), fields.len()));
+    }
+
+    Ok(())
+}
+
+// binary values and timestamps are written as objects with a single tag
+fn is_tagged(object: &Map<String, Value>) -> bool {
+    object.len() == 1 && (object.contains_key(BINARY_TAG) || object.contains_key(TIMESTAMP_TAG))
+}
+
+// keys of a timestamp tree can be given as plain rfc 3339 strings
+fn coerce_key(field: &Field, keytype: KeyType, path: &str) -> Result<Field, JsonError> {
+    if keytype == KeyType::Timestamp && field.get_datatype() == KeyType::String {
+        let micros = str::from_utf8(&field.data)
+            .ok()
+            .and_then(parse_timestamp)
+            .ok_or(JsonError::InvalidTimestamp(path.to_string()))?;
+
+        return Ok(Field::timestamp(field.get_key().to_vec(), micros));
+    }
+
+    Ok(field.clone())
+}
+
+fn to_fields(object: &Map<String, Value>, path: &str) -> Result<Vec<Field>, JsonError> {
+    if object.len() > u8::MAX as usize {
+        return Err(JsonError::TooManyFields(path.to_string(), object.len()));
+    }
+
+    let mut fields = Vec::new();
+
+    for (key, value) in object {
+        let path = format!("{path}.{key}");
+
+        if key.len() > u8::MAX as usize {
+            return Err(JsonError::TooLarge(path));
+        }
+
+        fields.push(to_field(key.as_bytes().to_vec(), value, &path)?);
+    }
+
+    Ok(fields)
+}
+
+pub fn to_field(key: Vec<u8>, value: &Value, path: &str) -> Result<Field, JsonError> {
+    let field = match value {
+        Value::String(string) => Field::new(key, KeyType::String, string.as_bytes().to_vec()),
+        Value::Number(number) => {
+            if let Some(number) = number.as_u64() {
+                Field::new(key, KeyType::UInt64, number.to_le_bytes().to_vec())
+            } else if let Some(number) = number.as_i64() {
+                Field::new(key, KeyType::Int64, number.to_le_bytes().to_vec())
+            } else if let Some(number) = number.as_f64() {
+                Field::new(key, KeyType::Float64, number.to_le_bytes().to_vec())
+            } else {
+                return Err(JsonError::Unsupported(path.to_string(), "number"));
+            }
+        }
+        Value::Bool(bool) => Field::new(key, KeyType::Bool, vec![*bool as u8]),
+        Value::Null => Field::new(key, KeyType::Null, Vec::new()),
+        Value::Object(object) if object.len() == 1 && object.contains_key(BINARY_TAG) => {
+            match &object[BINARY_TAG] {
+                Value::String(encoded) => match BASE64_STANDARD.decode(encoded) {
+                    Ok(bytes) => Field::new(key, KeyType::Bytes, bytes),
+                    Err(_) => return Err(JsonError::InvalidBinary(format!("{path}.{BINARY_TAG}"))),
+                },
+                _ => return Err(JsonError::InvalidBinary(format!("{path}.{BINARY_TAG}"))),
+            }
+        }
+        Value::Object(object) if object.len() == 1 && object.contains_key(TIMESTAMP_TAG) => {
+            match object[TIMESTAMP_TAG].as_str().and_then(parse_timestamp) {
+                Some(micros) => Field::timestamp(key, micros),
+                None => {
+                    return Err(JsonError::InvalidTimestamp(format!(
+                        "{path}.{TIMESTAMP_TAG}"
+                    )));
+                }
+            }
+        }
+        Value::Object(object) => Field::object(key, to_fields(object, path)?),
+        Value::Array(array) => {
+            if array.len() > u8::MAX as usize {
+                return Err(JsonError::TooManyFields(path.to_string(), array.len()));
+            }
+
+            let elements: Result<Vec<Field>, JsonError> = array
+                .iter()
+                .enumerate()
+                .map(|(idx, value)| to_field(Vec::new(), value, &format!("{path}[{idx}]")))
+                .collect();
+
+            Field::array(key, elements?)
+        }
+    };
+
+    if field.len() > MAX_FIELD_LEN {
+        return Err(JsonError::TooLarge(path.to_string()));
+    }
+
+    Ok(field)
+}
+
+fn check_key(path: &str, key: &KeyField, field: &Field) -> Result<(), JsonError> {
+    if field.get_datatype() != key.keytype {
+        return Err(JsonError::WrongKeytype(
+            path.to_string(),
+            key.keytype,
+            field.get_datatype(),
+        ));
+    }
+
+    if key.keytype == KeyType::String && field.data.len() > key.size as usize {
+        return Err(JsonError::KeyTooLong(
+            path.to_string(),
+            key.size,
+            field.data.len(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum JsonError {
+    #[error("expected an object at {0}, found {1}")]
+    NotAnObject(String, &'static str),
+
+    #[error("unsupported value at {0}: {1}")]
+    Unsupported(String, &'static str),
+
+    #[error("expected a base64 string at {0}")]
+    InvalidBinary(String),
+
+    #[error("expected an rfc 3339 timestamp at {0}")]
+    InvalidTimestamp(String),
+
+    #[error("value at {0} is too large to store")]
+    TooLarge(String),
+
+    #[error("object at {0} has too many fields ({1})")]
+    TooManyFields(String, usize),
+
+    #[error("missing key field {0}")]
+    MissingKey(String),
+
+    #[error("expected key of type {1:?} at {0}, found {2:?}")]
+    WrongKeytype(String, KeyType, KeyType),
+
+    #[error("key at {0} is longer than {1} bytes ({2})")]
+    KeyTooLong(String, u16, usize),
+
+    #[error("expected at most {0} key values, found {1}")]
+    KeyArity(usize, usize),
+
+    #[error("{0} is not a valid key")]
+    InvalidKey(String),
+
+    #[error("{0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn data() {
+        let data = to_data(&json!({"id": "A", "age": 22})).unwrap();
+
+        let id = data.get_field(b"id").unwrap();
+        assert_eq!(id.get_datatype(), KeyType::String);
+        assert_eq!(id.get_data(), "A");
+
+        let age = data.get_field(b"age").unwrap();
+        assert_eq!(age.get_datatype(), KeyType::UInt64);
+        assert_eq!(age.get_data(), "22");
+    }
+
+    #[test]
+    fn types() {
+        let data = to_data(&json!({
+            "balance": -12,
+            "price": 9.5,
+            "admin": true,
+            "deleted": null,
+            "avatar": {"$binary": "AAEC"},
+        }))
+        .unwrap();
+
+        let balance = data.get_field(b"balance").unwrap();
+        assert_eq!(balance.get_datatype(), KeyType::Int64);
+        assert_eq!(balance.get_data(), "-12");
+
+        let price = data.get_field(b"price").unwrap();
+        assert_eq!(price.get_datatype(), KeyType::Float64);
+        assert_eq!(price.get_data(), "9.5");
+
+        let admin = data.get_field(b"admin").unwrap();
+        assert_eq!(admin.get_datatype(), KeyType::Bool);
+        assert_eq!(admin.get_data(), "true");
+
+        let deleted = data.get_field(b"deleted").unwrap();
+        assert_eq!(deleted.get_datatype(), KeyType::Null);
+
+        let avatar = data.get_field(b"avatar").unwrap();
+        assert_eq!(avatar.get_datatype(), KeyType::Bytes);
+        assert_eq!(avatar.data, vec![0x00, 0x01, 0x02]);
+
+        assert!(data.is_valid());
+
+        let roundtrip: Value = serde_json::from_str(&data.json().unwrap()).unwrap();
+        assert_eq!(roundtrip["avatar"], json!({"$binary": "AAEC"}));
+        assert_eq!(roundtrip["balance"], json!(-12));
+    }
+
+    #[test]
+    fn errors() {
+        let err = to_data(&json!(["A"])).unwrap_err();
+        assert_eq!(err.to_string(), "expected an object at $, found array");
+
+        let err = to_data(&json!({"id": "A", "avatar": {"$binary": 1}})).unwrap_err();
+        assert!(matches!(err, JsonError::InvalidBinary(path) if path == "$.avatar.$binary"));
+
+        let err = to_data(&json!({"id": "A", "address": {"city": {"$binary": "?"}}})).unwrap_err();
+        assert!(matches!(err, JsonError::InvalidBinary(path) if path == "$.address.city.$binary"));
+
+        let err = to_data(&json!({"id": "A", "tags": ["a", "b".repeat(5000)]})).unwrap_err();
+        assert!(matches!(err, JsonError::TooLarge(path) if path == "$.tags[1]"));
+
+        let err = to_data(&json!({"name": "a".repeat(5000)})).unwrap_err();
+        assert!(matches!(err, JsonError::TooLarge(path) if path == "$.name"));
+    }
+
+    #[test]
+    fn timestamp() {
+        let data =
+            to_data(&json!({"created": {"$timestamp": "2024-03-01T12:30:00.5+01:00"}})).unwrap();
+
+        let created = data.get_field(b"created").unwrap();
+        assert_eq!(created.get_datatype(), KeyType::Timestamp);
+        assert_eq!(created.get_timestamp(), Some(1_709_292_600_500_000));
+        assert_eq!(created.get_data(), "2024-03-01T11:30:00.500Z");
+
+        let roundtrip: Value = serde_json::from_str(&data.json().unwrap()).unwrap();
+        assert_eq!(
+            roundtrip["created"],
+            json!({"$timestamp": "2024-03-01T11:30:00.500Z"})
+        );
+
+        // what is written comes back as a timestamp
+        let reread: Data = serde_json::from_str(&data.json().unwrap()).unwrap();
+        assert_eq!(
+            reread.get_field(b"created").unwrap().get_timestamp(),
+            Some(1_709_292_600_500_000)
+        );
+
+        let err = to_data(&json!({"created": {"$timestamp": "yesterday"}})).unwrap_err();
+        assert!(matches!(err, JsonError::InvalidTimestamp(path) if path == "$.created.$timestamp"));
+
+        // timestamp keys accept plain strings and sort chronologically
+        let before = to_key(
+            &json!("1969-12-31T23:59:59Z"),
+            &keys(b"at", KeyType::Timestamp, 8),
+        )
+        .unwrap();
+        let epoch = to_key(
+            &json!("1970-01-01T00:00:00Z"),
+            &keys(b"at", KeyType::Timestamp, 8),
+        )
+        .unwrap();
+        let after = to_key(
+            &json!("2024-01-01T00:00:00Z"),
+            &keys(b"at", KeyType::Timestamp, 8),
+        )
+        .unwrap();
+        assert!(before < epoch && epoch < after);
+
+        let record = to_record(
+            &json!({"at": "2024-01-01T00:00:00Z"}),
+            &keys(b"at", KeyType::Timestamp, 8),
+        )
+        .unwrap();
+        assert_eq!(record.get_field(b"at").unwrap().data, after[0]);
+    }
+
+    #[test]
+    fn nested() {
+        let value = json!({
+            "id": "A",
+            "address": {"street": "vej 1", "city": "aarhus"},
+            "tags": ["a", "b", {"nested": true}],
+        });
+
+        let data = to_data(&value).unwrap();
+
+        assert!(data.is_valid());
+        assert_eq!(
+            data.get_field(b"address.city").unwrap().get_data(),
+            "aarhus"
+        );
+        assert_eq!(data.get_field(b"tags.1").unwrap().get_data(), "b");
+        assert_eq!(data.get_field(b"tags.2.nested").unwrap().get_data(), "true");
+        assert!(data.get_field(b"tags.3").is_none());
+        assert!(data.get_field(b"address.zip").is_none());
+
+        let roundtrip: Value = serde_json::from_str(&data.json().unwrap()).unwrap();
+        assert_eq!(roundtrip, value);
+    }
+
+    #[test]
+    fn record() {
+        let record = to_record(&json!({"id": "A"}), &keys(b"id", KeyType::String, 10));
+        assert!(record.is_ok());
+
+        let err = to_record(&json!({"name": "A"}), &keys(b"id", KeyType::String, 10)).unwrap_err();
+        assert!(matches!(err, JsonError::MissingKey(path) if path == "$.id"));
+
+        let err = to_record(&json!({"id": 1}), &keys(b"id", KeyType::String, 10)).unwrap_err();
+        assert!(matches!(
+            err,
+            JsonError::WrongKeytype(_, KeyType::String, KeyType::UInt64)
+        ));
+
+        let err = to_record(
+            &json!({"id": "ABCDEFGHIJK"}),
+            &keys(b"id", KeyType::String, 10),
+        )
+        .unwrap_err();
+        assert!(matches!(err, JsonError::KeyTooLong(_, 10, 11)));
+    }
+
+    #[test]
+    fn key() {
+        assert_eq!(
+            to_key(&json!(5), &keys(b"id", KeyType::UInt64, 8)).unwrap(),
+            vec![5_u64.to_le_bytes().to_vec()]
+        );
+        assert_eq!(
+            to_key(&json!("A"), &keys(b"id", KeyType::String, 10)).unwrap(),
+            vec![b"A".to_vec()]
+        );
+        assert!(to_key(&json!(-5), &keys(b"id", KeyType::UInt64, 8)).is_err());
+        assert!(to_key(&json!("A"), &keys(b"id", KeyType::UInt64, 8)).is_err());
+
+        let mut composite = keys(b"tenant", KeyType::UInt64, 8);
+        composite.extend(keys(b"record", KeyType::String, 10));
+
+        assert_eq!(
+            to_key(&json!([5, "A"]), &composite).unwrap(),
+            vec![5_u64.to_le_bytes().to_vec(), b"A".to_vec()]
+        );
+        assert_eq!(to_key(&json!([5]), &composite).unwrap().len(), 1);
+        assert!(matches!(
+            to_key(&json!([5, "A", "B"]), &composite),
+            Err(JsonError::KeyArity(2, 3))
+        ));
+        assert!(matches!(
+            to_key(&json!(["A"]), &composite),
+            Err(JsonError::WrongKeytype(path, KeyType::UInt64, KeyType::String)) if path == "key[0]"
+        ));
+
+        let err = to_record(&json!({"tenant": 5}), &composite).unwrap_err();
+        assert!(matches!(err, JsonError::MissingKey(path) if path == "$.record"));
+    }
+
+    #[test]
+    fn parse() {
+        let id = keys(b"id", KeyType::UInt64, 8);
+        let mut composite = keys(b"tenant", KeyType::UInt64, 8);
+        composite.extend(keys(b"record", KeyType::String, 10));
+
+        assert_eq!(parse_key("5", &id).unwrap(), json!(5));
+        assert!(matches!(
+            parse_key("five", &id),
+            Err(JsonError::InvalidKey(_))
+        ));
+        assert_eq!(
+            parse_key("5", &keys(b"name", KeyType::String, 10)).unwrap(),
+            json!("5")
+        );
+        assert_eq!(
+            parse_key(r#"[5,"A"]"#, &composite).unwrap(),
+            json!([5, "A"])
+        );
+        assert!(parse_key("5", &composite).is_err());
+
+        assert_eq!(
+            with_key(&json!({"name": "a"}), &json!([5, "A"]), &composite).unwrap(),
+            json!({"tenant": 5, "record": "A", "name": "a"})
+        );
+        // key fields in the object are left for the caller to compare
+        assert_eq!(
+            with_key(&json!({"id": 6}), &json!(5), &id).unwrap(),
+            json!({"id": 6})
+        );
+    }
+
+    #[test]
+    fn patch() {
+        let mut data = to_data(&json!({
+            "a": "b",
+            "c": {"d": "e", "f": "g"},
+            "at": {"$timestamp": "2024-03-01T11:30:00Z"},
+        }))
+        .unwrap();
+
+        patch_data(
+            &mut data,
+            &json!({"a": "z", "c": {"f": null}, "h": [1], "i": {"j": null}}),
+        )
+        .unwrap();
+
+        let value = serde_json::to_value(&data).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "a": "z",
+                "c": {"d": "e"},
+                "at": {"$timestamp": "2024-03-01T11:30:00Z"},
+                "h": [1],
+                "i": {},
+            })
+        );
+
+        // fields the patch leaves alone keep their type
+        let at = data.get_field(b"at").unwrap();
+        assert_eq!(at.get_datatype(), KeyType::Timestamp);
+
+        patch_data(&mut data, &json!({"c": 5, "a": {"$binary": "AA=="}})).unwrap();
+        assert_eq!(
+            data.get_field(b"c").unwrap().get_datatype(),
+            KeyType::UInt64
+        );
+        assert_eq!(data.get_field(b"a").unwrap().get_datatype(), KeyType::Bytes);
+
+        let err = patch_data(&mut data, &json!(["x"])).unwrap_err();
+        assert!(matches!(err, JsonError::NotAnObject(path, "array") if path == "$"));
+    }
+
+    fn keys(name: &[u8], keytype: KeyType, size: u16) -> Vec<KeyField> {
+        vec![KeyField {
+            name: name.to_vec(),
+            keytype,
+            size,
+        }]
+    }
+}
@@ -0,0 +1,330 @@
+use crate::database::page::{KeyField, KeyType};
+use thiserror::Error;
+
+// the longest encoded key nodes and leaves can hold, a leaf with two keys of this length still
+// fits in a page
+pub const MAX_KEY_LEN: usize = 2000;
+
+// integers in index keys are widened to 128 bits
+const INTEGER_LEN: usize = 16;
+
+// NOTE: keys in nodes and leaves are stored in this encoding, so comparing two encoded keys
+// byte by byte gives the same order as comparing the values they were made from
+
+// encodes the stored bytes of a field value into its memcomparable key
+pub fn encode(keytype: KeyType, data: &[u8]) -> Result<Vec<u8>, KeyError> {
+    let key = match keytype {
+        KeyType::UInt64 => u64::from_le_bytes(fixed(keytype, data)?)
+            .to_be_bytes()
+            .to_vec(),
+        KeyType::Int64 => flip_sign(u64::from_le_bytes(fixed(keytype, data)?)),
+        KeyType::Float64 => {
+            let bits = u64::from_le_bytes(fixed(keytype, data)?);
+
+            // negative floats sort in reverse, so all of their bits are flipped
+            if bits >> 63 == 1 {
+                (!bits).to_be_bytes().to_vec()
+            } else {
+                flip_sign(bits)
+            }
+        }
+        // timestamps are already stored sign-flipped and big endian
+        KeyType::Timestamp => fixed(keytype, data)?.to_vec(),
+        KeyType::String | KeyType::Bytes => escape(data),
+        _ => return Err(KeyError::Unsupported(keytype)),
+    };
+
+    if key.len() > MAX_KEY_LEN {
+        return Err(KeyError::TooLong(key.len()));
+    }
+
+    Ok(key)
+}
+
+// encodes a tuple of values, one per key field, by concatenating their keys. fewer values than
+// key fields gives a prefix that every key starting with those values sorts after
+pub fn encode_tuple(keys: &[KeyField], values: &[&[u8]]) -> Result<Vec<u8>, KeyError> {
+    if values.len() > keys.len() {
+        return Err(KeyError::Arity(keys.len(), values.len()));
+    }
+
+    let mut encoded = Vec::new();
+
+    for (key, value) in keys.iter().zip(values) {
+        encoded.extend(encode(key.keytype, value)?);
+    }
+
+    if encoded.len() > MAX_KEY_LEN {
+        return Err(KeyError::TooLong(encoded.len()));
+    }
+
+    Ok(encoded)
+}
+
+// encodes a value of any keyable type behind its type tag, so values of different types can
+// share an index without their encodings running into each other. json gives non-negative
+// integers as uint64 and negative ones as int64, so integers of both types are widened and
+// share the int64 tag, which keeps a range from -5 to 5 in one stretch of the index
+pub fn encode_value(keytype: KeyType, data: &[u8]) -> Result<Vec<u8>, KeyError> {
+    let integer = match keytype {
+        KeyType::UInt64 => Some(i128::from(u64::from_le_bytes(fixed(keytype, data)?))),
+        KeyType::Int64 => Some(i128::from(i64::from_le_bytes(fixed(keytype, data)?))),
+        _ => None,
+    };
+
+    if let Some(integer) = integer {
+        let mut encoded = vec![KeyType::Int64.tag()];
+        encoded.extend(((integer as u128) ^ (1 << 127)).to_be_bytes());
+
+        return Ok(encoded);
+    }
+
+    let mut encoded = vec![keytype.tag()];
+    encoded.extend(encode(keytype, data)?);
+
+    Ok(encoded)
+}
+
+// the length of the value encoded at the start of an index key, the primary key follows it
+pub fn value_len(encoded: &[u8]) -> Option<usize> {
+    let keytype = KeyType::try_from(*encoded.first()?).ok()?;
+
+    if keytype == KeyType::Int64 {
+        return Some(1 + INTEGER_LEN);
+    }
+
+    if let Some(size) = keytype.size() {
+        return Some(1 + size);
+    }
+
+    // escaped zero bytes are followed by 0xFF, the terminator by 0x01
+    let mut idx = 1;
+    while idx + 1 < encoded.len() {
+        match (encoded[idx], encoded[idx + 1]) {
+            (0x00, 0x01) => return Some(idx + 2),
+            (0x00, _) => idx += 2,
+            _ => idx += 1,
+        }
+    }
+
+    None
+}
+
+// the smallest key that sorts after every key starting with the prefix, or none if there is
+// no such key
+pub fn successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+
+    while let Some(last) = successor.pop() {
+        if last != 0xFF {
+            successor.push(last + 1);
+            return Some(successor);
+        }
+    }
+
+    None
+}
+
+// the smallest key that sorts after the key, as nothing sorts between a key and the key followed
+// by a zero byte
+pub fn after(key: &[u8]) -> Vec<u8> {
+    [key, &[0x00]].concat()
+}
+
+// the shortest prefix of right that still sorts after left, so separators pushed up into nodes
+// only take up as many bytes as it takes to tell the two halves of a split apart
+pub fn separator(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let common = left
+        .iter()
+        .zip(right)
+        .take_while(|(left, right)| left == right)
+        .count();
+
+    right[..right.len().min(common + 1)].to_vec()
+}
+
+fn fixed(keytype: KeyType, data: &[u8]) -> Result<[u8; 8], KeyError> {
+    <[u8; 8]>::try_from(data).map_err(|_| KeyError::Size(keytype, data.len()))
+}
+
+fn flip_sign(bits: u64) -> Vec<u8> {
+    (bits ^ (1 << 63)).to_be_bytes().to_vec()
+}
+
+// 0x00 is escaped as 0x00 0xFF and the key ends in 0x00 0x01, so a key always sorts before
+// any longer key it is a prefix of, also once keys are concatenated
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(data.len() + 2);
+
+    for byte in data {
+        key.push(*byte);
+        if *byte == 0x00 {
+            key.push(0xFF);
+        }
+    }
+
+    key.extend([0x00, 0x01]);
+
+    key
+}
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("{0:?} cannot be used as a key")]
+    Unsupported(KeyType),
+
+    #[error("{0:?} key has the wrong size ({1})")]
+    Size(KeyType, usize),
+
+    #[error("encoded key is too long ({0})")]
+    TooLong(usize),
+
+    #[error("expected at most {0} key values, found {1}")]
+    Arity(usize, usize),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uint64() {
+        let keys: Vec<Vec<u8>> = [0_u64, 1, 255, 256, 1000, u64::MAX]
+            .iter()
+            .map(|n| encode(KeyType::UInt64, &n.to_le_bytes()).unwrap())
+            .collect();
+
+        assert!(keys.is_sorted());
+    }
+
+    #[test]
+    fn int64() {
+        let keys: Vec<Vec<u8>> = [i64::MIN, -256, -1, 0, 1, 256, i64::MAX]
+            .iter()
+            .map(|n| encode(KeyType::Int64, &n.to_le_bytes()).unwrap())
+            .collect();
+
+        assert!(keys.is_sorted());
+    }
+
+    #[test]
+    fn float64() {
+        let keys: Vec<Vec<u8>> = [f64::NEG_INFINITY, -2.5, -0.1, 0.0, 0.1, 2.5, f64::INFINITY]
+            .iter()
+            .map(|n| encode(KeyType::Float64, &n.to_le_bytes()).unwrap())
+            .collect();
+
+        assert!(keys.is_sorted());
+    }
+
+    #[test]
+    fn string() {
+        let keys: Vec<Vec<u8>> = [&b""[..], b"a", b"a\x00", b"a\x00b", b"ab", b"b"]
+            .iter()
+            .map(|s| encode(KeyType::String, s).unwrap())
+            .collect();
+
+        assert!(keys.is_sorted());
+        assert_eq!(keys[2], vec![b'a', 0x00, 0xFF, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn tuple() {
+        let keys = vec![
+            KeyField {
+                name: b"tenant".to_vec(),
+                keytype: KeyType::UInt64,
+                size: 8,
+            },
+            KeyField {
+                name: b"record".to_vec(),
+                keytype: KeyType::String,
+                size: 10,
+            },
+        ];
+
+        let one = 1_u64.to_le_bytes();
+        let two = 2_u64.to_le_bytes();
+
+        let tenant = encode_tuple(&keys, &[&one]).unwrap();
+        let a = encode_tuple(&keys, &[&one, b"a"]).unwrap();
+        let b = encode_tuple(&keys, &[&one, b"b"]).unwrap();
+        let next = encode_tuple(&keys, &[&two, b""]).unwrap();
+
+        assert!(tenant < a && a < b && b < next);
+        assert!(b < successor(&tenant).unwrap() && successor(&tenant).unwrap() <= next);
+
+        assert!(matches!(
+            encode_tuple(&keys, &[&one, b"a", b"b"]),
+            Err(KeyError::Arity(2, 3))
+        ));
+
+        assert!(b < after(&b) && after(&b) < next);
+
+        assert_eq!(successor(&[0x01, 0xFF]), Some(vec![0x02]));
+        assert_eq!(successor(&[0xFF, 0xFF]), None);
+    }
+
+    #[test]
+    fn separator() {
+        let left = encode(KeyType::String, b"https://example.com/a/long/path").unwrap();
+        let right = encode(KeyType::String, b"https://example.com/b").unwrap();
+
+        let separator = super::separator(&left, &right);
+
+        assert_eq!(separator, b"https://example.com/b".to_vec());
+        assert!(left < separator && separator <= right);
+
+        // a key that is a prefix of the next one is separated by the terminator
+        let left = encode(KeyType::String, b"ab").unwrap();
+        let right = encode(KeyType::String, b"abc").unwrap();
+
+        let separator = super::separator(&left, &right);
+
+        assert!(left < separator && separator <= right);
+        assert_eq!(separator.len(), 3);
+    }
+
+    #[test]
+    fn value() {
+        let name = encode_value(KeyType::String, b"a\x00b").unwrap();
+        let age = encode_value(KeyType::UInt64, &55_u64.to_le_bytes()).unwrap();
+
+        let mut key = name.clone();
+        key.extend(encode(KeyType::UInt64, &7_u64.to_le_bytes()).unwrap());
+
+        assert_eq!(value_len(&key), Some(name.len()));
+        assert_eq!(value_len(&age), Some(17));
+        assert_eq!(value_len(&name[..3]), None);
+
+        // values sort by type first
+        assert!(name < age);
+
+        // integers sort together whether they were stored signed or not
+        let below = encode_value(KeyType::Int64, &(-5_i64).to_le_bytes()).unwrap();
+        let above = encode_value(KeyType::UInt64, &u64::MAX.to_le_bytes()).unwrap();
+        let five = encode_value(KeyType::Int64, &5_i64.to_le_bytes()).unwrap();
+
+        assert!(below < age && age < above);
+        assert_eq!(
+            five,
+            encode_value(KeyType::UInt64, &5_u64.to_le_bytes()).unwrap()
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            encode(KeyType::UInt64, b"A"),
+            Err(KeyError::Size(KeyType::UInt64, 1))
+        ));
+        assert!(matches!(
+            encode(KeyType::Bool, &[0x01]),
+            Err(KeyError::Unsupported(KeyType::Bool))
+        ));
+        assert!(matches!(
+            encode(KeyType::String, &[b'a'; MAX_KEY_LEN - 1]),
+            Err(KeyError::TooLong(2001))
+        ));
+    }
+}
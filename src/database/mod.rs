@@ -1,31 +1,72 @@
+pub mod cache;
 pub mod handler;
+pub mod index;
+pub mod journal;
+pub mod json;
+pub mod key;
 pub mod page;
+pub mod schema;
+pub mod stats;
+pub mod tree;
 pub use crate::database::page::Data;
 
 use crate::database::handler::*;
+use crate::database::journal::Undo;
+use crate::database::json::JsonError;
+use crate::database::key::KeyError;
 use crate::database::page::*;
-use std::collections::VecDeque;
-use std::io::{Read, Seek, Write};
-use std::ops::Index;
+use crate::database::schema::{Upgrade, Violation};
+use crate::database::tree::Tree;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
 use thiserror::Error;
+use ulid::Generator;
+
+const DEFAULT_ORDER: usize = 4;
+pub const ULID_LEN: usize = 26;
+
+// where a scan starts and the key it stops before, none for the ends of the tree
+pub type Bounds = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+// the collection a database is built with and opens to
+pub const DEFAULT_COLLECTION: &str = "default";
+
+// the longest string key a database can be configured with, longer keys make for nodes and
+// leaves with fewer keys as they still have to fit in a page
+pub const MAX_STRING_KEY_SIZE: u16 = 1024;
 
 pub struct DatabaseBuilder<T: Read + Write + Seek> {
     source: T,
+    collection: Vec<u8>,
     key: Vec<u8>,
     keytype: KeyTypeSize,
+    keys: Vec<(Vec<u8>, KeyTypeSize)>,
     order: usize,
+    generation: KeyGeneration,
 }
 
 impl<T: Read + Write + Seek> DatabaseBuilder<T> {
     pub fn new(source: T) -> DatabaseBuilder<T> {
         DatabaseBuilder {
             source,
+            collection: DEFAULT_COLLECTION.as_bytes().to_vec(),
             key: Vec::new(),
             keytype: KeyTypeSize::UInt64,
+            keys: Vec::new(),
             order: 0,
+            generation: KeyGeneration::None,
         }
     }
 
+    // the name of the collection the database starts out with
+    #[allow(dead_code)]
+    pub fn collection(mut self, collection: Vec<u8>) -> DatabaseBuilder<T> {
+        self.collection = collection;
+        self
+    }
+
     pub fn key(mut self, key: Vec<u8>) -> DatabaseBuilder<T> {
         self.key = key;
         self
@@ -36,24 +77,53 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
         self
     }
 
+    // a composite key made up of several fields, takes precedence over key and keytype
+    #[allow(dead_code)]
+    pub fn keys(mut self, keys: Vec<(Vec<u8>, KeyTypeSize)>) -> DatabaseBuilder<T> {
+        self.keys = keys;
+        self
+    }
+
+    #[allow(dead_code)]
     pub fn order(mut self, order: usize) -> DatabaseBuilder<T> {
         self.order = order;
         self
     }
 
+    // fills in the key of records inserted without one, auto-increment needs a uint64 key and
+    // ulids need a string key of at least 26 bytes
+    pub fn generate(mut self, generation: KeyGeneration) -> DatabaseBuilder<T> {
+        self.generation = generation;
+        self
+    }
+
     pub fn build(self) -> Database<T> {
+        self.try_build().unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    // like build, but a key that can't be used is an error instead of a panic
+    pub fn try_build(mut self) -> Result<Database<T>, DatabaseError> {
+        if self.keys.is_empty() {
+            self.keys.push((self.key, self.keytype));
+        }
+
+        let collection = define(&self.collection, self.keys, self.order, self.generation)?;
+
         let mut db = Database {
             source: self.source,
-            key: self.key,
-            keytype: self.keytype.keytype(),
-            keytype_size: self.keytype.size(),
-            order: self.order,
-            root: 0,
+            name: Vec::new(),
+            keys: Vec::new(),
+            keytype: KeyType::UInt64,
+            order: DEFAULT_ORDER,
+            generation: KeyGeneration::None,
+            ulids: Generator::new(),
+            journal: None,
+            upgrades: HashMap::new(),
         };
 
-        db.init_header();
+        db.init(collection)?;
 
-        db
+        Ok(db)
     }
 
     pub fn build_mock_u64(self) -> Database<T> {
@@ -62,45 +132,49 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
         let node = PageType::Node(Node {
             keytype: KeyType::UInt64,
             keys: vec![
-                3_usize.to_le_bytes().to_vec(),
-                5_usize.to_le_bytes().to_vec(),
+                key::encode(KeyType::UInt64, &3_u64.to_le_bytes()).unwrap(),
+                key::encode(KeyType::UInt64, &5_u64.to_le_bytes()).unwrap(),
             ],
-            pointers: vec![1, 2, 3],
+            pointers: vec![2, 3, 4],
         });
 
         let leaf1 = PageType::Leaf(Leaf {
             keytype: KeyType::UInt64,
             keys: vec![
-                1_usize.to_le_bytes().to_vec(),
-                2_usize.to_le_bytes().to_vec(),
+                key::encode(KeyType::UInt64, &1_u64.to_le_bytes()).unwrap(),
+                key::encode(KeyType::UInt64, &2_u64.to_le_bytes()).unwrap(),
             ],
-            pointers: vec![4, 5],
-            next_leaf_pointer: 0,
+            pointers: vec![5, 6],
+            next_leaf_pointer: 3,
         });
 
         let leaf2 = PageType::Leaf(Leaf {
             keytype: KeyType::UInt64,
             keys: vec![
-                3_usize.to_le_bytes().to_vec(),
-                4_usize.to_le_bytes().to_vec(),
+                key::encode(KeyType::UInt64, &3_u64.to_le_bytes()).unwrap(),
+                key::encode(KeyType::UInt64, &4_u64.to_le_bytes()).unwrap(),
             ],
-            pointers: vec![6, 7],
-            next_leaf_pointer: 0,
+            pointers: vec![7, 8],
+            next_leaf_pointer: 4,
         });
 
         let leaf3 = PageType::Leaf(Leaf {
             keytype: KeyType::UInt64,
             keys: vec![
-                5_usize.to_le_bytes().to_vec(),
-                6_usize.to_le_bytes().to_vec(),
+                key::encode(KeyType::UInt64, &5_u64.to_le_bytes()).unwrap(),
+                key::encode(KeyType::UInt64, &6_u64.to_le_bytes()).unwrap(),
             ],
-            pointers: vec![8, 9],
+            pointers: vec![9, 10],
             next_leaf_pointer: 0,
         });
 
         let data1 = PageType::Data(Data {
             object: vec![
-                Field::new(b"id".to_vec(), KeyType::UInt64, b"A".to_vec()),
+                Field::new(
+                    b"id".to_vec(),
+                    KeyType::UInt64,
+                    1_usize.to_le_bytes().to_vec(),
+                ),
                 Field::new(b"name".to_vec(), KeyType::String, b"jonas".to_vec()),
                 Field::new(
                     b"age".to_vec(),
@@ -113,11 +187,16 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     87_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data2 = PageType::Data(Data {
             object: vec![
-                Field::new(b"id".to_vec(), KeyType::UInt64, b"B".to_vec()),
+                Field::new(
+                    b"id".to_vec(),
+                    KeyType::UInt64,
+                    2_usize.to_le_bytes().to_vec(),
+                ),
                 Field::new(b"cpr".to_vec(), KeyType::String, b"0101009999".to_vec()),
                 Field::new(b"name".to_vec(), KeyType::String, b"johnny".to_vec()),
                 Field::new(
@@ -126,11 +205,16 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     30_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data3 = PageType::Data(Data {
             object: vec![
-                Field::new(b"id".to_vec(), KeyType::UInt64, b"C".to_vec()),
+                Field::new(
+                    b"id".to_vec(),
+                    KeyType::UInt64,
+                    3_usize.to_le_bytes().to_vec(),
+                ),
                 Field::new(b"name".to_vec(), KeyType::String, b"dam".to_vec()),
                 Field::new(
                     b"age".to_vec(),
@@ -138,11 +222,16 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     300_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data4 = PageType::Data(Data {
             object: vec![
-                Field::new(b"id".to_vec(), KeyType::UInt64, b"D".to_vec()),
+                Field::new(
+                    b"id".to_vec(),
+                    KeyType::UInt64,
+                    4_usize.to_le_bytes().to_vec(),
+                ),
                 Field::new(b"name".to_vec(), KeyType::String, b"lars".to_vec()),
                 Field::new(
                     b"age".to_vec(),
@@ -150,11 +239,16 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     55_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data5 = PageType::Data(Data {
             object: vec![
-                Field::new(b"id".to_vec(), KeyType::UInt64, b"E".to_vec()),
+                Field::new(
+                    b"id".to_vec(),
+                    KeyType::UInt64,
+                    5_usize.to_le_bytes().to_vec(),
+                ),
                 Field::new(b"name".to_vec(), KeyType::String, b"john".to_vec()),
                 Field::new(
                     b"age".to_vec(),
@@ -162,11 +256,16 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     55_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data6 = PageType::Data(Data {
             object: vec![
-                Field::new(b"id".to_vec(), KeyType::UInt64, b"F".to_vec()),
+                Field::new(
+                    b"id".to_vec(),
+                    KeyType::UInt64,
+                    6_usize.to_le_bytes().to_vec(),
+                ),
                 Field::new(b"name".to_vec(), KeyType::String, b"hans".to_vec()),
                 Field::new(
                     b"age".to_vec(),
@@ -174,6 +273,7 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     55_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let mut root = db.get_root().unwrap();
@@ -190,6 +290,12 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
         let _ = PageHandler::new_page(&mut db.source, data5);
         let _ = PageHandler::new_page(&mut db.source, data6);
 
+        db.update_definition(|collection| {
+            collection.counter = 6;
+            collection.elements = 6;
+        })
+        .unwrap();
+
         db
     }
 
@@ -198,28 +304,40 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
 
         let node = PageType::Node(Node {
             keytype: KeyType::String,
-            keys: vec!["C".as_bytes().to_vec(), "E".as_bytes().to_vec()],
-            pointers: vec![1, 2, 3],
+            keys: vec![
+                key::encode(KeyType::String, b"C").unwrap(),
+                key::encode(KeyType::String, b"E").unwrap(),
+            ],
+            pointers: vec![2, 3, 4],
         });
 
         let leaf1 = PageType::Leaf(Leaf {
             keytype: KeyType::String,
-            keys: vec!["A".as_bytes().to_vec(), "B".as_bytes().to_vec()],
-            pointers: vec![4, 5],
-            next_leaf_pointer: 0,
+            keys: vec![
+                key::encode(KeyType::String, b"A").unwrap(),
+                key::encode(KeyType::String, b"B").unwrap(),
+            ],
+            pointers: vec![5, 6],
+            next_leaf_pointer: 3,
         });
 
         let leaf2 = PageType::Leaf(Leaf {
             keytype: KeyType::String,
-            keys: vec!["C".as_bytes().to_vec(), "D".as_bytes().to_vec()],
-            pointers: vec![6, 7],
-            next_leaf_pointer: 0,
+            keys: vec![
+                key::encode(KeyType::String, b"C").unwrap(),
+                key::encode(KeyType::String, b"D").unwrap(),
+            ],
+            pointers: vec![7, 8],
+            next_leaf_pointer: 4,
         });
 
         let leaf3 = PageType::Leaf(Leaf {
             keytype: KeyType::String,
-            keys: vec!["E".as_bytes().to_vec(), "F".as_bytes().to_vec()],
-            pointers: vec![8, 9],
+            keys: vec![
+                key::encode(KeyType::String, b"E").unwrap(),
+                key::encode(KeyType::String, b"F").unwrap(),
+            ],
+            pointers: vec![9, 10],
             next_leaf_pointer: 0,
         });
 
@@ -238,6 +356,7 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     87_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data2 = PageType::Data(Data {
@@ -251,6 +370,7 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     30_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data3 = PageType::Data(Data {
@@ -263,6 +383,7 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     300_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data4 = PageType::Data(Data {
@@ -275,6 +396,7 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     55_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data5 = PageType::Data(Data {
@@ -287,6 +409,7 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     55_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let data6 = PageType::Data(Data {
@@ -299,6 +422,7 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
                     55_usize.to_le_bytes().to_vec(),
                 ),
             ],
+            version: 0,
         });
 
         let mut root = db.get_root().unwrap();
@@ -315,211 +439,1741 @@ impl<T: Read + Write + Seek> DatabaseBuilder<T> {
         let _ = PageHandler::new_page(&mut db.source, data5);
         let _ = PageHandler::new_page(&mut db.source, data6);
 
+        db.update_definition(|collection| collection.elements = 6)
+            .unwrap();
+
         db
     }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub enum KeyTypeSize {
-    String(u8),
+    String(u16),
     UInt64,
+    Timestamp,
 }
 
 impl KeyTypeSize {
-    fn size(&self) -> u8 {
+    pub fn size(&self) -> u16 {
         match self {
             KeyTypeSize::String(n) => *n,
-            KeyTypeSize::UInt64 => 8,
+            KeyTypeSize::UInt64 | KeyTypeSize::Timestamp => 8,
         }
     }
 
-    fn keytype(&self) -> KeyType {
+    pub fn keytype(&self) -> KeyType {
         match self {
             KeyTypeSize::String(_) => KeyType::String,
             KeyTypeSize::UInt64 => KeyType::UInt64,
+            KeyTypeSize::Timestamp => KeyType::Timestamp,
+        }
+    }
+}
+
+// validates the definition of a collection, its root is filled in once its tree is created
+fn define(
+    name: &[u8],
+    keys: Vec<(Vec<u8>, KeyTypeSize)>,
+    order: usize,
+    generation: KeyGeneration,
+) -> Result<Collection, DatabaseError> {
+    let invalid = |reason: String| Err(DatabaseError::InvalidCollection(reason));
+
+    if name.is_empty() || name.len() > u8::MAX as usize {
+        return invalid("collection names must be between 1 and 255 bytes".to_string());
+    }
+
+    if keys.is_empty() {
+        return invalid("a collection needs at least one key field".to_string());
+    }
+
+    let keys: Vec<KeyField> = keys
+        .into_iter()
+        .map(|(name, keytype)| KeyField {
+            name,
+            keytype: keytype.keytype(),
+            size: keytype.size(),
+        })
+        .collect();
+
+    if let Some(key) = keys.iter().find(|key| key.name.len() > u8::MAX as usize) {
+        return invalid(format!(
+            "key names can be at most 255 bytes, {} is {}",
+            String::from_utf8_lossy(&key.name),
+            key.name.len()
+        ));
+    }
+
+    if let Some(key) = keys.iter().find(|key| key.size > MAX_STRING_KEY_SIZE) {
+        return invalid(format!(
+            "string keys can be at most {MAX_STRING_KEY_SIZE} bytes, {} is {}",
+            String::from_utf8_lossy(&key.name),
+            key.size
+        ));
+    }
+
+    match (generation, keys.as_slice()) {
+        (KeyGeneration::None, _) => {}
+        (KeyGeneration::AutoIncrement, [key]) if key.keytype == KeyType::UInt64 => {}
+        (KeyGeneration::Ulid, [key])
+            if key.keytype == KeyType::String && key.size as usize >= ULID_LEN => {}
+        (generation, _) => {
+            return invalid(format!(
+                "{generation:?} keys cannot be generated for this key"
+            ));
+        }
+    }
+
+    // long keys lower the order so a full node or leaf still fits in a page
+    let order = match order {
+        0 => DEFAULT_ORDER,
+        order => order,
+    }
+    .min(max_order(&keys))
+    .max(2);
+
+    Ok(Collection {
+        name: name.to_vec(),
+        elements: 0,
+        keys,
+        root: 0,
+        order: order.try_into().expect("order does not fit in a u8"),
+        generation,
+        counter: 0,
+        indexes: Vec::new(),
+        schema: None,
+        migrated: 0,
+        migrations: Vec::new(),
+    })
+}
+
+// builds the encoded primary key of a record from its key fields
+fn record_key(keys: &[KeyField], data: &Data) -> Result<Vec<u8>, DatabaseError> {
+    let mut values = Vec::new();
+
+    for key in keys {
+        let Some(field) = data.get_field(&key.name) else {
+            return Err(DatabaseError::MissingKey(
+                String::from_utf8_lossy(&key.name).to_string(),
+            ));
+        };
+
+        if field.get_datatype() != key.keytype {
+            return Err(DatabaseError::WrongKeytype(
+                key.keytype,
+                field.get_datatype(),
+            ));
+        }
+
+        if key.keytype == KeyType::String && field.data.len() > key.size as usize {
+            return Err(DatabaseError::KeyTooLong(
+                String::from_utf8_lossy(&key.name).to_string(),
+                key.size,
+                field.data.len(),
+            ));
         }
+
+        values.push(field.data.as_slice());
     }
+
+    Ok(key::encode_tuple(keys, &values)?)
 }
 
+// a database file holds any number of collections, each its own tree of records. operations
+// work on the selected collection, which the fields below describe
 pub struct Database<T: Read + Write + Seek> {
     pub source: T,
-    key: Vec<u8>,
+    name: Vec<u8>,
+    keys: Vec<KeyField>,
     keytype: KeyType,
-    keytype_size: u8,
     order: usize,
-    root: usize,
+    generation: KeyGeneration,
+    ulids: Generator,
+    journal: Option<Vec<Undo>>,
+    upgrades: HashMap<Vec<u8>, Upgrade>,
 }
 
 impl<T: Read + Write + Seek> Database<T> {
-    pub fn init_header(&mut self) {
-        let header = Header {
-            elements: 0,
-            keytype: self.keytype,
-            keytype_size: self.keytype_size,
-            key: self.key.clone(),
+    // opens a database file with the default collection selected, or the first one if the
+    // default has been dropped
+    pub fn open(source: T) -> Result<Database<T>, DatabaseError> {
+        let mut db = Database {
+            source,
+            name: DEFAULT_COLLECTION.as_bytes().to_vec(),
+            keys: Vec::new(),
+            keytype: KeyType::UInt64,
+            order: DEFAULT_ORDER,
+            generation: KeyGeneration::None,
+            ulids: Generator::new(),
+            journal: None,
+            upgrades: HashMap::new(),
+        };
+
+        let collections = db.catalog()?.collections;
+
+        if let Some(collection) = collections
+            .iter()
+            .find(|collection| collection.name == db.name)
+            .or(collections.first())
+        {
+            db.select(collection);
+        }
+
+        Ok(db)
+    }
+
+    // writes the header and an empty catalog, the catalog always takes up the first page
+    fn init(&mut self, collection: Collection) -> Result<(), DatabaseError> {
+        HeaderHandler::write(
+            &mut self.source,
+            Header {
+                catalog: 0,
+                free: 0,
+            },
+        )?;
+
+        let catalog =
+            PageHandler::new_page(&mut self.source, PageType::Catalog(Catalog::default()))?;
+
+        HeaderHandler::write(
+            &mut self.source,
+            Header {
+                catalog: catalog.id,
+                free: 0,
+            },
+        )?;
+
+        self.select(&collection);
+        self.add_collection(collection)
+    }
+
+    pub fn create_collection(
+        &mut self,
+        name: &[u8],
+        keys: Vec<(Vec<u8>, KeyTypeSize)>,
+        generation: KeyGeneration,
+    ) -> Result<(), DatabaseError> {
+        let collection = define(name, keys, 0, generation)?;
 
-            // this should be dynamic going forward, determined by keytype size
-            order: 4,
+        self.add_collection(collection)
+    }
+
+    fn add_collection(&mut self, mut collection: Collection) -> Result<(), DatabaseError> {
+        let mut catalog = self.catalog()?;
+
+        if catalog
+            .collections
+            .iter()
+            .any(|c| c.name == collection.name)
+        {
+            return Err(DatabaseError::CollectionExists(
+                String::from_utf8_lossy(&collection.name).to_string(),
+            ));
+        }
+
+        let tree = Tree::create(
+            &mut self.source,
+            collection.keytype(),
+            collection.order.into(),
+        )?;
+
+        collection.root = tree.root;
+        catalog.collections.push(collection);
+
+        if let Err(err) = self.write_catalog(catalog) {
+            PageHandler::free(&mut self.source, tree.root)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    // removes a collection and frees the pages of its tree, records and indexes
+    pub fn drop_collection(&mut self, name: &[u8]) -> Result<(), DatabaseError> {
+        let mut catalog = self.catalog()?;
 
-            root: self.root.try_into().expect("u64 to usize failure"),
+        let Some(idx) = catalog.collections.iter().position(|c| c.name == name) else {
+            return Err(DatabaseError::UnknownCollection(
+                String::from_utf8_lossy(name).to_string(),
+            ));
         };
 
-        HeaderHandler::write(&mut self.source, header).expect("couldnt initialize header");
+        let collection = catalog.collections.remove(idx);
+        self.write_catalog(catalog)?;
+        self.upgrades.remove(name);
+
+        let tree = Tree::new(
+            collection.root,
+            collection.keytype(),
+            collection.order.into(),
+        );
+
+        let mut pages: Vec<u64> = tree
+            .scan(&mut self.source, None, None)?
+            .into_iter()
+            .map(|(_, pointer)| pointer)
+            .collect();
+
+        pages.extend(tree.pages(&mut self.source)?);
+
+        for index in &collection.indexes {
+            pages.extend(
+                Tree::new(index.root, KeyType::Bytes, DEFAULT_ORDER).pages(&mut self.source)?,
+            );
+        }
+
+        for page in pages {
+            PageHandler::free(&mut self.source, page)?;
+        }
+
+        Ok(())
+    }
 
-        let leaf = Leaf::new(self.keytype);
-        let _ = PageHandler::new_page(&mut self.source, PageType::Leaf(leaf));
+    pub fn collections(&mut self) -> Result<Vec<String>, DatabaseError> {
+        Ok(self
+            .catalog()?
+            .collections
+            .iter()
+            .map(|collection| String::from_utf8_lossy(&collection.name).to_string())
+            .collect())
     }
 
-    pub fn get_keytype(&mut self) -> Result<KeyType, HandlerError> {
-        Ok(HeaderHandler::get(&mut self.source)?.keytype)
+    // makes the named collection the one operations work on
+    pub fn use_collection(&mut self, name: &[u8]) -> Result<(), DatabaseError> {
+        match self
+            .catalog()?
+            .collections
+            .iter()
+            .find(|collection| collection.name == name)
+        {
+            Some(collection) => {
+                self.select(collection);
+                Ok(())
+            }
+            None => Err(DatabaseError::UnknownCollection(
+                String::from_utf8_lossy(name).to_string(),
+            )),
+        }
     }
 
-    fn get_root(&mut self) -> Result<Page, HandlerError> {
-        let root_id = HeaderHandler::get(&mut self.source)?.root;
+    // the name of the collection operations work on
+    pub fn selected(&self) -> &[u8] {
+        &self.name
+    }
 
-        PageHandler::get_page(&mut self.source, root_id)
+    // the key fields of the selected collection
+    pub fn keys(&self) -> &[KeyField] {
+        &self.keys
     }
 
-    #[allow(dead_code)]
-    pub fn insert(&mut self, data: Data) -> Result<(), HandlerError> {
+    pub fn generation(&self) -> KeyGeneration {
+        self.generation
+    }
+
+    fn select(&mut self, collection: &Collection) {
+        self.name = collection.name.clone();
+        self.keys = collection.keys.clone();
+        self.keytype = collection.keytype();
+        self.order = collection.order.into();
+        self.generation = collection.generation;
+    }
+
+    fn catalog(&mut self) -> Result<Catalog, DatabaseError> {
+        let id = HeaderHandler::get(&mut self.source)?.catalog;
+
+        match PageHandler::get_page(&mut self.source, id)?.pagetype {
+            PageType::Catalog(catalog) => Ok(catalog),
+            _ => Err(DatabaseError::UnexpectedPagetype(
+                "catalog".to_string(),
+                "something else".to_string(),
+            )),
+        }
+    }
+
+    fn write_catalog(&mut self, catalog: Catalog) -> Result<(), DatabaseError> {
+        let page = Page {
+            id: HeaderHandler::get(&mut self.source)?.catalog,
+            pagetype: PageType::Catalog(catalog),
+        };
+
+        if !page.fits() {
+            return Err(DatabaseError::CatalogFull);
+        }
+
+        Ok(PageHandler::write(&mut self.source, page)?)
+    }
+
+    // the stored definition of the selected collection
+    fn definition(&mut self) -> Result<Collection, DatabaseError> {
+        let name = self.name.clone();
+
+        self.catalog()?
+            .collections
+            .into_iter()
+            .find(|collection| collection.name == name)
+            .ok_or_else(|| {
+                DatabaseError::UnknownCollection(String::from_utf8_lossy(&name).to_string())
+            })
+    }
+
+    fn update_definition(
+        &mut self,
+        update: impl FnOnce(&mut Collection),
+    ) -> Result<(), DatabaseError> {
+        let mut catalog = self.catalog()?;
+
+        let Some(collection) = catalog
+            .collections
+            .iter_mut()
+            .find(|collection| collection.name == self.name)
+        else {
+            return Err(DatabaseError::UnknownCollection(
+                String::from_utf8_lossy(&self.name).to_string(),
+            ));
+        };
+
+        update(collection);
+
+        self.write_catalog(catalog)
+    }
+
+    fn get_root(&mut self) -> Result<Page, DatabaseError> {
+        let root_id = self.definition()?.root;
+
+        Ok(PageHandler::get_page(&mut self.source, root_id)?)
+    }
+
+    fn primary(&mut self) -> Result<Tree, DatabaseError> {
+        let root = self.definition()?.root;
+
+        Ok(Tree::new(root, self.keytype, self.order))
+    }
+
+    // reads a record in the current shape of the collection
+    fn read_data(&mut self, id: u64) -> Result<Data, DatabaseError> {
+        match PageHandler::get_page(&mut self.source, id)?.pagetype {
+            PageType::Data(data) => self.upgrade(data),
+            _ => Err(DatabaseError::UnexpectedPagetype(
+                "data".to_string(),
+                "something else".to_string(),
+            )),
+        }
+    }
+
+    // stores a new record and returns it as stored, with the defaults of the schema filled in
+    pub fn insert(&mut self, data: Data) -> Result<Data, DatabaseError> {
+        let data = self.conform(data)?;
+
         if !data.is_valid() {
-            // TODO: return error regarding undefined data
-            todo!()
+            return Err(DatabaseError::InvalidData);
         }
 
-        if let Some(field) = data.get_field(&self.key) {
-            let mut nodestack = VecDeque::new();
+        let key = record_key(&self.keys, &data)?;
 
-            let mut current_node = self.get_root()?;
+        let mut tree = self.primary()?;
 
-            while let PageType::Node(ref node) = current_node.pagetype {
-                let child_id = if let Some(idx) =
-                    node.keys.iter().position(|node_key| *node_key > field.data)
-                {
-                    node.pointers.index(idx)
-                } else {
-                    node.pointers.last().unwrap()
-                };
+        if tree.find(&mut self.source, &key)?.is_some() {
+            return Err(DatabaseError::DuplicateKey);
+        }
 
-                nodestack.push_front(current_node.id);
-                current_node = PageHandler::get_page(&mut self.source, *child_id)?;
-            }
+        // index keys are built up front so a value that can't be indexed fails the insert
+        // before anything is written
+        let entries = self.index_entries(&key, &data)?;
+        self.check_unique(&entries, &data)?;
 
-            if let PageType::Leaf(mut leaf) = current_node.pagetype {
-                if leaf.keys.contains(&field.data) {
-                    // TODO: return error, db already contains this key
-                    todo!()
-                }
+        // journaled before anything is written, so an insert that fails halfway is undone too
+        self.record(Undo::Insert(self.name.clone(), key.clone()));
 
-                let data_page =
-                    PageHandler::new_page(&mut self.source, PageType::Leaf(leaf.clone()))?;
+        let data_page = PageHandler::new_page(&mut self.source, PageType::Data(data.clone()))?;
 
-                if let Some(idx) = leaf.keys.iter().position(|leaf_key| *leaf_key > field.data) {
-                    leaf.keys.insert(idx, field.data.clone());
-                    leaf.pointers.insert(idx, data_page.id);
-                } else {
-                    leaf.keys.push(field.data.clone());
-                    leaf.pointers.push(data_page.id);
-                }
+        tree.insert(&mut self.source, key, data_page.id)?;
 
-                if leaf.pointers.len() > self.order {
-                    let split = leaf.split();
+        self.update_definition(|collection| {
+            collection.root = tree.root;
+            collection.elements += 1;
+        })?;
 
-                    if let Some(parent) = nodestack.pop_front() {
-                        // TODO: split logic, look at slotmap implementation
-                        todo!()
-                    }
-                }
+        self.index_insert(entries)?;
 
-                todo!()
-            }
-        } else {
-            // TODO: return error regarding data not having the required key
-            todo!()
+        Ok(data)
+    }
+
+    // replaces the record with the same key
+    pub fn update(&mut self, data: Data) -> Result<Data, DatabaseError> {
+        let data = self.conform(data)?;
+
+        if !data.is_valid() {
+            return Err(DatabaseError::InvalidData);
+        }
+
+        let key = record_key(&self.keys, &data)?;
+
+        let Some(pointer) = self.primary()?.find(&mut self.source, &key)? else {
+            return Err(DatabaseError::NotFound);
+        };
+
+        let old = self.read_data(pointer)?;
+
+        let removed = self.index_entries(&key, &old)?;
+        let added = self.index_entries(&key, &data)?;
+        self.check_unique(&added, &data)?;
+
+        self.record(Undo::Update(self.name.clone(), old));
+
+        PageHandler::write(
+            &mut self.source,
+            Page {
+                id: pointer,
+                pagetype: PageType::Data(data.clone()),
+            },
+        )?;
+
+        self.index_remove(removed)?;
+        self.index_insert(added)?;
+
+        Ok(data)
+    }
+
+    // removes a record by the values of its key fields and returns it
+    pub fn delete(&mut self, values: &[&[u8]]) -> Result<Option<Data>, DatabaseError> {
+        if values.len() != self.keys.len() {
+            return Err(KeyError::Arity(self.keys.len(), values.len()).into());
         }
 
-        todo!();
+        self.delete_encoded(&key::encode_tuple(&self.keys, values)?)
     }
 
-    pub fn get(&mut self, key: &[u8]) -> Result<Option<Data>, DatabaseError> {
-        let mut current_node = self.get_root().expect("couldnt get root");
+    fn delete_encoded(&mut self, key: &[u8]) -> Result<Option<Data>, DatabaseError> {
+        let mut tree = self.primary()?;
 
-        while let PageType::Node(ref node) = current_node.pagetype {
-            let child_id = if let Some(idx) = node
-                .keys
-                .iter()
-                .position(|node_key| *node_key > key.to_vec())
-            {
-                node.pointers.index(idx)
-            } else {
-                node.pointers.last().unwrap()
-            };
+        let Some(pointer) = tree.find(&mut self.source, key)? else {
+            return Ok(None);
+        };
+
+        let data = self.read_data(pointer)?;
+        let entries = self.index_entries(key, &data)?;
+
+        self.record(Undo::Delete(self.name.clone(), data.clone()));
+
+        tree.remove(&mut self.source, key)?;
+
+        PageHandler::free(&mut self.source, pointer)?;
+
+        self.update_definition(|collection| {
+            collection.root = tree.root;
+            collection.elements -= 1;
+        })?;
+
+        self.index_remove(entries)?;
+
+        Ok(Some(data))
+    }
+
+    pub fn insert_json(&mut self, value: &Value) -> Result<Data, DatabaseError> {
+        let data = match self.generate_key(value)? {
+            Some(value) => json::to_record(&value, &self.keys)?,
+            None => json::to_record(value, &self.keys)?,
+        };
+
+        let data = self.insert(data)?;
 
-            current_node = PageHandler::get_page(&mut self.source, *child_id)?;
+        if self.generation == KeyGeneration::AutoIncrement {
+            self.bump_counter(&data)?;
         }
 
-        if let PageType::Leaf(ref leaf) = current_node.pagetype {
-            let pointer_id = if let Some(idx) = leaf
-                .keys
-                .iter()
-                .position(|leaf_key| *leaf_key == key.to_vec())
-            {
-                leaf.pointers.index(idx)
-            } else {
-                return Ok(None);
-            };
+        Ok(data)
+    }
+
+    // fills in a generated key if the record is missing one and keys are generated
+    fn generate_key(&mut self, value: &Value) -> Result<Option<Value>, DatabaseError> {
+        let [key] = self.keys.as_slice() else {
+            return Ok(None);
+        };
+
+        let name = String::from_utf8_lossy(&key.name).to_string();
+
+        let Some(object) = value.as_object() else {
+            return Ok(None);
+        };
 
-            let data = PageHandler::get_page(&mut self.source, *pointer_id)?;
-            match data.pagetype {
-                PageType::Data(data) => Ok(Some(data)),
-                _ => Err(DatabaseError::UnexpectedPagetype(
-                    "data".to_string(),
-                    "something else".to_string(),
-                )),
+        if object.contains_key(&name) {
+            return Ok(None);
+        }
+
+        let generated = match self.generation {
+            KeyGeneration::None => return Ok(None),
+            KeyGeneration::AutoIncrement => {
+                let counter = self
+                    .definition()?
+                    .counter
+                    .checked_add(1)
+                    .ok_or(DatabaseError::KeyGeneration)?;
+                self.update_definition(|collection| collection.counter = counter)?;
+
+                Value::from(counter)
             }
-        } else {
-            Err(DatabaseError::UnexpectedPagetype(
-                "leaf".to_string(),
-                "node".to_string(),
-            ))
+            KeyGeneration::Ulid => match self.ulids.generate() {
+                Ok(ulid) => Value::from(ulid.to_string()),
+                Err(_) => return Err(DatabaseError::KeyGeneration),
+            },
+        };
+
+        let mut object = object.clone();
+        object.insert(name, generated);
+
+        Ok(Some(Value::Object(object)))
+    }
+
+    // keeps the counter ahead of keys that were given explicitly, so it never hands them out
+    fn bump_counter(&mut self, data: &Data) -> Result<(), DatabaseError> {
+        let Some(field) = data.get_field(&self.keys[0].name) else {
+            return Ok(());
+        };
+
+        let Ok(bytes) = <[u8; 8]>::try_from(field.data.as_slice()) else {
+            return Ok(());
+        };
+
+        let key = u64::from_le_bytes(bytes);
+
+        if key > self.definition()?.counter {
+            self.update_definition(|collection| collection.counter = key)?;
         }
+
+        Ok(())
     }
-}
 
-#[derive(Debug, Error)]
-pub enum DatabaseError {
-    #[error("expected {0}, found {1}")]
-    UnexpectedPagetype(String, String),
+    pub fn update_json(&mut self, value: &Value) -> Result<Data, DatabaseError> {
+        let data = json::to_record(value, &self.keys)?;
 
-    #[error("handler error: {0}")]
-    FileHandlerError(#[from] HandlerError),
-}
+        self.update(data)
+    }
 
-#[cfg(test)]
-mod test {
-    use std::io::Cursor;
+    // stores the record under the key, replacing the record already there. returns the record
+    // and whether it was created. key fields left out of the record are taken from the key
+    pub fn put_json(&mut self, key: &Value, value: &Value) -> Result<(Data, bool), DatabaseError> {
+        let values = json::to_key(key, &self.keys)?;
 
-    use super::*;
-    #[test]
-    fn test() {
-        let file = vec![0x00; 4096];
+        if values.len() != self.keys.len() {
+            return Err(KeyError::Arity(self.keys.len(), values.len()).into());
+        }
 
-        let mut db = DatabaseBuilder::new(Cursor::new(file))
-            .key(b"id".to_vec())
-            .keytype(KeyTypeSize::String(10))
-            .build_mock_string();
+        let data = json::to_record(&json::with_key(value, key, &self.keys)?, &self.keys)?;
 
-        let a = db.get("A".as_bytes()).unwrap().unwrap();
+        let values: Vec<&[u8]> = values.iter().map(Vec::as_slice).collect();
 
-        assert_eq!(a.get_field(b"id").unwrap().get_data(), "A");
-        assert_eq!(a.get_field(b"name").unwrap().get_data(), "jonas");
-        assert_eq!(a.get_field(b"age").unwrap().get_data(), "22");
-        assert_eq!(a.get_field(b"weight").unwrap().get_data(), "87");
+        if record_key(&self.keys, &data)? != key::encode_tuple(&self.keys, &values)? {
+            return Err(DatabaseError::KeyMismatch);
+        }
 
-        let b = db.get("B".as_bytes()).unwrap().unwrap();
+        if self.get_tuple(&values)?.is_some() {
+            return Ok((self.update(data)?, false));
+        }
 
-        assert_eq!(b.get_field(b"id").unwrap().get_data(), "B");
-        assert_eq!(b.get_field(b"name").unwrap().get_data(), "johnny");
-        assert_eq!(b.get_field(b"age").unwrap().get_data(), "30");
-        assert_eq!(b.get_field(b"cpr").unwrap().get_data(), "0101009999");
+        let data = self.insert(data)?;
+
+        if self.generation == KeyGeneration::AutoIncrement {
+            self.bump_counter(&data)?;
+        }
+
+        Ok((data, true))
+    }
+
+    // applies a json merge patch to the record under the key, its key fields can't change
+    pub fn patch_json(&mut self, key: &Value, patch: &Value) -> Result<Data, DatabaseError> {
+        let Some(data) = self.get_json(key)? else {
+            return Err(DatabaseError::NotFound);
+        };
+
+        let mut patched = data.clone();
+        json::patch_data(&mut patched, patch)?;
+
+        if record_key(&self.keys, &patched)? != record_key(&self.keys, &data)? {
+            return Err(DatabaseError::KeyMismatch);
+        }
+
+        self.update(patched)
+    }
+
+    // reads a key given as text, like in a url
+    pub fn parse_key(&self, key: &str) -> Result<Value, DatabaseError> {
+        Ok(json::parse_key(key, &self.keys)?)
+    }
+
+    pub fn delete_json(&mut self, key: &Value) -> Result<Option<Data>, DatabaseError> {
+        let values = json::to_key(key, &self.keys)?;
+
+        self.delete(&values.iter().map(Vec::as_slice).collect::<Vec<_>>())
+    }
+
+    pub fn get_json(&mut self, key: &Value) -> Result<Option<Data>, DatabaseError> {
+        let values = json::to_key(key, &self.keys)?;
+
+        self.get_tuple(&values.iter().map(Vec::as_slice).collect::<Vec<_>>())
+    }
+
+    // gets a record by the values of its key fields, in the order they make up the key
+    pub fn get_tuple(&mut self, values: &[&[u8]]) -> Result<Option<Data>, DatabaseError> {
+        if values.len() != self.keys.len() {
+            return Err(KeyError::Arity(self.keys.len(), values.len()).into());
+        }
+
+        self.get_encoded(&key::encode_tuple(&self.keys, values)?)
+    }
+
+    // returns every record with a key between the two tuples. a tuple with fewer values than
+    // there are key fields matches every key starting with those values
+    pub fn range(
+        &mut self,
+        from: Bound<&[&[u8]]>,
+        to: Bound<&[&[u8]]>,
+    ) -> Result<Vec<Data>, DatabaseError> {
+        self.range_limit(from, to, usize::MAX)
+    }
+
+    // like range, but stops after limit records
+    pub fn range_limit(
+        &mut self,
+        from: Bound<&[&[u8]]>,
+        to: Bound<&[&[u8]]>,
+        limit: usize,
+    ) -> Result<Vec<Data>, DatabaseError> {
+        let start = match from {
+            Bound::Included(values) => Some(key::encode_tuple(&self.keys, values)?),
+            Bound::Excluded(values) => {
+                match key::successor(&key::encode_tuple(&self.keys, values)?) {
+                    Some(successor) => Some(successor),
+                    None => return Ok(Vec::new()),
+                }
+            }
+            Bound::Unbounded => None,
+        };
+
+        let end = match to {
+            Bound::Included(values) => key::successor(&key::encode_tuple(&self.keys, values)?),
+            Bound::Excluded(values) => Some(key::encode_tuple(&self.keys, values)?),
+            Bound::Unbounded => None,
+        };
+
+        self.scan(start, end, limit)
+    }
+
+    // returns up to limit records between two keys given as json like in requests, either
+    // bound includes every key starting with it
+    pub fn range_json(
+        &mut self,
+        from: Option<&Value>,
+        to: Option<&Value>,
+        limit: usize,
+    ) -> Result<Vec<Data>, DatabaseError> {
+        let (start, end) = self.bounds_json(from, to)?;
+
+        self.scan(start, end, limit)
+    }
+
+    // encodes keys given as json into where a scan starts and the key it stops before, so
+    // either bound includes every key starting with it
+    pub fn bounds_json(
+        &self,
+        from: Option<&Value>,
+        to: Option<&Value>,
+    ) -> Result<Bounds, DatabaseError> {
+        let encode = |value: &Value| -> Result<Vec<u8>, DatabaseError> {
+            let values = json::to_key(value, &self.keys)?;
+            let values: Vec<&[u8]> = values.iter().map(Vec::as_slice).collect();
+
+            Ok(key::encode_tuple(&self.keys, &values)?)
+        };
+
+        let start = from.map(encode).transpose()?;
+        let end = to
+            .map(encode)
+            .transpose()?
+            .and_then(|to| key::successor(&to));
+
+        Ok((start, end))
+    }
+
+    // returns every record whose key starts with the given values
+    #[allow(dead_code)]
+    pub fn prefix(&mut self, values: &[&[u8]]) -> Result<Vec<Data>, DatabaseError> {
+        self.range(Bound::Included(values), Bound::Included(values))
+    }
+
+    // walks the leaves from the first key at or after start, up to but not including end
+    fn scan(
+        &mut self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<Data>, DatabaseError> {
+        let entries = self.primary()?.scan_limit(
+            &mut self.source,
+            start.as_deref(),
+            end.as_deref(),
+            limit,
+        )?;
+
+        entries
+            .into_iter()
+            .map(|(_, pointer)| self.read_data(pointer))
+            .collect()
+    }
+
+    // returns up to limit records after the given key, or from the first one, along with the
+    // key of the last record returned when there are more after it
+    pub fn list(
+        &mut self,
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<(Vec<Data>, Option<Vec<u8>>), DatabaseError> {
+        self.page(after.map(key::after).as_deref(), None, limit)
+    }
+
+    // like list, but from the first key at or after start and up to but not including end
+    pub fn page(
+        &mut self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<(Vec<Data>, Option<Vec<u8>>), DatabaseError> {
+        // one more than asked for tells whether there is another page
+        let mut entries =
+            self.primary()?
+                .scan_limit(&mut self.source, start, end, limit.saturating_add(1))?;
+
+        let last = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+
+        let records = entries
+            .into_iter()
+            .map(|(_, pointer)| self.read_data(pointer))
+            .collect::<Result<_, _>>()?;
+
+        Ok((records, last))
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Data>, DatabaseError> {
+        self.get_tuple(&[key])
+    }
+
+    fn get_encoded(&mut self, key: &[u8]) -> Result<Option<Data>, DatabaseError> {
+        match self.primary()?.find(&mut self.source, key)? {
+            Some(pointer) => Ok(Some(self.read_data(pointer)?)),
+            None => Ok(None),
+        }
+    }
+
+    // walks every tree from its root and verifies that every page parses, that keys are sorted
+    // and within the bounds of their parent, that all leaves sit at the same depth and are
+    // chained in order, that every leaf pointer leads to a data page holding its key, that the
+    // indexes match the records and that no page is both in use and free, across all collections.
+    // a record count that is off and pages nothing points to are reported instead of failing,
+    // since an insert or delete cut short leaves exactly those behind and both are harmless
+    pub fn check(&mut self) -> Result<Report, DatabaseError> {
+        let header = HeaderHandler::get(&mut self.source)?;
+
+        let mut visited = HashSet::from([header.catalog]);
+        let mut report = Report::default();
+
+        for collection in self.catalog()?.collections {
+            let found = self.check_collection(&collection, &mut visited)?;
+
+            if collection.elements != found {
+                report.miscounted.push(Miscount {
+                    collection: String::from_utf8_lossy(&collection.name).to_string(),
+                    counted: collection.elements,
+                    found,
+                });
+            }
+        }
+
+        let mut free = header.free;
+
+        while free != 0 {
+            if !visited.insert(free) {
+                return Err(DatabaseError::Integrity(format!(
+                    "free page {free} is in use or freed twice"
+                )));
+            }
+
+            free = match PageHandler::get_page(&mut self.source, free)?.pagetype {
+                PageType::Free(next) => next,
+                _ => {
+                    return Err(DatabaseError::Integrity(format!(
+                        "page {free} is on the free list but is not free"
+                    )));
+                }
+            };
+        }
+
+        let file_size = self
+            .source
+            .seek(SeekFrom::End(0))
+            .map_err(|err| DatabaseError::FileHandlerError(err.into()))?;
+
+        // the header takes up the first block
+        let pages = (file_size / PAGESIZE).saturating_sub(1);
+
+        report.orphans = (0..pages).filter(|page| !visited.contains(page)).collect();
+
+        Ok(report)
+    }
+
+    // checks the file and sets the record count of every collection to what was found
+    pub fn repair(&mut self) -> Result<Report, DatabaseError> {
+        let report = self.check()?;
+
+        if report.miscounted.is_empty() {
+            return Ok(report);
+        }
+
+        let mut catalog = self.catalog()?;
+
+        for miscount in &report.miscounted {
+            for collection in &mut catalog.collections {
+                if String::from_utf8_lossy(&collection.name) == miscount.collection {
+                    collection.elements = miscount.found;
+                }
+            }
+        }
+
+        self.write_catalog(catalog)?;
+
+        Ok(report)
+    }
+
+    // returns the number of records found
+    fn check_collection(
+        &mut self,
+        collection: &Collection,
+        visited: &mut HashSet<u64>,
+    ) -> Result<u64, DatabaseError> {
+        let tree = Tree::new(
+            collection.root,
+            collection.keytype(),
+            collection.order.into(),
+        );
+
+        let entries = tree.check(&mut self.source, visited)?;
+
+        let mut records = Vec::new();
+
+        for (key, pointer) in entries {
+            if !visited.insert(pointer) {
+                return Err(DatabaseError::Integrity(format!(
+                    "data page {pointer} is referenced more than once"
+                )));
+            }
+
+            let data = match PageHandler::get_page(&mut self.source, pointer)?.pagetype {
+                PageType::Data(data) => data,
+                _ => {
+                    return Err(DatabaseError::Integrity(format!(
+                        "leaf points to page {pointer}, which is not a data page"
+                    )));
+                }
+            };
+
+            if data.version > collection.version() || data.version < collection.migrated {
+                return Err(DatabaseError::Integrity(format!(
+                    "data page {pointer} is at version {}, the collection at {} with changes \
+                     before {} dropped",
+                    data.version,
+                    collection.version(),
+                    collection.migrated
+                )));
+            }
+
+            let data = schema::migrate(collection, data);
+
+            if record_key(&collection.keys, &data).ok().as_ref() != Some(&key) {
+                return Err(DatabaseError::Integrity(format!(
+                    "data page {pointer} does not hold the key it is indexed by"
+                )));
+            }
+
+            records.push((key, data));
+        }
+
+        self.check_indexes(&collection.indexes, &records, visited)?;
+
+        Ok(records.len() as u64)
+    }
+}
+
+// what a check found wrong that doesn't make the file unreadable
+#[derive(Debug, Default)]
+pub struct Report {
+    pub miscounted: Vec<Miscount>,
+    pub orphans: Vec<u64>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.miscounted.is_empty() && self.orphans.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Miscount {
+    pub collection: String,
+    pub counted: u64,
+    pub found: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum DatabaseError {
+    #[error("integrity check failed: {0}")]
+    Integrity(String),
+
+    #[error("expected {0}, found {1}")]
+    UnexpectedPagetype(String, String),
+
+    #[error("data is not valid")]
+    InvalidData,
+
+    #[error("data is missing the key field \"{0}\"")]
+    MissingKey(String),
+
+    #[error("expected key of type {0:?}, found {1:?}")]
+    WrongKeytype(KeyType, KeyType),
+
+    #[error("key {0} is longer than {1} bytes ({2})")]
+    KeyTooLong(String, u16, usize),
+
+    #[error("database already contains this key")]
+    DuplicateKey,
+
+    #[error("the key of the record does not match the key it is stored under")]
+    KeyMismatch,
+
+    #[error("key not found")]
+    NotFound,
+
+    #[error("field {0} is already indexed")]
+    IndexExists(String),
+
+    #[error("field {0} is not indexed")]
+    UnknownIndex(String),
+
+    #[error("another record already holds {value} in the unique field {field}")]
+    UniqueViolation { field: String, value: String },
+
+    #[error("collection {0} does not exist")]
+    UnknownCollection(String),
+
+    #[error("collection {0} already exists")]
+    CollectionExists(String),
+
+    #[error("invalid collection: {0}")]
+    InvalidCollection(String),
+
+    #[error("the catalog has no room for another collection")]
+    CatalogFull,
+
+    #[error("record does not match the schema: {}", schema::describe(.0))]
+    Schema(Vec<Violation>),
+
+    #[error("invalid schema: {0}")]
+    InvalidSchema(String),
+
+    #[error("invalid schema change: {0}")]
+    InvalidMigration(String),
+
+    #[error("could not generate a key")]
+    KeyGeneration,
+
+    #[error("{0}")]
+    Json(#[from] JsonError),
+
+    #[error("{0}")]
+    Key(#[from] KeyError),
+
+    #[error("handler error: {0}")]
+    FileHandlerError(#[from] HandlerError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    #[test]
+    fn test() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::String(10))
+            .build_mock_string();
+
+        let a = db.get("A".as_bytes()).unwrap().unwrap();
+
+        assert_eq!(a.get_field(b"id").unwrap().get_data(), "A");
+        assert_eq!(a.get_field(b"name").unwrap().get_data(), "jonas");
+        assert_eq!(a.get_field(b"age").unwrap().get_data(), "22");
+        assert_eq!(a.get_field(b"weight").unwrap().get_data(), "87");
+
+        let b = db.get("B".as_bytes()).unwrap().unwrap();
+
+        assert_eq!(b.get_field(b"id").unwrap().get_data(), "B");
+        assert_eq!(b.get_field(b"name").unwrap().get_data(), "johnny");
+        assert_eq!(b.get_field(b"age").unwrap().get_data(), "30");
+        assert_eq!(b.get_field(b"cpr").unwrap().get_data(), "0101009999");
+    }
+
+    #[test]
+    fn check() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::String(10))
+            .build_mock_string();
+
+        assert!(db.check().unwrap().is_clean());
+
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build_mock_u64();
+
+        assert!(db.check().unwrap().is_clean());
+
+        let mut leaf = PageHandler::get_page(&mut db.source, 2).unwrap();
+        if let PageType::Leaf(leaf) = &mut leaf.pagetype {
+            leaf.keys.reverse();
+        }
+        PageHandler::write(&mut db.source, leaf).unwrap();
+
+        assert!(matches!(db.check(), Err(DatabaseError::Integrity(_))));
+    }
+
+    #[test]
+    fn patch_types() {
+        let mut db = DatabaseBuilder::new(Cursor::new(Vec::new()))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        db.create_index(b"at", false).unwrap();
+        db.insert_json(&serde_json::json!({
+            "id": 1,
+            "at": {"$timestamp": "2024-03-01T11:30:00Z"},
+            "blob": {"$binary": "AAE="},
+            "name": "a",
+        }))
+        .unwrap();
+
+        let key = serde_json::json!(1);
+        let patched = db
+            .patch_json(&key, &serde_json::json!({"name": "b"}))
+            .unwrap();
+
+        // fields the patch doesn't touch are stored and indexed as they were
+        let at = patched.get_field(b"at").unwrap();
+        assert_eq!(at.get_datatype(), KeyType::Timestamp);
+        assert_eq!(
+            patched.get_field(b"blob").unwrap().get_datatype(),
+            KeyType::Bytes
+        );
+
+        let stored = db.get_json(&key).unwrap().unwrap();
+        assert_eq!(
+            stored.get_field(b"at").unwrap().get_datatype(),
+            KeyType::Timestamp
+        );
+        assert_eq!(db.find_by(b"at", at).unwrap().len(), 1);
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn repair() {
+        let mut db = DatabaseBuilder::new(Cursor::new(Vec::new()))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        for id in 0..20_u64 {
+            db.insert_json(&serde_json::json!({"id": id})).unwrap();
+        }
+
+        // an insert cut short after writing its data page and before counting the record
+        let orphan = PageHandler::new_page(
+            &mut db.source,
+            PageType::Data(Data {
+                object: Vec::new(),
+                version: 0,
+            }),
+        )
+        .unwrap();
+        db.update_definition(|collection| collection.elements = 25)
+            .unwrap();
+
+        let report = db.check().unwrap();
+        assert_eq!(report.orphans, vec![orphan.id]);
+        assert_eq!(report.miscounted[0].counted, 25);
+        assert_eq!(report.miscounted[0].found, 20);
+
+        db.repair().unwrap();
+
+        let report = db.check().unwrap();
+        assert!(report.miscounted.is_empty());
+        assert_eq!(report.orphans, vec![orphan.id]);
+        assert_eq!(db.stats().unwrap().collections[0].records, 20);
+    }
+
+    #[test]
+    fn open() {
+        let file = vec![0x00; 4096];
+
+        let db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::String(10))
+            .build_mock_string();
+
+        let mut db = Database::open(db.source).unwrap();
+
+        assert_eq!(db.keys.len(), 1);
+        assert_eq!(db.keys[0].name, b"id".to_vec());
+        assert_eq!(db.keys[0].size, 10);
+        assert_eq!(db.keytype, KeyType::String);
+        assert!(db.check().unwrap().is_clean());
+
+        let c = db.get("C".as_bytes()).unwrap().unwrap();
+        assert_eq!(c.get_field(b"name").unwrap().get_data(), "dam");
+    }
+
+    #[test]
+    fn insert() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::String(10))
+            .build();
+
+        // insert out of order so splits happen on both ends of the tree
+        let ids: Vec<usize> = (0..60).map(|n| (n * 37) % 60).collect();
+
+        for id in &ids {
+            let data = Data {
+                object: vec![
+                    Field::new(
+                        b"id".to_vec(),
+                        KeyType::String,
+                        format!("{id:03}").into_bytes(),
+                    ),
+                    Field::new(
+                        b"value".to_vec(),
+                        KeyType::UInt64,
+                        id.to_le_bytes().to_vec(),
+                    ),
+                ],
+                version: 0,
+            };
+
+            db.insert(data).unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        for id in &ids {
+            let data = db.get(format!("{id:03}").as_bytes()).unwrap().unwrap();
+            assert_eq!(data.get_field(b"value").unwrap().get_data(), id.to_string());
+        }
+
+        let duplicate = Data {
+            object: vec![Field::new(b"id".to_vec(), KeyType::String, b"001".to_vec())],
+            version: 0,
+        };
+        assert!(matches!(
+            db.insert(duplicate),
+            Err(DatabaseError::DuplicateKey)
+        ));
+
+        let missing = Data {
+            object: vec![Field::new(
+                b"name".to_vec(),
+                KeyType::String,
+                b"jonas".to_vec(),
+            )],
+            version: 0,
+        };
+        assert!(matches!(
+            db.insert(missing),
+            Err(DatabaseError::MissingKey(_))
+        ));
+
+        let wrong = Data {
+            object: vec![Field::new(
+                b"id".to_vec(),
+                KeyType::UInt64,
+                1_usize.to_le_bytes().to_vec(),
+            )],
+            version: 0,
+        };
+        assert!(matches!(
+            db.insert(wrong),
+            Err(DatabaseError::WrongKeytype(_, _))
+        ));
+    }
+
+    #[test]
+    fn insert_into_mock() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::String(10))
+            .build_mock_string();
+
+        for id in ["G", "H", "I", "BA", "BB", "BC"] {
+            let data = Data {
+                object: vec![Field::new(
+                    b"id".to_vec(),
+                    KeyType::String,
+                    id.as_bytes().to_vec(),
+                )],
+                version: 0,
+            };
+
+            db.insert(data).unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        assert!(db.get(b"BB").unwrap().is_some());
+        assert!(db.get(b"A").unwrap().is_some());
+        assert!(db.get(b"I").unwrap().is_some());
+    }
+
+    #[test]
+    fn insert_timestamp() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"at".to_vec())
+            .keytype(KeyTypeSize::Timestamp)
+            .build();
+
+        for day in [5, 1, 9, 3, 7, 2, 8, 4, 6] {
+            let value = serde_json::json!({"at": format!("2024-01-0{day}T00:00:00Z"), "day": day});
+            db.insert_json(&value).unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        let found = db
+            .get_json(&serde_json::json!("2024-01-07T00:00:00Z"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.get_field(b"day").unwrap().get_data(), "7");
+    }
+
+    #[test]
+    fn insert_uint64() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        // keys past 255 used to sort before 1 because they were compared little endian
+        let ids: Vec<u64> = (0..600).map(|n| (n * 7919) % 600).collect();
+
+        for id in &ids {
+            db.insert_json(&serde_json::json!({"id": id})).unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        for id in &ids {
+            assert!(db.get(&id.to_le_bytes()).unwrap().is_some());
+        }
+
+        assert!(db.get(&600_u64.to_le_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn composite_key() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .keys(vec![
+                (b"tenant".to_vec(), KeyTypeSize::UInt64),
+                (b"record".to_vec(), KeyTypeSize::String(10)),
+            ])
+            .build();
+
+        for tenant in [3_u64, 1, 2] {
+            for record in ["c", "a", "e", "b", "d"] {
+                let value = serde_json::json!({"tenant": tenant, "record": record});
+                db.insert_json(&value).unwrap();
+            }
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        let two = 2_u64.to_le_bytes();
+        let three = 3_u64.to_le_bytes();
+
+        assert!(db.get_tuple(&[&two, b"c"]).unwrap().is_some());
+        assert!(db.get_tuple(&[&two, b"f"]).unwrap().is_none());
+        assert!(db.get_tuple(&[&two]).is_err());
+
+        let records = |found: Vec<Data>| -> Vec<String> {
+            found
+                .iter()
+                .map(|data| {
+                    let tenant = data.get_field(b"tenant").unwrap().get_data();
+                    let record = data.get_field(b"record").unwrap().get_data();
+                    format!("{tenant}{record}")
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            records(db.prefix(&[&two]).unwrap()),
+            vec!["2a", "2b", "2c", "2d", "2e"]
+        );
+        assert_eq!(
+            records(
+                db.range(
+                    Bound::Excluded(&[&two, b"d"]),
+                    Bound::Included(&[&three, b"b"])
+                )
+                .unwrap()
+            ),
+            vec!["2e", "3a", "3b"]
+        );
+        assert_eq!(
+            records(
+                db.range(Bound::Unbounded, Bound::Excluded(&[&two]))
+                    .unwrap()
+            )
+            .len(),
+            5
+        );
+
+        let found = db.get_json(&serde_json::json!([1, "e"])).unwrap().unwrap();
+        assert_eq!(found.get_field(b"record").unwrap().get_data(), "e");
+        assert!(db.get_json(&serde_json::json!([1, "e", 2])).is_err());
+    }
+
+    #[test]
+    fn records() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .keys(vec![
+                (b"tenant".to_vec(), KeyTypeSize::UInt64),
+                (b"record".to_vec(), KeyTypeSize::String(10)),
+            ])
+            .build();
+
+        let key = db.parse_key(r#"[1,"a"]"#).unwrap();
+
+        let (data, created) = db.put_json(&key, &serde_json::json!({"n": 1})).unwrap();
+        assert!(created);
+        assert_eq!(data.get_field(b"record").unwrap().get_data(), "a");
+
+        let (_, created) = db
+            .put_json(&key, &serde_json::json!({"n": 2, "m": 3}))
+            .unwrap();
+        assert!(!created);
+
+        assert!(matches!(
+            db.put_json(&key, &serde_json::json!({"record": "b"})),
+            Err(DatabaseError::KeyMismatch)
+        ));
+
+        let patched = db
+            .patch_json(&key, &serde_json::json!({"n": 5, "m": null}))
+            .unwrap();
+        assert_eq!(patched.get_field(b"n").unwrap().get_data(), "5");
+        assert!(patched.get_field(b"m").is_none());
+
+        assert!(matches!(
+            db.patch_json(&key, &serde_json::json!({"tenant": 2})),
+            Err(DatabaseError::KeyMismatch)
+        ));
+        assert!(matches!(
+            db.patch_json(&serde_json::json!([1, "z"]), &serde_json::json!({})),
+            Err(DatabaseError::NotFound)
+        ));
+
+        for (tenant, record) in [(1, "b"), (1, "c"), (2, "a"), (3, "a")] {
+            let value = serde_json::json!({"tenant": tenant, "record": record});
+            db.insert_json(&value).unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        let from = serde_json::json!([1, "b"]);
+        let to = serde_json::json!([2]);
+
+        assert_eq!(db.range_json(Some(&from), Some(&to), 10).unwrap().len(), 3);
+        assert_eq!(db.range_json(Some(&from), None, 2).unwrap().len(), 2);
+        assert_eq!(db.range_json(None, None, 100).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn list() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        for id in (1..=25_u64).rev() {
+            db.insert_json(&serde_json::json!({"id": id})).unwrap();
+        }
+
+        let mut ids = Vec::new();
+        let mut pages = 0;
+        let mut after = None;
+
+        loop {
+            let (records, last) = db.list(after.as_deref(), 10).unwrap();
+
+            ids.extend(
+                records
+                    .iter()
+                    .map(|data| data.get_field(b"id").unwrap().get_data()),
+            );
+            pages += 1;
+
+            match last {
+                Some(last) => after = Some(last),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages, 3);
+        assert_eq!(ids, (1..=25).map(|id| id.to_string()).collect::<Vec<_>>());
+
+        // a full last page doesn't lead to an empty one
+        let (records, last) = db.list(None, 25).unwrap();
+        assert_eq!((records.len(), last), (25, None));
+
+        let (start, end) = db
+            .bounds_json(Some(&serde_json::json!(5)), Some(&serde_json::json!(12)))
+            .unwrap();
+
+        let (records, last) = db.page(start.as_deref(), end.as_deref(), 5).unwrap();
+        assert_eq!(records.len(), 5);
+
+        let start = key::after(&last.unwrap());
+        let (records, last) = db.page(Some(&start), end.as_deref(), 5).unwrap();
+        assert_eq!((records.len(), last), (3, None));
+    }
+
+    #[test]
+    fn generate_auto_increment() {
+        let file = vec![0x00; 4096];
+
+        let db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .generate(KeyGeneration::AutoIncrement)
+            .build_mock_u64();
+
+        // the counter survives reopening the database
+        let mut db = Database::open(db.source).unwrap();
+
+        let data = db.insert_json(&serde_json::json!({"name": "a"})).unwrap();
+        assert_eq!(data.get_field(b"id").unwrap().get_data(), "7");
+
+        // explicit keys move the counter past them
+        db.insert_json(&serde_json::json!({"id": 10, "name": "b"}))
+            .unwrap();
+
+        let data = db.insert_json(&serde_json::json!({"name": "c"})).unwrap();
+        assert_eq!(data.get_field(b"id").unwrap().get_data(), "11");
+
+        assert!(db.check().unwrap().is_clean());
+        assert!(db.get(&11_u64.to_le_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn generate_ulid() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::String(26))
+            .generate(KeyGeneration::Ulid)
+            .build();
+
+        let ids: Vec<String> = (0..20)
+            .map(|n| {
+                let data = db.insert_json(&serde_json::json!({"n": n})).unwrap();
+                data.get_field(b"id").unwrap().get_data()
+            })
+            .collect();
+
+        assert!(ids.iter().all(|id| id.len() == 26));
+        assert!(ids.is_sorted());
+
+        assert!(db.check().unwrap().is_clean());
+
+        let first = db.range(Bound::Unbounded, Bound::Unbounded).unwrap();
+        assert_eq!(first[0].get_field(b"n").unwrap().get_data(), "0");
+    }
+
+    #[test]
+    #[should_panic]
+    fn generate_wrong_keytype() {
+        let file = vec![0x00; 4096];
+
+        DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::String(10))
+            .generate(KeyGeneration::Ulid)
+            .build();
+    }
+
+    #[test]
+    fn long_string_keys() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"url".to_vec())
+            .keytype(KeyTypeSize::String(MAX_STRING_KEY_SIZE))
+            .build();
+
+        assert_eq!(db.order, 2);
+
+        let path = "segment/".repeat(100);
+        let urls: Vec<String> = (0..40)
+            .map(|n| format!("https://example.com/{}/{path}", (n * 17) % 40))
+            .collect();
+
+        for url in &urls {
+            db.insert_json(&serde_json::json!({"url": url})).unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+
+        for url in &urls {
+            assert!(db.get(url.as_bytes()).unwrap().is_some());
+        }
+
+        // separators only keep enough of a key to tell the leaves apart
+        let PageType::Node(root) = db.get_root().unwrap().pagetype else {
+            panic!()
+        };
+        assert!(root.keys.iter().all(|key| key.len() < urls[0].len()));
+
+        let url = format!("https://example.com/{}", "a".repeat(1100));
+        assert!(matches!(
+            db.insert_json(&serde_json::json!({"url": url})),
+            Err(DatabaseError::Json(JsonError::KeyTooLong(_, 1024, 1120)))
+        ));
+        assert!(matches!(
+            db.insert(json::to_data(&serde_json::json!({"url": url})).unwrap()),
+            Err(DatabaseError::KeyTooLong(_, 1024, 1120))
+        ));
+    }
+
+    #[test]
+    fn collections() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build_mock_u64();
+
+        db.create_collection(
+            b"urls",
+            vec![(b"url".to_vec(), KeyTypeSize::String(100))],
+            KeyGeneration::None,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            db.create_collection(
+                b"urls",
+                vec![(b"id".to_vec(), KeyTypeSize::UInt64)],
+                KeyGeneration::None
+            ),
+            Err(DatabaseError::CollectionExists(_))
+        ));
+        assert!(matches!(
+            db.create_collection(
+                b"ids",
+                vec![(b"id".to_vec(), KeyTypeSize::UInt64)],
+                KeyGeneration::Ulid
+            ),
+            Err(DatabaseError::InvalidCollection(_))
+        ));
+
+        assert_eq!(db.collections().unwrap(), vec!["default", "urls"]);
+
+        db.use_collection(b"urls").unwrap();
+        db.create_index(b"host", false).unwrap();
+
+        for n in 0..30 {
+            let value = serde_json::json!({"url": format!("https://{n}.dk"), "host": n % 3});
+            db.insert_json(&value).unwrap();
+        }
+
+        // the collections share the file, but not their records
+        assert!(db.get(&1_u64.to_le_bytes()).unwrap().is_none());
+        assert!(db.get(b"https://7.dk").unwrap().is_some());
+
+        db.use_collection(b"default").unwrap();
+        assert!(db.get(&1_u64.to_le_bytes()).unwrap().is_some());
+        assert!(db.get(b"https://7.dk").is_err());
+
+        assert!(db.check().unwrap().is_clean());
+
+        // the file survives reopening with every collection in it
+        let mut db = Database::open(db.source).unwrap();
+        assert_eq!(db.keytype, KeyType::UInt64);
+        db.use_collection(b"urls").unwrap();
+        assert_eq!(
+            db.range(Bound::Unbounded, Bound::Unbounded).unwrap().len(),
+            30
+        );
+
+        let size = db.source.get_ref().len();
+
+        db.drop_collection(b"urls").unwrap();
+        assert_eq!(db.collections().unwrap(), vec!["default"]);
+        assert!(matches!(
+            db.get(b"https://7.dk"),
+            Err(DatabaseError::UnknownCollection(_))
+        ));
+        assert!(db.check().unwrap().is_clean());
+
+        // the pages of a dropped collection are reused
+        db.create_collection(
+            b"urls",
+            vec![(b"url".to_vec(), KeyTypeSize::String(100))],
+            KeyGeneration::None,
+        )
+        .unwrap();
+        db.use_collection(b"urls").unwrap();
+
+        for n in 0..30 {
+            db.insert_json(&serde_json::json!({"url": format!("https://{n}.dk")}))
+                .unwrap();
+        }
+
+        assert!(db.check().unwrap().is_clean());
+        assert_eq!(db.source.get_ref().len(), size);
     }
 }
@@ -1,13 +1,24 @@
+use crate::database::{json, key};
+use base64::prelude::*;
+use chrono::{DateTime, SecondsFormat, Utc};
 use nom::Parser;
 use nom::multi::{count, length_count};
-use nom::number::{Endianness, u8, u64};
-use serde::ser::{Serialize, SerializeMap, Serializer};
+use nom::number::{Endianness, u8, u16, u64};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{self as ser, Serialize, SerializeMap, SerializeSeq, Serializer};
+use serde_json::Value;
 use thiserror::Error;
 
 // NOTE: LITTLE ENDIAN BYTES
-const PAGESIZE: u64 = 4096;
+pub const PAGESIZE: u64 = 4096;
 type Id = u64;
 
+// the key of the single entry object binary values are written as in json
+pub const BINARY_TAG: &str = "$binary";
+
+// the key of the single entry object timestamps are written as in json
+pub const TIMESTAMP_TAG: &str = "$timestamp";
+
 pub trait SerializeDeserialize: Sized {
     fn serialize(self) -> Vec<u8>;
     fn deserialize(bytes: &[u8]) -> Result<Self, FileError>;
@@ -15,12 +26,11 @@ pub trait SerializeDeserialize: Sized {
 
 #[derive(Debug)]
 pub struct Header {
-    pub elements: u64,
-    pub keytype: KeyType,
-    pub keytype_size: u8,
-    pub key: Vec<u8>,
-    pub root: Id,
-    pub order: u8,
+    // the page listing the collections in the file
+    pub catalog: Id,
+
+    // the first page of the list of freed pages, 0 if there are none
+    pub free: Id,
 }
 
 impl SerializeDeserialize for Header {
@@ -29,65 +39,459 @@ impl SerializeDeserialize for Header {
             return Err(FileError::Pagesize(PAGESIZE as usize, bytes.len()));
         }
 
-        let (_, (elements, keytype, keytype_size, key, root, order)) = (
+        let (_, (catalog, free)) =
+            (u64(Endianness::Little), u64(Endianness::Little)).parse(bytes)?;
+
+        Ok(Header { catalog, free })
+    }
+
+    fn serialize(self) -> Vec<u8> {
+        let mut b = Vec::<u8>::new();
+
+        b.extend(self.catalog.to_le_bytes());
+        b.extend(self.free.to_le_bytes());
+
+        b
+    }
+}
+
+// a named tree of records with its own key and indexes, listed in the catalog
+#[derive(Debug, Clone)]
+pub struct Collection {
+    pub name: Vec<u8>,
+    pub elements: u64,
+    pub keys: Vec<KeyField>,
+    pub root: Id,
+    pub order: u8,
+    pub generation: KeyGeneration,
+
+    // the last key handed out when keys are auto-incremented
+    pub counter: u64,
+    pub indexes: Vec<Index>,
+
+    // collections without a schema take any record
+    pub schema: Option<Schema>,
+
+    // changes every record has been through, which are dropped to keep the catalog small
+    pub migrated: u16,
+
+    // every change made to the shape of the records since, in order. records are at the version
+    // of the number of changes they have been through, the dropped ones included
+    pub migrations: Vec<Migration>,
+}
+
+impl Collection {
+    pub fn version(&self) -> u16 {
+        let pending = u16::try_from(self.migrations.len()).expect("couldnt parse migrations len");
+
+        self.migrated + pending
+    }
+
+    // the type nodes and leaves store their keys as, composite keys are variable length
+    pub fn keytype(&self) -> KeyType {
+        tree_keytype(&self.keys)
+    }
+}
+
+pub fn tree_keytype(keys: &[KeyField]) -> KeyType {
+    match keys {
+        [key] => key.keytype,
+        _ => KeyType::Bytes,
+    }
+}
+
+// the most keys a node or leaf can hold when every key is as long as it is allowed to get
+pub fn max_order(keys: &[KeyField]) -> usize {
+    let key_len = keys
+        .iter()
+        .map(|key| match key.keytype.size() {
+            Some(size) => size,
+            // every byte of a string could be escaped, plus the terminator
+            None => 2 * key.size as usize + 2,
+        })
+        .sum::<usize>()
+        .min(key::MAX_KEY_LEN);
+
+    // each key takes its length, the key and a pointer, besides the counts, keytype and the
+    // trailing pointer of the page
+    (PAGESIZE_NO_HEADER - 32) / (2 + key_len + ID_SIZE)
+}
+
+// a secondary index on a field, its tree maps the field value followed by the primary key to
+// nothing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Index {
+    pub field: Vec<u8>,
+    pub root: Id,
+
+    // no two records may hold the same value in the field
+    pub unique: bool,
+}
+
+// the declared fields of a collection, records may hold fields beyond these
+#[derive(Debug, Clone)]
+pub struct Schema {
+    pub fields: Vec<SchemaField>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SchemaField {
+    pub name: Vec<u8>,
+    pub fieldtype: KeyType,
+    pub required: bool,
+
+    // the most bytes a string or binary value, or elements an array, may hold
+    pub max_len: Option<u16>,
+
+    // filled in when a record leaves the field out
+    pub default: Option<Field>,
+}
+
+impl SerializeDeserialize for Schema {
+    fn serialize(self) -> Vec<u8> {
+        let mut b = Vec::<u8>::new();
+
+        b.push(
+            self.fields
+                .len()
+                .try_into()
+                .expect("couldnt parse schema fields len"),
+        );
+
+        for field in self.fields {
+            b.push(field.name.len().try_into().expect("couldnt parse name len"));
+            b.extend(field.name);
+            b.push(field.fieldtype.tag());
+            b.push(field.required as u8);
+
+            // 0 means there is no limit
+            b.extend(field.max_len.unwrap_or(0).to_le_bytes());
+
+            match field.default {
+                Some(default) => {
+                    b.push(0x01);
+                    b.extend(default.serialize());
+                }
+                None => b.push(0x00),
+            }
+        }
+
+        b
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Schema, FileError> {
+        let (mut input, fields_len) = u8().parse(bytes)?;
+
+        let mut fields = Vec::new();
+
+        for _ in 0..fields_len {
+            let (rest, (name, fieldtype, required, max_len, has_default)) = (
+                length_count(u8(), u8()),
+                u8(),
+                u8(),
+                u16(Endianness::Little),
+                u8(),
+            )
+                .parse(input)?;
+
+            input = rest;
+
+            let default = match has_default {
+                0x00 => None,
+                _ => {
+                    let (rest, default) =
+                        length_count(u16(Endianness::Little), u8()).parse(input)?;
+                    input = rest;
+
+                    Some(<Field as SerializeDeserialize>::deserialize(&default)?)
+                }
+            };
+
+            fields.push(SchemaField {
+                name,
+                fieldtype: KeyType::try_from(fieldtype)?,
+                required: required == 0x01,
+                max_len: (max_len != 0).then_some(max_len),
+                default,
+            });
+        }
+
+        Ok(Schema { fields })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Migration {
+    Add(Field),               // 0x01, the field holds the name and default value
+    Drop(Vec<u8>),            // 0x02
+    Rename(Vec<u8>, Vec<u8>), // 0x03
+}
+
+impl SerializeDeserialize for Migration {
+    fn serialize(self) -> Vec<u8> {
+        let mut b = Vec::<u8>::new();
+
+        match self {
+            Migration::Add(field) => {
+                b.push(0x01);
+                b.extend(field.serialize());
+            }
+            Migration::Drop(name) => {
+                b.push(0x02);
+                b.push(name.len().try_into().expect("couldnt parse name len"));
+                b.extend(name);
+            }
+            Migration::Rename(from, to) => {
+                b.push(0x03);
+                b.push(from.len().try_into().expect("couldnt parse name len"));
+                b.extend(from);
+                b.push(to.len().try_into().expect("couldnt parse name len"));
+                b.extend(to);
+            }
+        }
+
+        b
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Migration, FileError> {
+        let (input, tag) = u8().parse(bytes)?;
+
+        match tag {
+            0x01 => {
+                let (_, field) = length_count(u16(Endianness::Little), u8()).parse(input)?;
+
+                Ok(Migration::Add(
+                    <Field as SerializeDeserialize>::deserialize(&field)?,
+                ))
+            }
+            0x02 => Ok(Migration::Drop(length_count(u8(), u8()).parse(input)?.1)),
+            0x03 => {
+                let (_, (from, to)) =
+                    (length_count(u8(), u8()), length_count(u8(), u8())).parse(input)?;
+
+                Ok(Migration::Rename(from, to))
+            }
+            tag => Err(FileError::Migration(tag)),
+        }
+    }
+}
+
+// one field of the primary key, composite keys are made up of several in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyField {
+    pub name: Vec<u8>,
+    pub keytype: KeyType,
+    pub size: u16,
+}
+
+impl SerializeDeserialize for Collection {
+    fn deserialize(bytes: &[u8]) -> Result<Collection, FileError> {
+        let (input, (name, elements, keys, root, order, generation, counter)) = (
+            length_count(u8(), u8()),
+            u64(Endianness::Little),
+            length_count(
+                u8(),
+                (u8(), u16(Endianness::Little), length_count(u8(), u8())),
+            ),
             u64(Endianness::Little),
             u8(),
             u8(),
-            length_count(u8(), u8()),
             u64(Endianness::Little),
-            u8(),
         )
             .parse(bytes)?;
 
-        let keytype = match keytype {
-            0x01 => KeyType::String,
-            0x02 => KeyType::UInt64,
-            _ => return Err(FileError::Keytype(keytype)),
+        let (input, (indexes, has_schema)) = (
+            length_count(
+                u8(),
+                (length_count(u8(), u8()), u64(Endianness::Little), u8()),
+            ),
+            u8(),
+        )
+            .parse(input)?;
+
+        let (input, (migrated, migrations)) = (
+            u16(Endianness::Little),
+            length_count(
+                u16(Endianness::Little),
+                length_count(u16(Endianness::Little), u8()),
+            ),
+        )
+            .parse(input)?;
+
+        let migrations: Result<Vec<Migration>, FileError> = migrations
+            .into_iter()
+            .map(|migration| Migration::deserialize(&migration))
+            .collect();
+
+        let schema = match has_schema {
+            0x00 => None,
+            _ => Some(Schema::deserialize(input)?),
         };
 
-        Ok(Header {
+        let keys: Result<Vec<KeyField>, FileError> = keys
+            .into_iter()
+            .map(|(keytype, size, name)| {
+                Ok(KeyField {
+                    name,
+                    keytype: KeyType::try_from(keytype)?,
+                    size,
+                })
+            })
+            .collect();
+
+        Ok(Collection {
+            name,
             elements,
-            keytype,
-            keytype_size,
-            key,
+            keys: keys?,
             root,
             order,
+            generation: KeyGeneration::try_from(generation)?,
+            counter,
+            indexes: indexes
+                .into_iter()
+                .map(|(field, root, unique)| Index {
+                    field,
+                    root,
+                    unique: unique == 0x01,
+                })
+                .collect(),
+            schema,
+            migrated,
+            migrations: migrations?,
         })
     }
 
     fn serialize(self) -> Vec<u8> {
         let mut b = Vec::<u8>::new();
 
-        b.extend(self.elements.to_le_bytes());
+        b.push(self.name.len().try_into().expect("couldnt parse name len"));
+        b.extend(self.name);
 
-        match self.keytype {
-            KeyType::String => {
-                b.push(0x01);
-                b.push(self.keytype_size);
-            }
-            KeyType::UInt64 => {
-                b.push(0x02);
-                b.push(self.keytype_size);
-            }
-        }
+        b.extend(self.elements.to_le_bytes());
 
-        b.push(self.key.len().try_into().expect("couldnt parse key len"));
+        b.push(self.keys.len().try_into().expect("couldnt parse keys len"));
 
-        b.extend(self.key);
+        for key in self.keys {
+            b.push(key.keytype.tag());
+            b.extend(key.size.to_le_bytes());
+            b.push(key.name.len().try_into().expect("couldnt parse key len"));
+            b.extend(key.name);
+        }
 
         b.extend(self.root.to_le_bytes());
 
         b.push(self.order);
 
+        b.push(self.generation.tag());
+
+        b.extend(self.counter.to_le_bytes());
+
+        b.push(
+            self.indexes
+                .len()
+                .try_into()
+                .expect("couldnt parse indexes len"),
+        );
+
+        for index in self.indexes {
+            b.push(
+                index
+                    .field
+                    .len()
+                    .try_into()
+                    .expect("couldnt parse index len"),
+            );
+            b.extend(index.field);
+            b.extend(index.root.to_le_bytes());
+            b.push(index.unique as u8);
+        }
+
+        b.push(self.schema.is_some() as u8);
+
+        // migrations come before the schema, which runs to the end of the collection
+        b.extend(self.migrated.to_le_bytes());
+        b.extend(
+            u16::try_from(self.migrations.len())
+                .expect("couldnt parse migrations len")
+                .to_le_bytes(),
+        );
+
+        for migration in self.migrations {
+            let bytes = migration.serialize();
+            b.extend(
+                u16::try_from(bytes.len())
+                    .expect("couldnt parse migration len")
+                    .to_le_bytes(),
+            );
+            b.extend(bytes);
+        }
+
+        if let Some(schema) = self.schema {
+            b.extend(schema.serialize());
+        }
+
         b
     }
 }
 
+// lists every collection in the file, it lives on a page of its own
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    pub collections: Vec<Collection>,
+}
+
+impl SerializeDeserialize for Catalog {
+    fn serialize(self) -> Vec<u8> {
+        let mut b = vec![0x05];
+
+        b.push(
+            self.collections
+                .len()
+                .try_into()
+                .expect("couldnt parse collections len"),
+        );
+
+        for collection in self.collections {
+            let bytes = collection.serialize();
+            b.extend(
+                u16::try_from(bytes.len())
+                    .expect("couldnt parse collection len")
+                    .to_le_bytes(),
+            );
+            b.extend(bytes);
+        }
+
+        b
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<Catalog, FileError> {
+        let (input, collections_len) = u8().parse(bytes)?;
+
+        let (_, collections) = count(
+            length_count(u16(Endianness::Little), u8()),
+            collections_len.into(),
+        )
+        .parse(input)?;
+
+        let collections: Result<Vec<Collection>, FileError> = collections
+            .into_iter()
+            .map(|c| Collection::deserialize(&c))
+            .collect();
+
+        Ok(Catalog {
+            collections: collections?,
+        })
+    }
+}
+
 const ID_SIZE: usize = size_of::<u64>();
 const PAGETYPE_SIZE: usize = size_of::<u8>();
 
 const PAGESIZE_NO_HEADER: usize = PAGESIZE as usize - ID_SIZE - PAGETYPE_SIZE;
 
+// the largest field a data page has room for next to its field count and length prefix
+pub const MAX_FIELD_LEN: usize = PAGESIZE_NO_HEADER - 3;
+
 #[derive(Debug, Clone)]
 pub struct Page {
     pub id: Id,
@@ -95,21 +499,71 @@ pub struct Page {
 }
 
 impl Page {
-    pub fn split(&mut self, new_id: Id) -> Result<Page, FileError> {
-        let new_page = match &mut self.pagetype {
-            PageType::Node(node) => Page {
-                id: new_id,
-                pagetype: PageType::Node(node.split()),
-            },
-            PageType::Leaf(leaf) => Page {
+    // splits the page in two, returning the key that separates them in the parent node
+    pub fn split(&mut self, new_id: Id) -> Result<(Vec<u8>, Page), FileError> {
+        let (separator, pagetype) = match &mut self.pagetype {
+            PageType::Node(node) => {
+                let mut new_node = node.split();
+                (new_node.keys.remove(0), PageType::Node(new_node))
+            }
+            PageType::Leaf(leaf) => {
+                let mut new_leaf = leaf.split();
+                new_leaf.set_next_leaf_pointer(leaf.next_leaf_pointer);
+                leaf.set_next_leaf_pointer(new_id);
+
+                let separator = leaf.separator(&new_leaf);
+
+                (separator, PageType::Leaf(new_leaf))
+            }
+            PageType::Data(_) => return Err(FileError::Pagetype(0x03)),
+            PageType::Free(_) => return Err(FileError::Pagetype(0x04)),
+            PageType::Catalog(_) => return Err(FileError::Pagetype(0x05)),
+        };
+
+        Ok((
+            separator,
+            Page {
                 id: new_id,
-                pagetype: PageType::Leaf(leaf.split()),
+                pagetype,
             },
-            // TODO: return error,
-            _ => todo!(),
-        };
+        ))
+    }
+
+    // merges the right sibling into this page, the separator between them comes down from the
+    // parent when merging nodes
+    pub fn merge(&mut self, right: Page, separator: Vec<u8>) -> Result<(), FileError> {
+        match (&mut self.pagetype, right.pagetype) {
+            (PageType::Node(node), PageType::Node(right)) => {
+                node.keys.push(separator);
+                node.keys.extend(right.keys);
+                node.pointers.extend(right.pointers);
+            }
+            (PageType::Leaf(leaf), PageType::Leaf(right)) => {
+                leaf.keys.extend(right.keys);
+                leaf.pointers.extend(right.pointers);
+                leaf.set_next_leaf_pointer(right.next_leaf_pointer);
+            }
+            _ => return Err(FileError::Pagetype(0x03)),
+        }
+
+        Ok(())
+    }
+
+    pub fn keys_len(&self) -> usize {
+        match &self.pagetype {
+            PageType::Node(node) => node.keys.len(),
+            PageType::Leaf(leaf) => leaf.keys.len(),
+            PageType::Data(_) | PageType::Free(_) | PageType::Catalog(_) => 0,
+        }
+    }
 
-        Ok(new_page)
+    // whether the page holds more keys than the order allows or no longer fits on disk
+    pub fn overflows(&self, order: usize) -> bool {
+        self.keys_len() > order || !self.fits()
+    }
+
+    pub fn fits(&self) -> bool {
+        self.clone().serialize().len() <= PAGESIZE as usize
     }
 }
 
@@ -124,7 +578,9 @@ impl SerializeDeserialize for Page {
         let pagetype = match pagetype {
             0x01 => PageType::Node(Node::deserialize(input)?),
             0x02 => PageType::Leaf(Leaf::deserialize(input)?),
-            0x03 => PageType::Data(Data::deserialize(input)?),
+            0x03 => PageType::Data(<Data as SerializeDeserialize>::deserialize(input)?),
+            0x04 => PageType::Free(u64(Endianness::Little).parse(input)?.1),
+            0x05 => PageType::Catalog(Catalog::deserialize(input)?),
             _ => return Err(FileError::Pagetype(pagetype)),
         };
 
@@ -140,6 +596,8 @@ impl SerializeDeserialize for Page {
             PageType::Node(node) => node.serialize(),
             PageType::Leaf(leaf) => leaf.serialize(),
             PageType::Data(data) => data.serialize(),
+            PageType::Free(next) => [0x04].into_iter().chain(next.to_le_bytes()).collect(),
+            PageType::Catalog(catalog) => catalog.serialize(),
         });
 
         b
@@ -162,6 +620,18 @@ impl Node {
         }
     }
 
+    pub fn child(&self, key: &[u8]) -> Id {
+        if let Some(idx) = self
+            .keys
+            .iter()
+            .position(|node_key| node_key.as_slice() > key)
+        {
+            self.pointers[idx]
+        } else {
+            *self.pointers.last().expect("node has no pointers")
+        }
+    }
+
     pub fn split(&mut self) -> Node {
         let mut new_node = Node::new(self.keytype);
 
@@ -187,15 +657,15 @@ impl SerializeDeserialize for Node {
 
         let (input, (keys_len, keytype)) = (u8(), u8()).parse(bytes)?;
 
-        let keytype = match keytype {
-            0x01 => KeyType::String,
-            0x02 => KeyType::UInt64,
-            _ => return Err(FileError::Keytype(keytype)),
-        };
+        let keytype = KeyType::try_from(keytype)?;
 
-        let (input, keys) = match keytype {
-            KeyType::String => count(length_count(u8(), u8()), keys_len as usize).parse(input)?,
-            KeyType::UInt64 => count(count(u8(), 8), keys_len as usize).parse(input)?,
+        let (input, keys) = match keytype.size() {
+            Some(size) => count(count(u8(), size), keys_len as usize).parse(input)?,
+            None => count(
+                length_count(u16(Endianness::Little), u8()),
+                keys_len as usize,
+            )
+            .parse(input)?,
         };
 
         let (_, pointers) = count(u64(Endianness::Little), keys_len as usize + 1).parse(input)?;
@@ -219,20 +689,16 @@ impl SerializeDeserialize for Node {
                 .expect("failed to write keys_len"),
         );
 
-        match self.keytype {
-            KeyType::String => {
-                b.push(0x01);
-                for key in self.keys {
-                    b.push(key.len() as u8);
-                    b.extend(key);
-                }
-            }
-            KeyType::UInt64 => {
-                b.push(0x02);
-                for key in self.keys {
-                    b.extend(key);
-                }
+        b.push(self.keytype.tag());
+        for key in self.keys {
+            if self.keytype.size().is_none() {
+                b.extend(
+                    u16::try_from(key.len())
+                        .expect("couldnt parse key len")
+                        .to_le_bytes(),
+                );
             }
+            b.extend(key);
         }
 
         for p in self.pointers {
@@ -269,20 +735,18 @@ impl Leaf {
             new_leaf.pointers.push(self.pointers.pop().unwrap());
         }
 
-        if !new_leaf.keys.is_sorted() && !new_leaf.pointers.is_sorted() {
-            new_leaf.keys.reverse();
-            new_leaf.pointers.reverse();
-        }
+        new_leaf.keys.reverse();
+        new_leaf.pointers.reverse();
 
         new_leaf
     }
 
-    fn from_node(node: Node) -> Leaf {
-        Leaf {
-            keytype: node.keytype,
-            keys: node.keys,
-            pointers: node.pointers,
-            next_leaf_pointer: 0x00,
+    // the separator only has to fall between this leaf and the one to its right, not be a whole
+    // key. fixed size keys are stored without a length so they are kept whole
+    pub fn separator(&self, right: &Leaf) -> Vec<u8> {
+        match (self.keys.last(), self.keytype.size()) {
+            (Some(last), None) => key::separator(last, &right.keys[0]),
+            _ => right.keys[0].clone(),
         }
     }
 
@@ -296,22 +760,17 @@ impl SerializeDeserialize for Leaf {
         let mut b = Vec::new();
         b.push(0x02);
 
-        match self.keytype {
-            KeyType::String => {
-                b.push(0x01);
-                b.push(u8::try_from(self.keys.len()).expect("couldnt parse keys_len"));
-                for key in &self.keys {
-                    b.push(key.len() as u8);
-                    b.extend(key);
-                }
-            }
-            KeyType::UInt64 => {
-                b.push(0x02);
-                b.push(u8::try_from(self.keys.len()).expect("couldnt parse keys_len"));
-                for key in &self.keys {
-                    b.extend(key);
-                }
+        b.push(self.keytype.tag());
+        b.push(u8::try_from(self.keys.len()).expect("couldnt parse keys_len"));
+        for key in &self.keys {
+            if self.keytype.size().is_none() {
+                b.extend(
+                    u16::try_from(key.len())
+                        .expect("couldnt parse key len")
+                        .to_le_bytes(),
+                );
             }
+            b.extend(key);
         }
 
         for p in self.pointers {
@@ -330,18 +789,16 @@ impl SerializeDeserialize for Leaf {
 
         let (input, (keytype, keys_len)) = (u8(), u8()).parse(bytes)?;
 
-        let keytype = match keytype {
-            0x01 => KeyType::String,
-            0x02 => KeyType::UInt64,
-            _ => return Err(FileError::Keytype(keytype)),
-        };
+        let keytype = KeyType::try_from(keytype)?;
 
         let (input, keys) = match keys_len == 0 {
-            false => match keytype {
-                KeyType::String => {
-                    count(length_count(u8(), u8()), keys_len as usize).parse(input)?
-                }
-                KeyType::UInt64 => count(count(u8(), 8), keys_len as usize).parse(input)?,
+            false => match keytype.size() {
+                Some(size) => count(count(u8(), size), keys_len as usize).parse(input)?,
+                None => count(
+                    length_count(u16(Endianness::Little), u8()),
+                    keys_len as usize,
+                )
+                .parse(input)?,
             },
             _ => (input, Vec::new()),
         };
@@ -365,54 +822,49 @@ impl SerializeDeserialize for Leaf {
 #[derive(Debug, Clone)]
 pub struct Data {
     pub object: Vec<Field>,
+
+    // the number of schema changes of the collection the record has been through, it is
+    // brought up to date when it is read
+    pub version: u16,
 }
 
 impl Data {
-    pub fn json(&self) -> String {
-        let mut json = "{".to_string();
-
-        for (idx, field) in self.object.iter().enumerate() {
-            json.push_str(format!("\"{}\"", &field.get_key()).as_str());
-            json.push_str(": ");
-            match field.datatype {
-                KeyType::String => json.push_str(format!("\"{}\"", field.get_data()).as_str()),
-                KeyType::UInt64 => json.push_str(&field.get_data()),
-            }
-            if idx != self.object.len() - 1 {
-                json.push(',');
-            }
-        }
-
-        json.push('}');
-
-        json
+    pub fn json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
     }
 
     pub fn is_valid(&self) -> bool {
-        for field in &self.object {
-            match field.datatype {
-                KeyType::UInt64 => {
-                    if field.key.len() != 8 {
-                        return false;
-                    }
-                }
-                KeyType::String => {
-                    if String::from_utf8(field.key.clone()).is_err() {
-                        return false;
-                    }
-                }
-            }
+        if self.object.len() > u8::MAX as usize {
+            return false;
+        }
+
+        if !self.object.iter().all(|field| field.is_valid()) {
+            return false;
         }
 
-        true
+        // the data has to fit in a page next to the page id and type
+        let size = 3 + self.object.iter().map(|f| 2 + f.len()).sum::<usize>();
+
+        size <= PAGESIZE_NO_HEADER
     }
 
+    // finds a field by its key, or by a dotted path into nested objects and arrays such as
+    // "address.city" or "tags.0"
     pub fn get_field(&self, key: &[u8]) -> Option<&Field> {
         if let Some(field) = self.object.iter().find(|field| field.key == key) {
-            Some(field)
-        } else {
-            None
+            return Some(field);
         }
+
+        let mut path = key.split(|byte| *byte == b'.');
+
+        let first = path.next()?;
+        let mut field = self.object.iter().find(|field| field.key == first)?;
+
+        for segment in path {
+            field = field.get_child(segment)?;
+        }
+
+        Some(field)
     }
 }
 
@@ -431,6 +883,9 @@ impl SerializeDeserialize for Data {
             }
         }
 
+        // pages written before records were versioned end in zeros, so they read as version 0
+        bytes.extend(self.version.to_le_bytes());
+
         bytes
     }
 
@@ -442,12 +897,48 @@ impl SerializeDeserialize for Data {
         // TODO: this is wrong, field parsing is super wrong
         let (input, object_len) = u8().parse(bytes)?;
 
-        let (_, fields) = count(length_count(u8(), u8()), object_len.into()).parse(input)?;
+        let (_, (fields, version)) = (
+            count(
+                length_count(u16(Endianness::Little), u8()),
+                object_len.into(),
+            ),
+            u16(Endianness::Little),
+        )
+            .parse(input)?;
 
-        let fields: Result<Vec<Field>, FileError> =
-            fields.into_iter().map(|f| Field::deserialize(&f)).collect();
+        let fields: Result<Vec<Field>, FileError> = fields
+            .into_iter()
+            .map(|f| <Field as SerializeDeserialize>::deserialize(&f))
+            .collect();
 
-        Ok(Data { object: fields? })
+        Ok(Data {
+            object: fields?,
+            version,
+        })
+    }
+}
+
+impl Serialize for Data {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.object.len()))?;
+
+        for field in &self.object {
+            let key = str::from_utf8(&field.key)
+                .map_err(|_| ser::Error::custom("field key is not valid utf-8"))?;
+            map.serialize_entry(key, field)?;
+        }
+
+        map.end()
+    }
+}
+
+// records are read through the same conversion as json::to_data, so the size and field count
+// limits apply however a record comes in
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Data, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+
+        json::to_data(&value).map_err(de::Error::custom)
     }
 }
 
@@ -456,6 +947,7 @@ pub struct Field {
     key: Vec<u8>,
     datatype: KeyType,
     pub data: Vec<u8>,
+    pub fields: Vec<Field>,
 }
 
 impl Field {
@@ -464,6 +956,26 @@ impl Field {
             key,
             datatype,
             data,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn object(key: Vec<u8>, fields: Vec<Field>) -> Field {
+        Field {
+            key,
+            datatype: KeyType::Object,
+            data: Vec::new(),
+            fields,
+        }
+    }
+
+    // elements of an array are fields without a key
+    pub fn array(key: Vec<u8>, fields: Vec<Field>) -> Field {
+        Field {
+            key,
+            datatype: KeyType::Array,
+            data: Vec::new(),
+            fields,
         }
     }
 
@@ -471,31 +983,104 @@ impl Field {
         let mut size = size_of::<u8>() // size of key
         + self.key.len() // the key
         + size_of::<u8>() // size of data type
-        + self.data.len(); // the data
+        + self.data.len() // the data
+        + self.fields.iter().map(|f| 2 + f.len()).sum::<usize>(); // nested fields
 
-        if self.datatype == KeyType::String {
-            size += 0x01 // size of the data len
+        match self.datatype {
+            KeyType::Object | KeyType::Array => size += 0x01, // the number of nested fields
+            datatype if datatype.size().is_none() => size += 0x02, // size of the data len
+            _ => {}
         }
 
         size
     }
 
-    pub fn get_key(&self) -> String {
-        String::from_utf8(self.key.clone()).expect("couldnt parse key")
+    pub fn is_valid(&self) -> bool {
+        if self.key.len() > u8::MAX as usize || self.len() > MAX_FIELD_LEN {
+            return false;
+        }
+
+        let valid = match self.datatype.size() {
+            Some(size) => self.data.len() == size,
+            None => self.data.len() <= u16::MAX as usize,
+        };
+
+        if !valid {
+            return false;
+        }
+
+        match self.datatype {
+            KeyType::String if str::from_utf8(&self.data).is_err() => false,
+            KeyType::Bool if self.data[0] > 0x01 => false,
+            KeyType::Object | KeyType::Array => {
+                self.data.is_empty()
+                    && self.fields.len() <= u8::MAX as usize
+                    && self.fields.iter().all(|field| field.is_valid())
+            }
+            _ => self.fields.is_empty(),
+        }
     }
 
-    pub fn get_data(&self) -> String {
+    pub fn timestamp(key: Vec<u8>, micros: i64) -> Field {
+        // flipping the sign bit and storing big endian makes the bytes sort chronologically
+        let bytes = ((micros as u64) ^ (1 << 63)).to_be_bytes();
+
+        Field::new(key, KeyType::Timestamp, bytes.to_vec())
+    }
+
+    pub fn get_timestamp(&self) -> Option<i64> {
+        if self.datatype != KeyType::Timestamp {
+            return None;
+        }
+
+        let bytes = <[u8; 8]>::try_from(self.data.as_slice()).ok()?;
+
+        Some((u64::from_be_bytes(bytes) ^ (1 << 63)) as i64)
+    }
+
+    fn get_child(&self, segment: &[u8]) -> Option<&Field> {
         match self.datatype {
-            KeyType::String => {
-                String::from_utf8(self.data.clone()).expect("couldnt parse data to string")
+            KeyType::Object => self.fields.iter().find(|field| field.key == segment),
+            KeyType::Array => {
+                let idx: usize = str::from_utf8(segment).ok()?.parse().ok()?;
+                self.fields.get(idx)
             }
-            KeyType::UInt64 => usize::from_le_bytes(
-                self.data
-                    .clone()
-                    .try_into()
-                    .expect("couldnt parse data to usize"),
-            )
-            .to_string(),
+            _ => None,
+        }
+    }
+
+    pub fn set_key(&mut self, key: Vec<u8>) {
+        self.key = key;
+    }
+
+    pub fn get_key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn get_datatype(&self) -> KeyType {
+        self.datatype
+    }
+
+    // renders the value for display, use the serde impls for json
+    #[allow(dead_code)]
+    pub fn get_data(&self) -> String {
+        let bytes = <[u8; 8]>::try_from(self.data.as_slice());
+
+        match (self.datatype, bytes) {
+            (KeyType::String, _) => String::from_utf8_lossy(&self.data).to_string(),
+            (KeyType::UInt64, Ok(bytes)) => u64::from_le_bytes(bytes).to_string(),
+            (KeyType::Int64, Ok(bytes)) => i64::from_le_bytes(bytes).to_string(),
+            (KeyType::Float64, Ok(bytes)) => f64::from_le_bytes(bytes).to_string(),
+            (KeyType::Bool, _) => (self.data == [0x01]).to_string(),
+            (KeyType::Null, _) => "null".to_string(),
+            (KeyType::Timestamp, _) => match self.get_timestamp().and_then(format_timestamp) {
+                Some(timestamp) => timestamp,
+                None => format!("{:?}", self.data),
+            },
+            (KeyType::Object | KeyType::Array, _) => {
+                serde_json::to_string(self).unwrap_or_default()
+            }
+            _ => format!("{:?}", self.data),
         }
     }
 }
@@ -504,26 +1089,42 @@ impl SerializeDeserialize for Field {
     fn serialize(self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::new();
 
-        bytes.push(
-            self.len()
-                .try_into()
-                .expect("couldnt parse length of field"),
+        bytes.extend(
+            u16::try_from(self.len())
+                .expect("couldnt parse length of field")
+                .to_le_bytes(),
         );
 
         bytes.push(self.key.len().try_into().expect("couldnt parse key len"));
 
         bytes.extend(self.key);
 
+        bytes.push(self.datatype.tag());
+
         match self.datatype {
-            KeyType::String => {
-                bytes.push(0x01);
-                bytes.push(self.data.len().try_into().expect("couldnt parse data len"));
+            KeyType::Object | KeyType::Array => {
+                bytes.push(
+                    self.fields
+                        .len()
+                        .try_into()
+                        .expect("couldnt parse fields len"),
+                );
+                for field in self.fields {
+                    bytes.extend(field.serialize());
+                }
+            }
+            _ => {
+                if self.datatype.size().is_none() {
+                    bytes.extend(
+                        u16::try_from(self.data.len())
+                            .expect("couldnt parse data len")
+                            .to_le_bytes(),
+                    );
+                }
+                bytes.extend(self.data);
             }
-            KeyType::UInt64 => bytes.push(0x02),
         }
 
-        bytes.extend(self.data);
-
         bytes
     }
 
@@ -532,36 +1133,215 @@ impl SerializeDeserialize for Field {
 
         let (input, datatype) = u8().parse(input)?;
 
-        let datatype = match datatype {
-            0x01 => KeyType::String,
-            0x02 => KeyType::UInt64,
-            _ => return Err(FileError::Keytype(datatype)),
+        let datatype = KeyType::try_from(datatype)?;
+
+        if let KeyType::Object | KeyType::Array = datatype {
+            let (input, fields_len) = u8().parse(input)?;
+
+            let (_, fields) = count(
+                length_count(u16(Endianness::Little), u8()),
+                fields_len.into(),
+            )
+            .parse(input)?;
+
+            let fields: Result<Vec<Field>, FileError> = fields
+                .into_iter()
+                .map(|f| <Field as SerializeDeserialize>::deserialize(&f))
+                .collect();
+
+            return Ok(Field {
+                key,
+                datatype,
+                data: Vec::new(),
+                fields: fields?,
+            });
+        }
+
+        let (_, data) = match datatype.size() {
+            Some(size) => count(u8(), size).parse(input)?,
+            None => length_count(u16(Endianness::Little), u8()).parse(input)?,
         };
 
-        let (_, data) = match datatype {
-            KeyType::String => length_count(u8(), u8()).parse(input)?,
-            KeyType::UInt64 => count(u8(), 8).parse(input)?,
+        Ok(Field::new(key, datatype, data))
+    }
+}
+
+// a field serializes to just its value, the key belongs to the object holding it
+impl Serialize for Field {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fixed = || {
+            <[u8; 8]>::try_from(self.data.as_slice())
+                .map_err(|_| ser::Error::custom("number field is not 8 bytes"))
         };
 
-        Ok(Field {
-            key,
-            datatype,
-            data,
-        })
+        match self.datatype {
+            KeyType::String => serializer.serialize_str(
+                str::from_utf8(&self.data)
+                    .map_err(|_| ser::Error::custom("string field is not valid utf-8"))?,
+            ),
+            KeyType::UInt64 => serializer.serialize_u64(u64::from_le_bytes(fixed()?)),
+            KeyType::Int64 => serializer.serialize_i64(i64::from_le_bytes(fixed()?)),
+            KeyType::Float64 => serializer.serialize_f64(f64::from_le_bytes(fixed()?)),
+            KeyType::Bool => serializer.serialize_bool(self.data == [0x01]),
+            KeyType::Null => serializer.serialize_unit(),
+            KeyType::Bytes => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(BINARY_TAG, &BASE64_STANDARD.encode(&self.data))?;
+                map.end()
+            }
+            KeyType::Timestamp => {
+                let timestamp = self
+                    .get_timestamp()
+                    .and_then(format_timestamp)
+                    .ok_or(ser::Error::custom("timestamp field is out of range"))?;
+
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(TIMESTAMP_TAG, &timestamp)?;
+                map.end()
+            }
+            KeyType::Object => {
+                let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+                for field in &self.fields {
+                    let key = str::from_utf8(&field.key)
+                        .map_err(|_| ser::Error::custom("field key is not valid utf-8"))?;
+                    map.serialize_entry(key, field)?;
+                }
+                map.end()
+            }
+            KeyType::Array => {
+                let mut seq = serializer.serialize_seq(Some(self.fields.len()))?;
+                for field in &self.fields {
+                    seq.serialize_element(field)?;
+                }
+                seq.end()
+            }
+        }
+    }
+}
+
+// deserializes a value into a field without a key, through json::to_field
+impl<'de> Deserialize<'de> for Field {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Field, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+
+        json::to_field(Vec::new(), &value, "$").map_err(de::Error::custom)
     }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum KeyType {
-    String, // 0x01
-    UInt64, // 0x02
+    String,    // 0x01
+    UInt64,    // 0x02
+    Int64,     // 0x03
+    Float64,   // 0x04
+    Bool,      // 0x05
+    Null,      // 0x06
+    Bytes,     // 0x07
+    Object,    // 0x08
+    Array,     // 0x09
+    Timestamp, // 0x0A, microseconds since the epoch
+}
+
+impl KeyType {
+    pub fn tag(&self) -> u8 {
+        match self {
+            KeyType::String => 0x01,
+            KeyType::UInt64 => 0x02,
+            KeyType::Int64 => 0x03,
+            KeyType::Float64 => 0x04,
+            KeyType::Bool => 0x05,
+            KeyType::Null => 0x06,
+            KeyType::Bytes => 0x07,
+            KeyType::Object => 0x08,
+            KeyType::Array => 0x09,
+            KeyType::Timestamp => 0x0A,
+        }
+    }
+
+    // the fixed number of bytes a value takes up, or none if it is stored with a length prefix
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            KeyType::String | KeyType::Bytes | KeyType::Object | KeyType::Array => None,
+            KeyType::UInt64 | KeyType::Int64 | KeyType::Float64 | KeyType::Timestamp => Some(8),
+            KeyType::Bool => Some(1),
+            KeyType::Null => Some(0),
+        }
+    }
+}
+
+impl TryFrom<u8> for KeyType {
+    type Error = FileError;
+
+    fn try_from(tag: u8) -> Result<KeyType, FileError> {
+        match tag {
+            0x01 => Ok(KeyType::String),
+            0x02 => Ok(KeyType::UInt64),
+            0x03 => Ok(KeyType::Int64),
+            0x04 => Ok(KeyType::Float64),
+            0x05 => Ok(KeyType::Bool),
+            0x06 => Ok(KeyType::Null),
+            0x07 => Ok(KeyType::Bytes),
+            0x08 => Ok(KeyType::Object),
+            0x09 => Ok(KeyType::Array),
+            0x0A => Ok(KeyType::Timestamp),
+            _ => Err(FileError::Keytype(tag)),
+        }
+    }
+}
+
+// how keys are filled in for records inserted without one
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyGeneration {
+    None,          // 0x00
+    AutoIncrement, // 0x01, from the counter in the header
+    Ulid,          // 0x02
+}
+
+impl KeyGeneration {
+    pub fn tag(&self) -> u8 {
+        match self {
+            KeyGeneration::None => 0x00,
+            KeyGeneration::AutoIncrement => 0x01,
+            KeyGeneration::Ulid => 0x02,
+        }
+    }
+}
+
+impl TryFrom<u8> for KeyGeneration {
+    type Error = FileError;
+
+    fn try_from(tag: u8) -> Result<KeyGeneration, FileError> {
+        match tag {
+            0x00 => Ok(KeyGeneration::None),
+            0x01 => Ok(KeyGeneration::AutoIncrement),
+            0x02 => Ok(KeyGeneration::Ulid),
+            _ => Err(FileError::KeyGeneration(tag)),
+        }
+    }
+}
+
+pub fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    Some(
+        DateTime::parse_from_rfc3339(timestamp)
+            .ok()?
+            .timestamp_micros(),
+    )
+}
+
+pub fn format_timestamp(micros: i64) -> Option<String> {
+    Some(
+        DateTime::<Utc>::from_timestamp_micros(micros)?
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+    )
 }
 
 #[derive(Debug, Clone)]
 pub enum PageType {
-    Node(Node), // 0x01
-    Leaf(Leaf), // 0x02
-    Data(Data), // 0x03
+    Node(Node),       // 0x01
+    Leaf(Leaf),       // 0x02
+    Data(Data),       // 0x03
+    Free(Id),         // 0x04, points to the next free page
+    Catalog(Catalog), // 0x05
 }
 
 #[derive(Error, Debug)]
@@ -572,6 +1352,12 @@ pub enum FileError {
     #[error("keytype could not be parsed ({0})")]
     Keytype(u8),
 
+    #[error("key generation could not be parsed ({0})")]
+    KeyGeneration(u8),
+
+    #[error("schema change could not be parsed ({0})")]
+    Migration(u8),
+
     #[error("page type was not correct")]
     Pagetype(u8),
 
@@ -624,21 +1410,23 @@ mod test {
                         1234_usize.to_le_bytes().to_vec(),
                     ),
                 ],
+                version: 3,
             };
 
             let mut expected = vec![
                 0x03, //pagetype
                 0x02, // has 2 fields
-                0x09, // size of field
+                0x0A, 0x00, // size of field
                 0x03, b'f', b'o', b'o', // key is 3 chars long, foo
-                0x01, 0x03, b'b', b'a',
+                0x01, 0x03, 0x00, b'b', b'a',
                 b'r', // data is of type string and is 3 chars long, bar
-                0x0E, // size of field
+                0x0E, 0x00, // size of field
                 0x04, b't', b'e', b's', b't', // key is 4 chars long, test
                 0x02, // data is uint64
             ];
 
             expected.extend(1234_usize.to_le_bytes());
+            expected.extend([0x03, 0x00]); // version
 
             let bytes = data.serialize();
 
@@ -649,11 +1437,11 @@ mod test {
         fn deserialize() {
             let mut bytes = vec![
                 0x02, // has 2 fields
-                0x09, // size of field
+                0x0A, 0x00, // size of field
                 0x03, b'f', b'o', b'o', // key is 3 chars long, foo
-                0x01, 0x03, b'b', b'a',
+                0x01, 0x03, 0x00, b'b', b'a',
                 b'r', // data is of type string and is 3 chars long, bar
-                0x0E, // size of field
+                0x0E, 0x00, // size of field
                 0x04, b't', b'e', b's', b't', // key is 4 chars long, test
                 0x02, // data is uint64
             ];
@@ -668,14 +1456,18 @@ mod test {
                         1234_usize.to_le_bytes().to_vec(),
                     ),
                 ],
+                version: 0,
             };
 
             let mut buf = [0x00; PAGESIZE_NO_HEADER];
             buf[0..bytes.len()].as_mut().write_all(&bytes).unwrap();
 
-            let data = Data::deserialize(&buf);
+            let data = <Data as SerializeDeserialize>::deserialize(&buf);
 
             if let Ok(data) = data {
+                // pages from before records were versioned have no version
+                assert_eq!(data.version, 0);
+
                 for (field_expected, field) in data_expected.object.iter().zip(data.object) {
                     assert_eq!(field_expected.key, field.key);
                     assert_eq!(field_expected.data, field.data);
@@ -688,6 +1480,218 @@ mod test {
         }
     }
 
+    mod nestedtests {
+        use super::*;
+        use ::std::io::Write;
+
+        #[test]
+        fn roundtrip() {
+            let data = Data {
+                object: vec![
+                    Field::new(b"id".to_vec(), KeyType::String, b"A".to_vec()),
+                    Field::object(
+                        b"address".to_vec(),
+                        vec![Field::new(
+                            b"city".to_vec(),
+                            KeyType::String,
+                            b"aarhus".to_vec(),
+                        )],
+                    ),
+                    Field::array(
+                        b"tags".to_vec(),
+                        vec![
+                            Field::new(Vec::new(), KeyType::String, b"a".to_vec()),
+                            Field::new(Vec::new(), KeyType::Bool, vec![0x01]),
+                        ],
+                    ),
+                ],
+                version: 0,
+            };
+
+            let bytes = data.serialize();
+
+            let mut buf = [0x00; PAGESIZE_NO_HEADER];
+            buf[0..bytes.len() - 1]
+                .as_mut()
+                .write_all(&bytes[1..])
+                .unwrap();
+
+            let data = <Data as SerializeDeserialize>::deserialize(&buf).unwrap();
+
+            assert_eq!(data.get_field(b"address.city").unwrap().data, b"aarhus");
+            assert_eq!(data.get_field(b"tags.0").unwrap().data, b"a");
+            assert_eq!(data.get_field(b"tags.1").unwrap().datatype, KeyType::Bool);
+            assert_eq!(data.get_field(b"tags").unwrap().fields.len(), 2);
+        }
+    }
+
+    mod catalogtests {
+        use super::*;
+
+        #[test]
+        fn roundtrip() {
+            let catalog = Catalog {
+                collections: vec![
+                    Collection {
+                        name: b"users".to_vec(),
+                        elements: 3,
+                        keys: vec![KeyField {
+                            name: b"id".to_vec(),
+                            keytype: KeyType::String,
+                            size: 26,
+                        }],
+                        root: 4,
+                        order: 4,
+                        generation: KeyGeneration::Ulid,
+                        counter: 0,
+                        indexes: vec![Index {
+                            field: b"cpr".to_vec(),
+                            root: 7,
+                            unique: true,
+                        }],
+                        schema: Some(Schema {
+                            fields: vec![
+                                SchemaField {
+                                    name: b"cpr".to_vec(),
+                                    fieldtype: KeyType::String,
+                                    required: true,
+                                    max_len: Some(10),
+                                    default: None,
+                                },
+                                SchemaField {
+                                    name: b"age".to_vec(),
+                                    fieldtype: KeyType::UInt64,
+                                    required: false,
+                                    max_len: None,
+                                    default: Some(Field::new(
+                                        b"age".to_vec(),
+                                        KeyType::UInt64,
+                                        18_u64.to_le_bytes().to_vec(),
+                                    )),
+                                },
+                            ],
+                        }),
+                        migrated: 0,
+                        migrations: Vec::new(),
+                    },
+                    Collection {
+                        name: b"orders".to_vec(),
+                        elements: 0,
+                        keys: vec![KeyField {
+                            name: b"id".to_vec(),
+                            keytype: KeyType::UInt64,
+                            size: 8,
+                        }],
+                        root: 9,
+                        order: 8,
+                        generation: KeyGeneration::AutoIncrement,
+                        counter: 12,
+                        indexes: Vec::new(),
+                        schema: None,
+                        migrated: 2,
+                        migrations: vec![
+                            Migration::Rename(b"total".to_vec(), b"sum".to_vec()),
+                            Migration::Drop(b"note".to_vec()),
+                            Migration::Add(Field::new(b"paid".to_vec(), KeyType::Bool, vec![0x00])),
+                        ],
+                    },
+                ],
+            };
+
+            let bytes = catalog.serialize();
+            assert_eq!(bytes[0], 0x05);
+
+            let catalog = Catalog::deserialize(&bytes[1..]).unwrap();
+
+            assert_eq!(catalog.collections.len(), 2);
+            assert_eq!(catalog.collections[0].name, b"users");
+            assert_eq!(catalog.collections[0].indexes[0].root, 7);
+            assert!(catalog.collections[0].indexes[0].unique);
+            assert_eq!(catalog.collections[1].keytype(), KeyType::UInt64);
+            assert_eq!(catalog.collections[1].counter, 12);
+            assert!(catalog.collections[1].schema.is_none());
+            assert_eq!(catalog.collections[1].version(), 5);
+            assert!(matches!(
+                &catalog.collections[1].migrations[..],
+                [
+                    Migration::Rename(from, to),
+                    Migration::Drop(_),
+                    Migration::Add(field),
+                ] if from == b"total" && to == b"sum" && field.get_key() == b"paid"
+            ));
+
+            let schema = catalog.collections[0].schema.as_ref().unwrap();
+            assert_eq!(schema.fields[0].max_len, Some(10));
+            assert!(schema.fields[0].required);
+            assert_eq!(schema.fields[1].max_len, None);
+            assert_eq!(schema.fields[1].default.as_ref().unwrap().get_data(), "18");
+        }
+    }
+
+    mod serdetests {
+        use super::*;
+
+        #[test]
+        fn serialize() {
+            let data = Data {
+                object: vec![
+                    Field::new(b"name".to_vec(), KeyType::String, b"\"quoted\"\n".to_vec()),
+                    Field::new(
+                        b"age".to_vec(),
+                        KeyType::UInt64,
+                        22_usize.to_le_bytes().to_vec(),
+                    ),
+                ],
+                version: 0,
+            };
+
+            assert_eq!(data.json().unwrap(), r#"{"name":"\"quoted\"\n","age":22}"#);
+
+            let invalid = Data {
+                object: vec![Field::new(
+                    b"name".to_vec(),
+                    KeyType::String,
+                    vec![0xFF, 0xFE],
+                )],
+                version: 0,
+            };
+
+            assert!(invalid.json().is_err());
+        }
+
+        #[test]
+        fn deserialize() {
+            let data: Data = serde_json::from_str(r#"{"name": "jonas", "age": 22}"#).unwrap();
+
+            let name = data.get_field(b"name").unwrap();
+            assert_eq!(name.datatype, KeyType::String);
+            assert_eq!(name.data, b"jonas".to_vec());
+
+            let age = data.get_field(b"age").unwrap();
+            assert_eq!(age.datatype, KeyType::UInt64);
+            assert_eq!(age.data, 22_usize.to_le_bytes().to_vec());
+
+            let data: Data = serde_json::from_str(r#"{"age": -1, "admin": false}"#).unwrap();
+            assert_eq!(data.get_field(b"age").unwrap().datatype, KeyType::Int64);
+            assert_eq!(data.get_field(b"admin").unwrap().datatype, KeyType::Bool);
+
+            let data: Data =
+                serde_json::from_str(r#"{"tags": ["a"], "bin": {"$binary": "AA=="}}"#).unwrap();
+            assert_eq!(data.get_field(b"tags").unwrap().datatype, KeyType::Array);
+            assert_eq!(data.get_field(b"tags.0").unwrap().data, b"a".to_vec());
+            assert_eq!(data.get_field(b"bin").unwrap().datatype, KeyType::Bytes);
+            assert!(serde_json::from_str::<Data>(r#"["jonas"]"#).is_err());
+
+            // the same limits as records coming in through json::to_data
+            let wide: serde_json::Map<String, Value> = (0..300)
+                .map(|idx| (format!("f{idx}"), Value::from(idx)))
+                .collect();
+            let wide = serde_json::to_string(&wide).unwrap();
+            assert!(serde_json::from_str::<Data>(&wide).is_err());
+            assert!(serde_json::from_str::<Field>(r#"{"$binary": 1}"#).is_err());
+        }
+    }
+
     mod leaftests {
         use super::*;
         use ::std::io::Write;
@@ -754,11 +1758,11 @@ mod test {
 
             let mut expected: Vec<u8> = vec![0x02, 0x01, 0x03];
 
-            expected.push(0x03);
+            expected.extend([0x03, 0x00]);
             b"foo".iter().for_each(|b| expected.push(*b));
-            expected.push(0x03);
+            expected.extend([0x03, 0x00]);
             b"bar".iter().for_each(|b| expected.push(*b));
-            expected.push(0x03);
+            expected.extend([0x03, 0x00]);
             b"baz".iter().for_each(|b| expected.push(*b));
 
             usize::to_le_bytes(4).iter().for_each(|b| expected.push(*b));
@@ -793,7 +1797,7 @@ mod test {
 
         #[test]
         fn deserialize_with_data_uint64() {
-            let mut bytes: Vec<u8> = vec![0x03, 0x02];
+            let mut bytes: Vec<u8> = vec![0x02, 0x03]; // uint64 keys, 3 of them
 
             usize::to_le_bytes(1).iter().for_each(|b| bytes.push(*b));
             usize::to_le_bytes(2).iter().for_each(|b| bytes.push(*b));
@@ -802,7 +1806,6 @@ mod test {
             usize::to_le_bytes(4).iter().for_each(|b| bytes.push(*b));
             usize::to_le_bytes(5).iter().for_each(|b| bytes.push(*b));
             usize::to_le_bytes(6).iter().for_each(|b| bytes.push(*b));
-            usize::to_le_bytes(7).iter().for_each(|b| bytes.push(*b));
 
             usize::to_le_bytes(1).iter().for_each(|b| bytes.push(*b));
 
@@ -821,28 +1824,29 @@ mod test {
                         vec![0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
                     ]
                 );
-                assert_eq!(leaf.pointers, vec![4, 5, 6, 7]);
+                assert_eq!(leaf.pointers, vec![4, 5, 6]);
                 assert_eq!(leaf.next_leaf_pointer, 1);
+            } else {
+                panic!()
             }
         }
 
         #[test]
         fn deserialize_with_data_string() {
-            let mut bytes: Vec<u8> = vec![0x03, 0x01];
+            let mut bytes: Vec<u8> = vec![0x01, 0x03]; // string keys, 3 of them
 
-            bytes.push(0x03);
+            bytes.extend([0x03, 0x00]);
             b"foo".iter().for_each(|b| bytes.push(*b));
 
-            bytes.push(0x03);
+            bytes.extend([0x03, 0x00]);
             b"bar".iter().for_each(|b| bytes.push(*b));
 
-            bytes.push(0x03);
+            bytes.extend([0x03, 0x00]);
             b"baz".iter().for_each(|b| bytes.push(*b));
 
             usize::to_le_bytes(4).iter().for_each(|b| bytes.push(*b));
             usize::to_le_bytes(5).iter().for_each(|b| bytes.push(*b));
             usize::to_le_bytes(6).iter().for_each(|b| bytes.push(*b));
-            usize::to_le_bytes(7).iter().for_each(|b| bytes.push(*b));
 
             usize::to_le_bytes(1).iter().for_each(|b| bytes.push(*b));
 
@@ -861,8 +1865,10 @@ mod test {
                         vec![b'b', b'a', b'z'],
                     ]
                 );
-                assert_eq!(leaf.pointers, vec![4, 5, 6, 7]);
+                assert_eq!(leaf.pointers, vec![4, 5, 6]);
                 assert_eq!(leaf.next_leaf_pointer, 1);
+            } else {
+                panic!()
             }
         }
     }
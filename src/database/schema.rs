@@ -0,0 +1,809 @@
+use crate::database::handler::*;
+use crate::database::page::*;
+use crate::database::{Database, DatabaseError};
+use crate::database::{json, key};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::io::{Read, Seek, Write};
+
+// NOTE: a schema declares top-level fields of the records in a collection. writes are checked
+// against it, missing fields are filled in from their defaults and every problem with a record
+// is reported at once. fields the schema doesn't mention are stored as they are
+
+// NOTE: changes to the shape of the records are kept as a list of migrations and a record knows
+// how many of them it has been through. stored records are left alone when the shape changes,
+// they are migrated when read and rewritten in the background a few at a time. each batch picks
+// up after the last key the one before it checked, and once a pass has gone through the whole
+// collection it isn't scanned again until the shape changes. a pass that leaves no record
+// behind drops the migrations from the catalog, only their count is kept as the version
+
+// how far the background upgrade of a collection has got, kept in memory only
+#[derive(Default)]
+pub struct Upgrade {
+    // the version the pass brings records up to, a new migration starts a new pass
+    version: u16,
+
+    // where the next batch starts, none once the pass is done
+    next: Option<Vec<u8>>,
+    done: bool,
+
+    // whether a record was too big to be rewritten in its new shape
+    behind: bool,
+}
+
+impl<T: Read + Write + Seek> Database<T> {
+    // sets the schema of the selected collection, or removes it. the records already stored
+    // have to match the new schema
+    pub fn set_schema(&mut self, schema: Option<Schema>) -> Result<(), DatabaseError> {
+        if let Some(schema) = &schema {
+            let records = self.primary()?.scan(&mut self.source, None, None)?;
+
+            for (_, pointer) in records {
+                let data = self.read_data(pointer)?;
+                schema.apply(data)?;
+            }
+        }
+
+        self.update_definition(|collection| collection.schema = schema)
+    }
+
+    pub fn schema(&mut self) -> Result<Option<Schema>, DatabaseError> {
+        Ok(self.definition()?.schema)
+    }
+
+    // checks a record being written against the schema of the collection, records are always
+    // written in the current shape
+    pub(super) fn conform(&mut self, mut data: Data) -> Result<Data, DatabaseError> {
+        let collection = self.definition()?;
+
+        data.version = collection.version();
+
+        match collection.schema {
+            Some(schema) => schema.apply(data),
+            None => Ok(data),
+        }
+    }
+
+    pub(super) fn upgrade(&mut self, data: Data) -> Result<Data, DatabaseError> {
+        let collection = self.definition()?;
+
+        Ok(migrate(&collection, data))
+    }
+
+    // changes the shape of the records in the selected collection. key fields and indexed
+    // fields can't be changed, and a declared schema follows the change
+    pub fn alter(&mut self, migration: Migration) -> Result<(), DatabaseError> {
+        let collection = self.definition()?;
+
+        let invalid = |reason: String| Err(DatabaseError::InvalidMigration(reason));
+
+        let names: Vec<&[u8]> = match &migration {
+            Migration::Add(field) => vec![field.get_key()],
+            Migration::Drop(name) => vec![name],
+            Migration::Rename(from, to) => vec![from, to],
+        };
+
+        for name in &names {
+            let lossy = String::from_utf8_lossy(name);
+
+            if name.is_empty() || name.len() > u8::MAX as usize {
+                return invalid(format!("{lossy} must be between 1 and 255 bytes long"));
+            }
+
+            if collection.keys.iter().any(|key| key.name == *name) {
+                return invalid(format!("{lossy} is part of the key"));
+            }
+
+            if collection.indexes.iter().any(|index| index.field == *name) {
+                return invalid(format!("{lossy} is indexed"));
+            }
+        }
+
+        if let Migration::Rename(from, to) = &migration
+            && from == to
+        {
+            return invalid("a field can't be renamed to itself".to_string());
+        }
+
+        if let Migration::Add(field) = &migration
+            && !field.is_valid()
+        {
+            return invalid("the default value is not valid".to_string());
+        }
+
+        if collection.version() == u16::MAX {
+            return invalid("the collection has been changed too many times".to_string());
+        }
+
+        self.update_definition(|collection| {
+            if let Some(schema) = &mut collection.schema {
+                schema.migrate(&migration);
+            }
+
+            collection.migrations.push(migration);
+        })
+    }
+
+    // checks the next limit records of the selected collection and rewrites the ones that are
+    // behind its shape. returns how many were checked, 0 once the collection is up to date
+    pub fn upgrade_records(&mut self, limit: usize) -> Result<usize, DatabaseError> {
+        let collection = self.definition()?;
+
+        if collection.migrations.is_empty() {
+            return Ok(0);
+        }
+
+        let current = collection.version();
+
+        let upgrade = self.upgrades.entry(self.name.clone()).or_default();
+        if upgrade.version != current {
+            *upgrade = Upgrade {
+                version: current,
+                next: None,
+                done: false,
+                behind: false,
+            };
+        }
+
+        if upgrade.done {
+            return Ok(0);
+        }
+
+        let start = upgrade.next.clone();
+        let records =
+            self.primary()?
+                .scan_limit(&mut self.source, start.as_deref(), None, limit)?;
+
+        let checked = records.len();
+        let next = records.last().map(|(last, _)| key::after(last));
+        let mut behind = false;
+
+        for (_, pointer) in records {
+            let PageType::Data(data) = PageHandler::get_page(&mut self.source, pointer)?.pagetype
+            else {
+                return Err(DatabaseError::UnexpectedPagetype(
+                    "data".to_string(),
+                    "something else".to_string(),
+                ));
+            };
+
+            if data.version == current {
+                continue;
+            }
+
+            // a record that no longer fits in its page stays behind and is migrated on read
+            let data = migrate(&collection, data);
+            if !data.is_valid() {
+                behind = true;
+                continue;
+            }
+
+            PageHandler::write(
+                &mut self.source,
+                Page {
+                    id: pointer,
+                    pagetype: PageType::Data(data),
+                },
+            )?;
+        }
+
+        let upgrade = self.upgrades.entry(self.name.clone()).or_default();
+        upgrade.done = checked < limit;
+        upgrade.next = next;
+        upgrade.behind |= behind;
+
+        // every record has been through the migrations, so they aren't needed anymore
+        if upgrade.done && !upgrade.behind {
+            self.update_definition(|collection| {
+                collection.migrated = collection.version();
+                collection.migrations.clear();
+            })?;
+        }
+
+        Ok(checked)
+    }
+}
+
+// brings a record up to date with the migrations it hasn't been through yet
+pub fn migrate(collection: &Collection, mut data: Data) -> Data {
+    let done = data.version.saturating_sub(collection.migrated);
+
+    for migration in collection.migrations.iter().skip(done.into()) {
+        match migration {
+            Migration::Add(default) => {
+                if !data
+                    .object
+                    .iter()
+                    .any(|field| field.get_key() == default.get_key())
+                {
+                    data.object.push(default.clone());
+                }
+            }
+            Migration::Drop(name) => data.object.retain(|field| field.get_key() != name),
+            Migration::Rename(from, to) => {
+                if data.object.iter().any(|field| field.get_key() == from) {
+                    data.object.retain(|field| field.get_key() != to);
+                }
+
+                for field in &mut data.object {
+                    if field.get_key() == from {
+                        field.set_key(to.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    data.version = collection.version();
+
+    data
+}
+
+impl Schema {
+    fn migrate(&mut self, migration: &Migration) {
+        match migration {
+            Migration::Add(default) => {
+                self.fields.retain(|field| field.name != default.get_key());
+                self.fields.push(SchemaField {
+                    name: default.get_key().to_vec(),
+                    fieldtype: default.get_datatype(),
+                    required: false,
+                    max_len: None,
+                    default: Some(default.clone()),
+                });
+            }
+            Migration::Drop(name) => self.fields.retain(|field| field.name != *name),
+            Migration::Rename(from, to) => {
+                self.fields.retain(|field| field.name != *to);
+
+                for field in &mut self.fields {
+                    if field.name == *from {
+                        field.name = to.clone();
+
+                        if let Some(default) = &mut field.default {
+                            default.set_key(to.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // returns the record with defaults filled in and values converted to their declared types,
+    // or everything that is wrong with it
+    pub fn apply(&self, mut data: Data) -> Result<Data, DatabaseError> {
+        let mut violations = Vec::new();
+
+        for declared in &self.fields {
+            let name = String::from_utf8_lossy(&declared.name).to_string();
+
+            let Some(idx) = data
+                .object
+                .iter()
+                .position(|field| field.get_key() == declared.name)
+            else {
+                match (&declared.default, declared.required) {
+                    (Some(default), _) => data.object.push(default.clone()),
+                    (None, true) => violations.push(Violation::new(&name, "is required")),
+                    (None, false) => {}
+                }
+
+                continue;
+            };
+
+            let field = &data.object[idx];
+
+            // optional fields may be left empty
+            if field.get_datatype() == KeyType::Null && !declared.required {
+                continue;
+            }
+
+            let Some(field) = coerce(field, declared.fieldtype) else {
+                violations.push(Violation::new(
+                    &name,
+                    &format!(
+                        "expected {}, found {}",
+                        type_name(declared.fieldtype),
+                        type_name(field.get_datatype())
+                    ),
+                ));
+                continue;
+            };
+
+            if let Some(max_len) = declared.max_len
+                && length(&field) > max_len as usize
+            {
+                violations.push(Violation::new(
+                    &name,
+                    &format!("is longer than {max_len} ({})", length(&field)),
+                ));
+            }
+
+            data.object[idx] = field;
+        }
+
+        if !violations.is_empty() {
+            return Err(DatabaseError::Schema(violations));
+        }
+
+        Ok(data)
+    }
+
+    // reads a schema given as {"fields": [{"name": "age", "type": "uint64", "required": true,
+    // "default": 18, "max_length": 3}, ...]}, only name and type have to be given
+    pub fn from_json(value: &Value) -> Result<Schema, DatabaseError> {
+        let invalid = |reason: String| DatabaseError::InvalidSchema(reason);
+
+        let Some(fields) = value["fields"].as_array() else {
+            return Err(invalid("fields must be an array".to_string()));
+        };
+
+        if fields.len() > u8::MAX as usize {
+            return Err(invalid(format!("too many fields ({})", fields.len())));
+        }
+
+        let mut declared: Vec<SchemaField> = Vec::new();
+
+        for (idx, field) in fields.iter().enumerate() {
+            let Some(name) = field["name"].as_str() else {
+                return Err(invalid(format!("fields[{idx}].name must be a string")));
+            };
+
+            if name.is_empty() || name.len() > u8::MAX as usize {
+                return Err(invalid(format!(
+                    "{name} must be between 1 and 255 bytes long"
+                )));
+            }
+
+            if declared.iter().any(|field| field.name == name.as_bytes()) {
+                return Err(invalid(format!("{name} is declared twice")));
+            }
+
+            let Some(fieldtype) = field["type"].as_str().and_then(parse_type) else {
+                return Err(invalid(format!("{name} has an unknown type")));
+            };
+
+            let required = match &field["required"] {
+                Value::Null => false,
+                Value::Bool(required) => *required,
+                _ => return Err(invalid(format!("{name}.required must be a boolean"))),
+            };
+
+            let max_len = match &field["max_length"] {
+                Value::Null => None,
+                value => match value.as_u64().map(u16::try_from) {
+                    Some(Ok(max_len)) if max_len > 0 => Some(max_len),
+                    _ => {
+                        return Err(invalid(format!(
+                            "{name}.max_length must be between 1 and {}",
+                            u16::MAX
+                        )));
+                    }
+                },
+            };
+
+            if max_len.is_some()
+                && !matches!(fieldtype, KeyType::String | KeyType::Bytes | KeyType::Array)
+            {
+                return Err(invalid(format!(
+                    "{name} is a {}, only strings, binary values and arrays have a length",
+                    type_name(fieldtype)
+                )));
+            }
+
+            let default = match &field["default"] {
+                Value::Null => None,
+                value => {
+                    let path = format!("$.fields[{idx}].default");
+                    let default = json::to_field(name.as_bytes().to_vec(), value, &path)?;
+
+                    let fits = |default: &Field| {
+                        max_len.is_none_or(|max_len| length(default) <= max_len as usize)
+                    };
+
+                    match coerce(&default, fieldtype) {
+                        Some(default) if fits(&default) => Some(default),
+                        _ => {
+                            return Err(invalid(format!(
+                                "the default of {name} does not fit its type"
+                            )));
+                        }
+                    }
+                }
+            };
+
+            declared.push(SchemaField {
+                name: name.as_bytes().to_vec(),
+                fieldtype,
+                required,
+                max_len,
+                default,
+            });
+        }
+
+        Ok(Schema { fields: declared })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let fields: Vec<Value> = self
+            .fields
+            .iter()
+            .map(|field| {
+                let mut object = Map::new();
+
+                object.insert(
+                    "name".to_string(),
+                    Value::from(String::from_utf8_lossy(&field.name).to_string()),
+                );
+                object.insert("type".to_string(), Value::from(type_name(field.fieldtype)));
+                object.insert("required".to_string(), Value::from(field.required));
+
+                if let Some(max_len) = field.max_len {
+                    object.insert("max_length".to_string(), Value::from(max_len));
+                }
+
+                if let Some(default) = &field.default {
+                    object.insert(
+                        "default".to_string(),
+                        serde_json::to_value(default).unwrap_or_default(),
+                    );
+                }
+
+                Value::Object(object)
+            })
+            .collect();
+
+        serde_json::json!({ "fields": fields })
+    }
+}
+
+// one thing wrong with a record, e.g. a missing required field
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub field: String,
+    pub problem: String,
+}
+
+impl Violation {
+    fn new(field: &str, problem: &str) -> Violation {
+        Violation {
+            field: field.to_string(),
+            problem: problem.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.problem)
+    }
+}
+
+pub fn describe(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(Violation::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// converts a value to the declared type where no information is lost, json numbers come in as
+// the narrowest type that holds them and timestamps as strings
+fn coerce(field: &Field, fieldtype: KeyType) -> Option<Field> {
+    let key = field.get_key().to_vec();
+
+    if field.get_datatype() == fieldtype {
+        return Some(field.clone());
+    }
+
+    let number = || <[u8; 8]>::try_from(field.data.as_slice()).ok();
+
+    match (field.get_datatype(), fieldtype) {
+        (KeyType::UInt64, KeyType::Int64) => {
+            let value = i64::try_from(u64::from_le_bytes(number()?)).ok()?;
+            Some(Field::new(key, fieldtype, value.to_le_bytes().to_vec()))
+        }
+        (KeyType::UInt64, KeyType::Float64) => {
+            let value = u64::from_le_bytes(number()?) as f64;
+            Some(Field::new(key, fieldtype, value.to_le_bytes().to_vec()))
+        }
+        (KeyType::Int64, KeyType::Float64) => {
+            let value = i64::from_le_bytes(number()?) as f64;
+            Some(Field::new(key, fieldtype, value.to_le_bytes().to_vec()))
+        }
+        (KeyType::String, KeyType::Timestamp) => {
+            let micros = parse_timestamp(str::from_utf8(&field.data).ok()?)?;
+            Some(Field::timestamp(key, micros))
+        }
+        _ => None,
+    }
+}
+
+fn length(field: &Field) -> usize {
+    match field.get_datatype() {
+        KeyType::Array | KeyType::Object => field.fields.len(),
+        _ => field.data.len(),
+    }
+}
+
+pub fn type_name(fieldtype: KeyType) -> &'static str {
+    match fieldtype {
+        KeyType::String => "string",
+        KeyType::UInt64 => "uint64",
+        KeyType::Int64 => "int64",
+        KeyType::Float64 => "float64",
+        KeyType::Bool => "bool",
+        KeyType::Null => "null",
+        KeyType::Bytes => "bytes",
+        KeyType::Object => "object",
+        KeyType::Array => "array",
+        KeyType::Timestamp => "timestamp",
+    }
+}
+
+fn parse_type(name: &str) -> Option<KeyType> {
+    [
+        KeyType::String,
+        KeyType::UInt64,
+        KeyType::Int64,
+        KeyType::Float64,
+        KeyType::Bool,
+        KeyType::Null,
+        KeyType::Bytes,
+        KeyType::Object,
+        KeyType::Array,
+        KeyType::Timestamp,
+    ]
+    .into_iter()
+    .find(|fieldtype| type_name(*fieldtype) == name.to_lowercase())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{DatabaseBuilder, KeyTypeSize};
+    use serde_json::json;
+    use std::io::Cursor;
+
+    fn schema_of(value: Value) -> Schema {
+        Schema::from_json(&value).unwrap()
+    }
+
+    fn schema() -> Schema {
+        Schema::from_json(&json!({"fields": [
+            {"name": "name", "type": "string", "required": true, "max_length": 6},
+            {"name": "age", "type": "uint64", "default": 18},
+            {"name": "score", "type": "float64"},
+            {"name": "born", "type": "timestamp"},
+        ]}))
+        .unwrap()
+    }
+
+    #[test]
+    fn validate() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        db.set_schema(Some(schema())).unwrap();
+
+        // defaults are filled in and numbers widened to their declared type
+        let data = db
+            .insert_json(
+                &json!({"id": 1, "name": "jonas", "score": 3, "born": "2000-01-01T00:00:00Z"}),
+            )
+            .unwrap();
+        assert_eq!(data.get_field(b"age").unwrap().get_data(), "18");
+        assert_eq!(
+            data.get_field(b"score").unwrap().get_datatype(),
+            KeyType::Float64
+        );
+        assert_eq!(
+            data.get_field(b"born").unwrap().get_datatype(),
+            KeyType::Timestamp
+        );
+
+        // every violation is reported at once
+        let err = db
+            .insert_json(&json!({"id": 2, "age": "old", "score": "high", "extra": true}))
+            .unwrap_err();
+        let DatabaseError::Schema(violations) = err else {
+            panic!("{err}")
+        };
+        let fields: Vec<&str> = violations.iter().map(|v| v.field.as_str()).collect();
+        assert_eq!(fields, vec!["name", "age", "score"]);
+
+        assert!(matches!(
+            db.update_json(&json!({"id": 1, "name": "jonathan"})),
+            Err(DatabaseError::Schema(violations)) if violations.len() == 1
+        ));
+
+        // optional fields can be null, required ones can't
+        db.update_json(&json!({"id": 1, "name": "jonas", "score": null}))
+            .unwrap();
+        assert!(db.update_json(&json!({"id": 1, "name": null})).is_err());
+
+        assert!(db.get(&2_u64.to_le_bytes()).unwrap().is_none());
+        assert!(db.check().unwrap().is_clean());
+
+        // a schema the stored records don't match is refused, without one anything goes
+        let strict = Schema::from_json(&json!({"fields": [
+            {"name": "email", "type": "string", "required": true},
+        ]}))
+        .unwrap();
+        assert!(matches!(
+            db.set_schema(Some(strict)),
+            Err(DatabaseError::Schema(_))
+        ));
+
+        db.set_schema(None).unwrap();
+        db.insert_json(&json!({"id": 2, "age": "old"})).unwrap();
+    }
+
+    #[test]
+    fn left_behind() {
+        let mut db = DatabaseBuilder::new(Cursor::new(Vec::new()))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        db.insert_json(&json!({"id": 1, "text": "a".repeat(3000)}))
+            .unwrap();
+        db.insert_json(&json!({"id": 2, "text": "a"})).unwrap();
+
+        db.alter(Migration::Add(Field::new(
+            b"notes".to_vec(),
+            KeyType::String,
+            vec![b'b'; 2000],
+        )))
+        .unwrap();
+
+        // the big record doesn't fit in its page with the new field, so the migration is kept
+        assert_eq!(db.upgrade_records(10).unwrap(), 2);
+        assert_eq!(db.upgrade_records(10).unwrap(), 0);
+        assert_eq!(db.definition().unwrap().migrations.len(), 1);
+
+        let data = db.get(&1_u64.to_le_bytes()).unwrap().unwrap();
+        assert_eq!(data.get_field(b"notes").unwrap().get_data().len(), 2000);
+        assert!(db.check().unwrap().is_clean());
+    }
+
+    #[test]
+    fn from_json() {
+        let schema = schema();
+
+        assert_eq!(schema.fields.len(), 4);
+        assert_eq!(schema.fields[0].max_len, Some(6));
+        assert_eq!(
+            Schema::from_json(&schema.to_json()).unwrap().to_json(),
+            schema.to_json()
+        );
+
+        for invalid in [
+            json!({"fields": [{"name": "a", "type": "text"}]}),
+            json!({"fields": [{"name": "a", "type": "uint64", "max_length": 3}]}),
+            json!({"fields": [{"name": "a", "type": "uint64", "default": "x"}]}),
+            json!({"fields": [{"name": "a", "type": "string"}, {"name": "a", "type": "bool"}]}),
+            json!({"fields": {}}),
+        ] {
+            assert!(matches!(
+                Schema::from_json(&invalid),
+                Err(DatabaseError::InvalidSchema(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn evolve() {
+        let file = vec![0x00; 4096];
+
+        let mut db = DatabaseBuilder::new(Cursor::new(file))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build_mock_u64();
+
+        db.create_index(b"age", false).unwrap();
+        db.set_schema(Some(schema_of(json!({"fields": [
+            {"name": "name", "type": "string", "required": true},
+            {"name": "weight", "type": "uint64"},
+        ]}))))
+        .unwrap();
+
+        db.alter(Migration::Rename(b"name".to_vec(), b"first_name".to_vec()))
+            .unwrap();
+        db.alter(Migration::Drop(b"weight".to_vec())).unwrap();
+        db.alter(Migration::Add(Field::new(
+            b"active".to_vec(),
+            KeyType::Bool,
+            vec![0x01],
+        )))
+        .unwrap();
+
+        for invalid in [
+            Migration::Drop(b"id".to_vec()),
+            Migration::Rename(b"age".to_vec(), b"years".to_vec()),
+            Migration::Rename(b"cpr".to_vec(), b"cpr".to_vec()),
+        ] {
+            assert!(matches!(
+                db.alter(invalid),
+                Err(DatabaseError::InvalidMigration(_))
+            ));
+        }
+
+        // stored records are migrated as they are read
+        let raw = |db: &mut Database<Cursor<Vec<u8>>>, id: u64| match PageHandler::get_page(
+            &mut db.source,
+            id,
+        )
+        .unwrap()
+        .pagetype
+        {
+            PageType::Data(data) => data,
+            _ => panic!(),
+        };
+        assert_eq!(raw(&mut db, 5).version, 0);
+
+        let jonas = db.get(&1_u64.to_le_bytes()).unwrap().unwrap();
+        assert_eq!(jonas.version, 3);
+        assert_eq!(jonas.get_field(b"first_name").unwrap().get_data(), "jonas");
+        assert!(jonas.get_field(b"name").is_none());
+        assert!(jonas.get_field(b"weight").is_none());
+        assert_eq!(jonas.get_field(b"active").unwrap().get_data(), "true");
+
+        // the schema follows the changes
+        let names: Vec<Vec<u8>> = db
+            .schema()
+            .unwrap()
+            .unwrap()
+            .fields
+            .into_iter()
+            .map(|field| field.name)
+            .collect();
+        assert_eq!(names, vec![b"first_name".to_vec(), b"active".to_vec()]);
+
+        // new records are written in the current shape
+        assert!(db.insert_json(&json!({"id": 7, "name": "a"})).is_err());
+        let data = db
+            .insert_json(&json!({"id": 7, "first_name": "a", "weight": 80}))
+            .unwrap();
+        assert_eq!(data.version, 3);
+        assert_eq!(data.get_field(b"weight").unwrap().get_data(), "80");
+
+        assert!(db.check().unwrap().is_clean());
+
+        // and old ones are rewritten in batches
+        // 6 records behind and the one just inserted
+        assert_eq!(db.upgrade_records(4).unwrap(), 4);
+        assert_eq!(db.upgrade_records(4).unwrap(), 3);
+        assert_eq!(db.upgrade_records(4).unwrap(), 0);
+
+        // once every record has been through them the migrations are dropped
+        let collection = db.definition().unwrap();
+        assert!(collection.migrations.is_empty());
+        assert_eq!(collection.version(), 3);
+        assert_eq!(raw(&mut db, 5).version, 3);
+        assert!(raw(&mut db, 5).get_field(b"first_name").is_some());
+
+        // a finished pass isn't scanned again until the shape changes
+        assert_eq!(db.upgrade_records(10).unwrap(), 0);
+        db.alter(Migration::Drop(b"nickname".to_vec())).unwrap();
+        assert_eq!(db.upgrade_records(10).unwrap(), 7);
+        assert_eq!(raw(&mut db, 5).version, 4);
+        assert_eq!(db.definition().unwrap().migrated, 4);
+
+        let mut db = Database::open(db.source).unwrap();
+        assert!(db.check().unwrap().is_clean());
+        assert_eq!(
+            db.find_by(
+                b"age",
+                &Field::new(Vec::new(), KeyType::UInt64, 55_u64.to_le_bytes().to_vec())
+            )
+            .unwrap()
+            .len(),
+            3
+        );
+    }
+}
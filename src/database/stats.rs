@@ -0,0 +1,168 @@
+use crate::database::handler::*;
+use crate::database::page::*;
+use crate::database::tree::Tree;
+use crate::database::{Database, DatabaseError};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// NOTE: stats are gathered when asked for. the free list and every tree are walked from the
+// header, so they cost a read per free page and per level of each tree. a summary only reads
+// the catalog
+
+pub struct Stats {
+    pub file_size: u64,
+    pub pages: u64,
+    pub free_pages: u64,
+    pub collections: Vec<CollectionStats>,
+}
+
+pub struct CollectionStats {
+    pub name: String,
+    pub records: u64,
+    pub height: usize,
+    pub order: u8,
+    pub keys: Vec<KeyField>,
+    pub indexes: usize,
+}
+
+impl<T: Read + Write + Seek> Database<T> {
+    // reads the header and the catalog it points to, failing if either doesn't parse
+    pub fn validate(&mut self) -> Result<(), DatabaseError> {
+        self.catalog().map(|_| ())
+    }
+
+    pub fn stats(&mut self) -> Result<Stats, DatabaseError> {
+        let mut stats = self.summary()?;
+        stats.free_pages = self.free_pages()?;
+
+        for (stats, collection) in stats
+            .collections
+            .iter_mut()
+            .zip(self.catalog()?.collections)
+        {
+            let tree = Tree::new(
+                collection.root,
+                collection.keytype(),
+                collection.order.into(),
+            );
+
+            stats.height = tree.height(&mut self.source)?;
+        }
+
+        Ok(stats)
+    }
+
+    // the size of the file and what the catalog says about each collection, without walking
+    // the free list or any tree, so free pages and heights are left at 0
+    pub fn summary(&mut self) -> Result<Stats, DatabaseError> {
+        let file_size = self.file_size()?;
+
+        let collections = self
+            .catalog()?
+            .collections
+            .into_iter()
+            .map(|collection| CollectionStats {
+                name: String::from_utf8_lossy(&collection.name).to_string(),
+                records: collection.elements,
+                height: 0,
+                order: collection.order,
+                keys: collection.keys,
+                indexes: collection.indexes.len(),
+            })
+            .collect();
+
+        Ok(Stats {
+            file_size,
+            pages: pages(file_size),
+            free_pages: 0,
+            collections,
+        })
+    }
+
+    // counts the pages on the free list by walking it from the header
+    pub fn free_pages(&mut self) -> Result<u64, DatabaseError> {
+        let pages = pages(self.file_size()?);
+
+        let mut free_pages = 0;
+        let mut free = HeaderHandler::get(&mut self.source)?.free;
+
+        while free != 0 {
+            free_pages += 1;
+
+            if free_pages > pages {
+                return Err(DatabaseError::Integrity(
+                    "the free list is longer than the file".to_string(),
+                ));
+            }
+
+            free = match PageHandler::get_page(&mut self.source, free)?.pagetype {
+                PageType::Free(next) => next,
+                _ => {
+                    return Err(DatabaseError::Integrity(format!(
+                        "page {free} is on the free list but is not free"
+                    )));
+                }
+            };
+        }
+
+        Ok(free_pages)
+    }
+
+    fn file_size(&mut self) -> Result<u64, DatabaseError> {
+        self.source
+            .seek(SeekFrom::End(0))
+            .map_err(|err| DatabaseError::FileHandlerError(err.into()))
+    }
+}
+
+// the header takes up the first block
+fn pages(file_size: u64) -> u64 {
+    (file_size / PAGESIZE).saturating_sub(1)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::database::{DatabaseBuilder, KeyTypeSize};
+    use serde_json::json;
+    use std::io::Cursor;
+
+    #[test]
+    fn stats() {
+        let mut db = DatabaseBuilder::new(Cursor::new(Vec::new()))
+            .key(b"id".to_vec())
+            .keytype(KeyTypeSize::UInt64)
+            .build();
+
+        db.validate().unwrap();
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.collections[0].records, 0);
+        assert_eq!(stats.collections[0].height, 1);
+        assert_eq!(stats.free_pages, 0);
+
+        for id in 0..100_u64 {
+            db.insert_json(&json!({"id": id})).unwrap();
+        }
+
+        for id in 0..10_u64 {
+            db.delete_json(&json!(id)).unwrap();
+        }
+
+        let stats = db.stats().unwrap();
+        let collection = &stats.collections[0];
+
+        let summary = db.summary().unwrap();
+        assert_eq!(summary.collections[0].records, 90);
+        assert_eq!(summary.file_size, stats.file_size);
+        assert_eq!(db.free_pages().unwrap(), stats.free_pages);
+
+        assert_eq!(collection.name, "default");
+        assert_eq!(collection.records, 90);
+        assert!(collection.height > 2);
+        assert_eq!(collection.keys[0].name, b"id");
+        assert!(stats.free_pages >= 10);
+        assert_eq!(
+            stats.file_size,
+            (stats.pages + 1) * crate::database::page::PAGESIZE
+        );
+    }
+}
@@ -0,0 +1,594 @@
+use crate::database::DatabaseError;
+use crate::database::handler::*;
+use crate::database::page::*;
+use crate::metrics;
+use std::collections::{HashSet, VecDeque};
+use std::io::{Read, Seek, Write};
+
+type Id = u64;
+
+// a b+ tree in the pages of the file. the primary tree maps keys to data pages, index trees map
+// their keys to nothing. operations that change the root update it here and leave it to the
+// caller to store it
+#[derive(Debug, Clone)]
+pub struct Tree {
+    pub root: Id,
+    pub keytype: KeyType,
+    pub order: usize,
+}
+
+impl Tree {
+    pub fn new(root: Id, keytype: KeyType, order: usize) -> Tree {
+        Tree {
+            root,
+            keytype,
+            order,
+        }
+    }
+
+    // creates an empty tree with a single leaf as its root
+    pub fn create<T: Read + Write + Seek>(
+        source: &mut T,
+        keytype: KeyType,
+        order: usize,
+    ) -> Result<Tree, DatabaseError> {
+        let root = PageHandler::new_page(source, PageType::Leaf(Leaf::new(keytype)))?;
+
+        Ok(Tree::new(root.id, keytype, order))
+    }
+
+    pub fn find<T: Read + Write + Seek>(
+        &self,
+        source: &mut T,
+        key: &[u8],
+    ) -> Result<Option<Id>, DatabaseError> {
+        let (leaf, _) = self.descend(source, key)?;
+
+        let PageType::Leaf(leaf) = leaf.pagetype else {
+            return Err(unexpected("leaf"));
+        };
+
+        Ok(leaf
+            .keys
+            .binary_search_by(|leaf_key| leaf_key.as_slice().cmp(key))
+            .ok()
+            .map(|idx| leaf.pointers[idx]))
+    }
+
+    // the levels from the root down to the leaves, a tree that is a single leaf has height 1
+    pub fn height<T: Read + Write + Seek>(&self, source: &mut T) -> Result<usize, DatabaseError> {
+        let mut height = 1;
+        let mut page = PageHandler::get_page(source, self.root)?;
+
+        while let PageType::Node(node) = page.pagetype {
+            let Some(&child) = node.pointers.first() else {
+                return Err(DatabaseError::Integrity(format!(
+                    "node {} has no children",
+                    page.id
+                )));
+            };
+
+            height += 1;
+            page = PageHandler::get_page(source, child)?;
+        }
+
+        Ok(height)
+    }
+
+    pub fn insert<T: Read + Write + Seek>(
+        &mut self,
+        source: &mut T,
+        key: Vec<u8>,
+        pointer: Id,
+    ) -> Result<(), DatabaseError> {
+        let (mut current_node, mut nodestack) = self.descend(source, &key)?;
+
+        if let PageType::Leaf(ref mut leaf) = current_node.pagetype {
+            let idx = match leaf.keys.binary_search(&key) {
+                Ok(_) => return Err(DatabaseError::DuplicateKey),
+                Err(idx) => idx,
+            };
+
+            leaf.keys.insert(idx, key);
+            leaf.pointers.insert(idx, pointer);
+        } else {
+            return Err(unexpected("leaf"));
+        }
+
+        // split the page for as long as it overflows, pushing the separator up into the parent
+        while current_node.overflows(self.order) {
+            let (separator, split_page) = PageHandler::split(source, &mut current_node)?;
+
+            if let Some(mut parent) = nodestack.pop_front() {
+                if let PageType::Node(ref mut node) = parent.pagetype {
+                    let idx = child_idx(&parent.id, node, current_node.id)?;
+
+                    node.keys.insert(idx, separator);
+                    node.pointers.insert(idx + 1, split_page.id);
+                }
+
+                current_node = parent;
+            } else {
+                let root = PageHandler::new_page(
+                    source,
+                    PageType::Node(Node {
+                        keytype: self.keytype,
+                        keys: vec![separator],
+                        pointers: vec![current_node.id, split_page.id],
+                    }),
+                )?;
+
+                self.root = root.id;
+
+                return Ok(());
+            }
+        }
+
+        PageHandler::write(source, current_node)?;
+
+        Ok(())
+    }
+
+    // removes a key and returns what it pointed to. pages left with too few keys are merged
+    // with a sibling, or borrow a key from it when the two don't fit in one page
+    pub fn remove<T: Read + Write + Seek>(
+        &mut self,
+        source: &mut T,
+        key: &[u8],
+    ) -> Result<Option<Id>, DatabaseError> {
+        let (mut current_node, mut nodestack) = self.descend(source, key)?;
+
+        let PageType::Leaf(ref mut leaf) = current_node.pagetype else {
+            return Err(unexpected("leaf"));
+        };
+
+        let Ok(idx) = leaf
+            .keys
+            .binary_search_by(|leaf_key| leaf_key.as_slice().cmp(key))
+        else {
+            return Ok(None);
+        };
+
+        leaf.keys.remove(idx);
+        let pointer = leaf.pointers.remove(idx);
+
+        while let Some(mut parent) = nodestack.pop_front() {
+            if current_node.keys_len() >= self.order / 2 {
+                PageHandler::write(source, current_node)?;
+                return Ok(Some(pointer));
+            }
+
+            self.rebalance(source, &mut parent, current_node)?;
+
+            current_node = parent;
+        }
+
+        // a root node left with a single child hands the root over to it
+        match current_node.pagetype {
+            PageType::Node(ref node) if node.keys.is_empty() => {
+                self.root = node.pointers[0];
+                PageHandler::free(source, current_node.id)?;
+            }
+            _ => PageHandler::write(source, current_node)?,
+        }
+
+        Ok(Some(pointer))
+    }
+
+    // returns every key from start up to but not including end, with what it points to
+    pub fn scan<T: Read + Write + Seek>(
+        &self,
+        source: &mut T,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<Vec<(Vec<u8>, Id)>, DatabaseError> {
+        self.scan_limit(source, start, end, usize::MAX)
+    }
+
+    // like scan, but stops following the leaf chain once it has found limit keys
+    pub fn scan_limit<T: Read + Write + Seek>(
+        &self,
+        source: &mut T,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Id)>, DatabaseError> {
+        let mut current_node = PageHandler::get_page(source, self.root)?;
+
+        while let PageType::Node(ref node) = current_node.pagetype {
+            let child_id = match start {
+                Some(start) => node.child(start),
+                None => node.pointers[0],
+            };
+
+            current_node = PageHandler::get_page(source, child_id)?;
+        }
+
+        let mut found = Vec::new();
+
+        loop {
+            let PageType::Leaf(leaf) = current_node.pagetype else {
+                return Err(unexpected("leaf"));
+            };
+
+            for (key, pointer) in leaf.keys.into_iter().zip(leaf.pointers) {
+                if start.is_some_and(|start| key.as_slice() < start) {
+                    continue;
+                }
+
+                if found.len() == limit || end.is_some_and(|end| key.as_slice() >= end) {
+                    return Ok(found);
+                }
+
+                found.push((key, pointer));
+            }
+
+            if leaf.next_leaf_pointer == 0 {
+                return Ok(found);
+            }
+
+            current_node = PageHandler::get_page(source, leaf.next_leaf_pointer)?;
+        }
+    }
+
+    // walks the whole tree checking its structure and returns the entries of its leaves in
+    // order. pages already in visited belong to another tree
+    pub fn check<T: Read + Write + Seek>(
+        &self,
+        source: &mut T,
+        visited: &mut HashSet<Id>,
+    ) -> Result<Vec<(Vec<u8>, Id)>, DatabaseError> {
+        let mut entries = Vec::new();
+        let mut leaves = Vec::new();
+        let mut leaf_depth = None;
+        let mut queue = VecDeque::from([(self.root, None::<Vec<u8>>, None::<Vec<u8>>, 0)]);
+
+        while let Some((id, lower, upper, depth)) = queue.pop_front() {
+            if !visited.insert(id) {
+                return Err(DatabaseError::Integrity(format!(
+                    "page {id} is referenced more than once"
+                )));
+            }
+
+            let page = PageHandler::get_page(source, id)?;
+
+            let (keys, keytype) = match &page.pagetype {
+                PageType::Node(node) => (&node.keys, node.keytype),
+                PageType::Leaf(leaf) => (&leaf.keys, leaf.keytype),
+                PageType::Data(_) | PageType::Free(_) | PageType::Catalog(_) => {
+                    return Err(DatabaseError::Integrity(format!(
+                        "page {id} is a data, free or catalog page inside the tree"
+                    )));
+                }
+            };
+
+            if keytype != self.keytype {
+                return Err(DatabaseError::Integrity(format!(
+                    "page {id} has keytype {keytype:?}, expected {:?}",
+                    self.keytype
+                )));
+            }
+
+            if !keys.is_sorted() || keys.windows(2).any(|pair| pair[0] == pair[1]) {
+                return Err(DatabaseError::Integrity(format!(
+                    "keys in page {id} are not strictly sorted"
+                )));
+            }
+
+            let out_of_bounds = keys.iter().any(|key| {
+                lower.as_ref().is_some_and(|lower| key < lower)
+                    || upper.as_ref().is_some_and(|upper| key >= upper)
+            });
+
+            if out_of_bounds {
+                return Err(DatabaseError::Integrity(format!(
+                    "keys in page {id} are outside the range of its parent"
+                )));
+            }
+
+            match page.pagetype {
+                PageType::Node(node) => {
+                    if node.pointers.len() != node.keys.len() + 1 {
+                        return Err(DatabaseError::Integrity(format!(
+                            "node {id} has {} keys but {} pointers",
+                            node.keys.len(),
+                            node.pointers.len()
+                        )));
+                    }
+
+                    for (idx, pointer) in node.pointers.iter().enumerate() {
+                        let child_lower = match idx {
+                            0 => lower.clone(),
+                            _ => Some(node.keys[idx - 1].clone()),
+                        };
+                        let child_upper = node.keys.get(idx).cloned().or(upper.clone());
+
+                        queue.push_back((*pointer, child_lower, child_upper, depth + 1));
+                    }
+                }
+                PageType::Leaf(leaf) => {
+                    if *leaf_depth.get_or_insert(depth) != depth {
+                        return Err(DatabaseError::Integrity(format!(
+                            "leaf {id} is at depth {depth}, expected {}",
+                            leaf_depth.unwrap_or_default()
+                        )));
+                    }
+
+                    if leaf.pointers.len() != leaf.keys.len() {
+                        return Err(DatabaseError::Integrity(format!(
+                            "leaf {id} has {} keys but {} pointers",
+                            leaf.keys.len(),
+                            leaf.pointers.len()
+                        )));
+                    }
+
+                    leaves.push((id, leaf.next_leaf_pointer));
+                    entries.extend(leaf.keys.into_iter().zip(leaf.pointers));
+                }
+                PageType::Data(_) | PageType::Free(_) | PageType::Catalog(_) => unreachable!(),
+            }
+        }
+
+        // leaves are visited left to right, so each one has to point at the next
+        for pair in leaves.windows(2) {
+            if pair[0].1 != pair[1].0 {
+                return Err(DatabaseError::Integrity(format!(
+                    "leaf {} links to {}, expected {}",
+                    pair[0].0, pair[0].1, pair[1].0
+                )));
+            }
+        }
+
+        if let Some((id, next)) = leaves.last()
+            && *next != 0
+        {
+            return Err(DatabaseError::Integrity(format!(
+                "last leaf {id} links to {next}"
+            )));
+        }
+
+        Ok(entries)
+    }
+
+    // the ids of every node and leaf in the tree
+    pub fn pages<T: Read + Write + Seek>(&self, source: &mut T) -> Result<Vec<Id>, DatabaseError> {
+        let mut pages = Vec::new();
+        let mut queue = VecDeque::from([self.root]);
+
+        while let Some(id) = queue.pop_front() {
+            pages.push(id);
+
+            match PageHandler::get_page(source, id)?.pagetype {
+                PageType::Node(node) => queue.extend(node.pointers),
+                PageType::Leaf(_) => {}
+                _ => return Err(unexpected("node or leaf")),
+            }
+        }
+
+        Ok(pages)
+    }
+
+    // finds the leaf the key belongs in, along with the nodes above it, closest first
+    fn descend<T: Read + Write + Seek>(
+        &self,
+        source: &mut T,
+        key: &[u8],
+    ) -> Result<(Page, VecDeque<Page>), DatabaseError> {
+        let mut nodestack = VecDeque::new();
+
+        let mut current_node = PageHandler::get_page(source, self.root)?;
+
+        while let PageType::Node(ref node) = current_node.pagetype {
+            let child_id = node.child(key);
+
+            nodestack.push_front(current_node.clone());
+            current_node = PageHandler::get_page(source, child_id)?;
+        }
+
+        Ok((current_node, nodestack))
+    }
+
+    // fixes up a page with too few keys by merging it with a sibling or borrowing a key from
+    // one. the parent is changed in memory and written by the caller
+    fn rebalance<T: Read + Write + Seek>(
+        &self,
+        source: &mut T,
+        parent: &mut Page,
+        page: Page,
+    ) -> Result<(), DatabaseError> {
+        let PageType::Node(ref mut node) = parent.pagetype else {
+            return Err(unexpected("node"));
+        };
+
+        let idx = child_idx(&parent.id, node, page.id)?;
+
+        if node.pointers.len() < 2 {
+            PageHandler::write(source, page)?;
+            return Ok(());
+        }
+
+        // the page is paired with its left sibling, or its right one if it is the first child
+        let (left_idx, page_is_left) = match idx {
+            0 => (0, true),
+            idx => (idx - 1, false),
+        };
+
+        let sibling = PageHandler::get_page(
+            source,
+            node.pointers[if page_is_left { 1 } else { left_idx }],
+        )?;
+
+        let (mut left, mut right) = match page_is_left {
+            true => (page, sibling),
+            false => (sibling, page),
+        };
+
+        let mut merged = left.clone();
+        merged
+            .merge(right.clone(), node.keys[left_idx].clone())
+            .map_err(HandlerError::from)?;
+
+        if !merged.overflows(self.order) {
+            PageHandler::write(source, merged)?;
+            PageHandler::free(source, right.id)?;
+            metrics::MERGES.inc();
+
+            node.keys.remove(left_idx);
+            node.pointers.remove(left_idx + 1);
+
+            return Ok(());
+        }
+
+        let sibling_len = match page_is_left {
+            true => right.keys_len(),
+            false => left.keys_len(),
+        };
+
+        // a sibling that can't spare a key leaves the page short, which is still a valid tree
+        if sibling_len > self.order / 2 {
+            let separator = node.keys[left_idx].clone();
+            node.keys[left_idx] = rotate(&mut left, &mut right, separator, page_is_left)?;
+        }
+
+        PageHandler::write(source, left)?;
+        PageHandler::write(source, right)?;
+
+        Ok(())
+    }
+}
+
+// moves one key between two siblings through the separator in their parent, from the right
+// page into the left one when to_left is set. returns the new separator
+fn rotate(
+    left: &mut Page,
+    right: &mut Page,
+    separator: Vec<u8>,
+    to_left: bool,
+) -> Result<Vec<u8>, DatabaseError> {
+    match (&mut left.pagetype, &mut right.pagetype) {
+        (PageType::Leaf(left), PageType::Leaf(right)) => {
+            if to_left {
+                left.keys.push(right.keys.remove(0));
+                left.pointers.push(right.pointers.remove(0));
+            } else {
+                right
+                    .keys
+                    .insert(0, left.keys.pop().ok_or(unexpected("key"))?);
+                right
+                    .pointers
+                    .insert(0, left.pointers.pop().ok_or(unexpected("pointer"))?);
+            }
+
+            Ok(left.separator(right))
+        }
+        (PageType::Node(left), PageType::Node(right)) => {
+            if to_left {
+                left.keys.push(separator);
+                left.pointers.push(right.pointers.remove(0));
+
+                Ok(right.keys.remove(0))
+            } else {
+                right.keys.insert(0, separator);
+                right
+                    .pointers
+                    .insert(0, left.pointers.pop().ok_or(unexpected("pointer"))?);
+
+                left.keys.pop().ok_or(unexpected("key"))
+            }
+        }
+        _ => Err(unexpected("siblings of the same type")),
+    }
+}
+
+fn child_idx(parent: &Id, node: &Node, child: Id) -> Result<usize, DatabaseError> {
+    node.pointers
+        .iter()
+        .position(|pointer| *pointer == child)
+        .ok_or(DatabaseError::Integrity(format!(
+            "node {parent} does not point to its child {child}"
+        )))
+}
+
+fn unexpected(expected: &str) -> DatabaseError {
+    DatabaseError::UnexpectedPagetype(expected.to_string(), "something else".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::key;
+    use std::io::Cursor;
+
+    fn tree(order: usize) -> (Cursor<Vec<u8>>, Tree) {
+        let mut file = Cursor::new(vec![0x00; 4096]);
+
+        HeaderHandler::write(
+            &mut file,
+            Header {
+                catalog: 0,
+                free: 0,
+            },
+        )
+        .unwrap();
+
+        let tree = Tree::create(&mut file, KeyType::UInt64, order).unwrap();
+
+        (file, tree)
+    }
+
+    fn key(n: u64) -> Vec<u8> {
+        key::encode(KeyType::UInt64, &n.to_le_bytes()).unwrap()
+    }
+
+    #[test]
+    fn remove() {
+        let (mut file, mut tree) = tree(4);
+
+        let ids: Vec<u64> = (0..200).map(|n| (n * 37) % 200).collect();
+
+        for id in &ids {
+            tree.insert(&mut file, key(*id), *id).unwrap();
+        }
+
+        for id in ids.iter().filter(|id| *id % 3 != 0) {
+            assert_eq!(tree.remove(&mut file, &key(*id)).unwrap(), Some(*id));
+            tree.check(&mut file, &mut HashSet::new()).unwrap();
+        }
+
+        assert_eq!(tree.remove(&mut file, &key(1)).unwrap(), None);
+
+        let entries = tree.check(&mut file, &mut HashSet::new()).unwrap();
+        let left: Vec<u64> = entries.iter().map(|(_, pointer)| *pointer).collect();
+        assert_eq!(left, (0..200).filter(|id| id % 3 == 0).collect::<Vec<_>>());
+
+        for id in left {
+            tree.remove(&mut file, &key(id)).unwrap();
+        }
+
+        // everything merged back into a single leaf
+        let root = PageHandler::get_page(&mut file, tree.root).unwrap();
+        assert!(matches!(root.pagetype, PageType::Leaf(leaf) if leaf.keys.is_empty()));
+    }
+
+    #[test]
+    fn reuses_pages() {
+        let (mut file, mut tree) = tree(4);
+
+        for id in 0..100 {
+            tree.insert(&mut file, key(id), id).unwrap();
+        }
+
+        let len = file.get_ref().len();
+
+        for id in 0..100 {
+            tree.remove(&mut file, &key(id)).unwrap();
+        }
+
+        for id in 0..100 {
+            tree.insert(&mut file, key(id), id).unwrap();
+        }
+
+        assert_eq!(file.get_ref().len(), len);
+        tree.check(&mut file, &mut HashSet::new()).unwrap();
+    }
+}
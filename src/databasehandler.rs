@@ -1,15 +1,129 @@
-pub use crate::database::page::KeyType;
-use crate::database::{Database, DatabaseBuilder, KeyTypeSize};
+use crate::apierror::ApiError;
+use crate::config::{Config, Durability};
+use crate::database::cache::PageCache;
+use crate::database::json::JsonError;
+use crate::database::page::{Data, KeyField, KeyGeneration, KeyType, Migration, PAGESIZE, Schema};
+use crate::database::stats::Stats;
+use crate::database::{
+    Bounds, DEFAULT_COLLECTION, Database, DatabaseBuilder, DatabaseError, KeyTypeSize,
+};
+use crate::database::{json, key, schema};
+use crate::metrics;
 use axum::http::{HeaderName, StatusCode, header};
+use base64::prelude::*;
+use serde_json::{Value, json};
 use std::fs::{File, OpenOptions};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
-pub type DatabaseResponse = (StatusCode, [(HeaderName, String); 1], String);
+const DATABASE_PATH: &str = ".db";
+
+// pages cached by the mock databases
+const MOCK_CACHE_SIZE: usize = 64;
+
+// how many records the background upgrade rewrites each time it takes the lock
+const UPGRADE_BATCH: usize = 64;
+
+// how many records a listing returns when no limit is given, and at most
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+// how long metrics scrapes reuse a count of the free list before walking it again
+const FREE_PAGES_AGE: Duration = Duration::from_secs(60);
+
+// how many records a streamed scan reads each time it takes the lock
+pub const SCAN_CHUNK: usize = 256;
+
+// the most items a batch can hold
+const MAX_BATCH: usize = 1000;
+
+pub type DatabaseResponse = Result<(StatusCode, [(HeaderName, String); 1], String), ApiError>;
 
 pub struct DatabaseHandler {
-    db: Database<File>,
+    db: Database<PageCache<File>>,
+    path: PathBuf,
+    cache_size: usize,
+    durability: Durability,
+
+    // set when recovering from a panic failed, so later requests don't try again
+    broken: bool,
+
+    // the pages on the free list when metrics last counted them, and when that was
+    free_pages: Option<(Instant, u64)>,
 }
 
 impl DatabaseHandler {
+    // opens the configured database file, or creates it with the configured key
+    pub fn open(config: &Config) -> Result<DatabaseHandler, DatabaseError> {
+        let exists = config
+            .database
+            .metadata()
+            .is_ok_and(|metadata| metadata.len() > 0);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&config.database)
+            .map_err(|err| DatabaseError::FileHandlerError(err.into()))?;
+
+        let source = PageCache::new(file, config.cache_size);
+
+        let mut db = if exists {
+            let db = Database::open(source)?;
+
+            if config.keys_given && db.selected() == DEFAULT_COLLECTION.as_bytes() {
+                key_mismatch(config, db.keys(), db.generation());
+            }
+
+            db
+        } else {
+            DatabaseBuilder::new(source)
+                .key(config.key_field.as_bytes().to_vec())
+                .keytype(config.keytype)
+                .generate(config.generation)
+                .try_build()?
+        };
+
+        let selected = db.selected().to_vec();
+
+        for declared in &config.indexes {
+            let collection = declared.collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+            db.use_collection(collection.as_bytes())?;
+
+            let field = declared.field.as_bytes();
+
+            match db.indexes()?.iter().find(|index| index.field == field) {
+                Some(index) if index.unique != declared.unique => log::warn!(
+                    "index on {} in {collection} is declared with unique = {}, but was created \
+                     with unique = {}, leaving it as it is",
+                    declared.field,
+                    declared.unique,
+                    index.unique
+                ),
+                Some(_) => {}
+                None => {
+                    db.create_index(field, declared.unique)?;
+                    log::info!("indexed {} in {collection}", declared.field);
+                }
+            }
+        }
+
+        db.use_collection(&selected)?;
+
+        Ok(DatabaseHandler {
+            db,
+            path: config.database.clone(),
+            cache_size: config.cache_size,
+            durability: config.durability,
+            broken: false,
+            free_pages: None,
+        })
+    }
+
     #[allow(dead_code)]
     pub fn new_u64() -> DatabaseHandler {
         let file = OpenOptions::new()
@@ -17,15 +131,27 @@ impl DatabaseHandler {
             .write(true)
             .create(true)
             .truncate(true)
-            .open(".db")
+            .open(DATABASE_PATH)
             .unwrap();
 
-        let db = DatabaseBuilder::new(file)
+        let mut db = DatabaseBuilder::new(PageCache::new(file, MOCK_CACHE_SIZE))
             .key(b"id".to_vec())
             .keytype(KeyTypeSize::UInt64)
+            .generate(KeyGeneration::AutoIncrement)
             .build_mock_u64();
 
-        DatabaseHandler { db }
+        // cprs belong to one person each
+        db.create_index(b"cpr", true)
+            .expect("couldnt create index on cpr");
+
+        DatabaseHandler {
+            db,
+            path: PathBuf::from(DATABASE_PATH),
+            cache_size: MOCK_CACHE_SIZE,
+            durability: Durability::Relaxed,
+            broken: false,
+            free_pages: None,
+        }
     }
 
     #[allow(dead_code)]
@@ -35,36 +161,796 @@ impl DatabaseHandler {
             .write(true)
             .create(true)
             .truncate(true)
-            .open(".db")
+            .open(DATABASE_PATH)
             .unwrap();
 
-        let db = DatabaseBuilder::new(file)
+        let mut db = DatabaseBuilder::new(PageCache::new(file, MOCK_CACHE_SIZE))
             .key(b"id".to_vec())
-            .keytype(KeyTypeSize::String(10))
+            .keytype(KeyTypeSize::String(26))
+            .generate(KeyGeneration::Ulid)
             .build_mock_string();
 
-        DatabaseHandler { db }
+        // cprs belong to one person each
+        db.create_index(b"cpr", true)
+            .expect("couldnt create index on cpr");
+
+        DatabaseHandler {
+            db,
+            path: PathBuf::from(DATABASE_PATH),
+            cache_size: MOCK_CACHE_SIZE,
+            durability: Durability::Relaxed,
+            broken: false,
+            free_pages: None,
+        }
+    }
+
+    // reopens the database from what is on disk and checks it, so a handler that panicked
+    // halfway through an operation doesn't take the server down. a record count the operation
+    // left off is recounted, and pages it wrote but never linked are reported
+    pub fn recover(&mut self) -> Result<(), DatabaseError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .map_err(|err| DatabaseError::FileHandlerError(err.into()))?;
+
+        // the cache is dropped with the old database, so nothing from before the panic is kept
+        let mut db = Database::open(PageCache::new(file, self.cache_size))?;
+        let report = db.repair()?;
+
+        if report.is_clean() {
+            log::info!("integrity check after reopening found nothing to repair");
+        }
+
+        for miscount in &report.miscounted {
+            log::warn!(
+                "collection {} counted {} records, found {}, recounted",
+                miscount.collection,
+                miscount.counted,
+                miscount.found
+            );
+        }
+
+        if !report.orphans.is_empty() {
+            log::warn!(
+                "pages {:?} are not part of any collection nor free",
+                report.orphans
+            );
+        }
+
+        self.db = db;
+
+        Ok(())
+    }
+
+    // waits for writes to reach the disk when the durability mode asks for it
+    fn persist(&mut self) -> Result<(), DatabaseError> {
+        match self.durability {
+            Durability::Relaxed => Ok(()),
+            Durability::Sync => self.flush(),
+        }
+    }
+
+    // the cache writes through, so there are no dirty pages to write, only the file to sync
+    pub fn flush(&mut self) -> Result<(), DatabaseError> {
+        self.db
+            .source
+            .sync()
+            .map_err(|err| DatabaseError::FileHandlerError(err.into()))
+    }
+
+    // selects the collection a request works on, requests without one use the default
+    pub fn select(&mut self, collection: &Value) -> Result<(), ApiError> {
+        let name = match collection {
+            Value::Null => DEFAULT_COLLECTION,
+            Value::String(name) => name.as_str(),
+            _ => return Err(bad_request("collection must be a string")),
+        };
+
+        Ok(self.db.use_collection(name.as_bytes())?)
+    }
+
+    // creates a collection keyed on a single field, e.g.
+    // {"collection": "users", "key": "id", "keytype": "string", "size": 26, "generate": "ulid"}
+    pub fn create_collection(&mut self, payload: &Value) -> DatabaseResponse {
+        let (name, keys, generation) = collection_spec(payload).map_err(ApiError::BadRequest)?;
+
+        self.db
+            .create_collection(name.as_bytes(), keys, generation)?;
+        self.persist()?;
+
+        Ok((
+            StatusCode::CREATED,
+            [(header::CONTENT_TYPE, "text/plain".to_string())],
+            format!("created collection {name}"),
+        ))
+    }
+
+    pub fn drop_collection(&mut self, collection: &Value) -> DatabaseResponse {
+        let Value::String(name) = collection else {
+            return Err(bad_request("collection must be a string"));
+        };
+
+        self.db.drop_collection(name.as_bytes())?;
+        self.persist()?;
+
+        Ok((
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain".to_string())],
+            format!("dropped collection {name}"),
+        ))
+    }
+
+    // the database is ready once its header and catalog can be read
+    pub fn ready(&mut self) -> Result<(), DatabaseError> {
+        self.db.validate()
+    }
+
+    // the storage gauges for a metrics scrape. record counts are read from the catalog every
+    // time, the free list is only walked again once the last count is older than FREE_PAGES_AGE
+    pub fn storage(&mut self) -> Result<Stats, DatabaseError> {
+        let mut stats = self.db.summary()?;
+
+        stats.free_pages = match self.free_pages {
+            Some((counted, free_pages)) if counted.elapsed() < FREE_PAGES_AGE => free_pages,
+            _ => {
+                let free_pages = self.db.free_pages()?;
+                self.free_pages = Some((Instant::now(), free_pages));
+                free_pages
+            }
+        };
+
+        Ok(stats)
+    }
+
+    pub fn stats(&mut self) -> DatabaseResponse {
+        let stats = self.db.stats()?;
+
+        let collections: Vec<Value> = stats
+            .collections
+            .iter()
+            .map(|collection| {
+                let keys: Vec<Value> = collection
+                    .keys
+                    .iter()
+                    .map(|key| {
+                        json!({
+                            "field": String::from_utf8_lossy(&key.name),
+                            "type": schema::type_name(key.keytype),
+                            "size": key.size,
+                        })
+                    })
+                    .collect();
+
+                json!({
+                    "name": collection.name,
+                    "records": collection.records,
+                    "height": collection.height,
+                    "order": collection.order,
+                    "keys": keys,
+                    "indexes": collection.indexes,
+                })
+            })
+            .collect();
+
+        let (hits, misses) = self.db.source.hits();
+        let hit_rate = match hits + misses {
+            0 => 0.0,
+            reads => hits as f64 / reads as f64,
+        };
+
+        json_response(
+            StatusCode::OK,
+            json!({
+                "records": stats.collections.iter().map(|collection| collection.records).sum::<u64>(),
+                "file_size": stats.file_size,
+                "page_size": PAGESIZE,
+                "pages": stats.pages,
+                "free_pages": stats.free_pages,
+                "collections": collections,
+                "cache": {
+                    "capacity": self.db.source.capacity(),
+                    "cached": self.db.source.cached(),
+                    "hits": hits,
+                    "misses": misses,
+                    "hit_rate": hit_rate,
+                },
+            })
+            .to_string(),
+        )
     }
 
-    pub fn get_keytype(&mut self) -> KeyType {
-        self.db.get_keytype().unwrap()
+    pub fn list_collections(&mut self) -> DatabaseResponse {
+        json_response(
+            StatusCode::OK,
+            Value::from(self.db.collections()?).to_string(),
+        )
     }
 
-    pub fn get_data(&mut self, key: &[u8]) -> DatabaseResponse {
-        let found = self.db.get(key);
+    // sets the schema of the selected collection, null removes it
+    pub fn set_schema(&mut self, schema: &Value) -> DatabaseResponse {
+        let schema = match schema {
+            Value::Null => None,
+            schema => Some(Schema::from_json(schema)?),
+        };
 
-        if let Ok(Some(data)) = found {
-            (
+        match self.db.set_schema(schema).and_then(|()| self.persist()) {
+            Ok(()) => Ok((
                 StatusCode::OK,
-                [(header::CONTENT_TYPE, "application/json".to_string())],
-                data.json(),
-            )
-        } else {
-            (
-                StatusCode::NOT_FOUND,
                 [(header::CONTENT_TYPE, "text/plain".to_string())],
-                "key not found".to_string(),
+                "schema updated".to_string(),
+            )),
+            // records already stored don't match the schema
+            Err(DatabaseError::Schema(violations)) => Err(ApiError::SchemaConflict(violations)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // changes the shape of the records in the selected collection, e.g.
+    // {"operation": "RENAME_FIELD", "field": "name", "to": "full_name"}
+    pub fn alter(&mut self, operation: &str, payload: &Value) -> DatabaseResponse {
+        let Value::String(field) = &payload["field"] else {
+            return Err(bad_request("field must be a string"));
+        };
+
+        let migration = match operation {
+            "ADD_FIELD" => Migration::Add(json::to_field(
+                field.as_bytes().to_vec(),
+                &payload["default"],
+                "default",
+            )?),
+            "DROP_FIELD" => Migration::Drop(field.as_bytes().to_vec()),
+            _ => match &payload["to"] {
+                Value::String(to) => {
+                    Migration::Rename(field.as_bytes().to_vec(), to.as_bytes().to_vec())
+                }
+                _ => return Err(bad_request("to must be a string")),
+            },
+        };
+
+        self.db.alter(migration)?;
+        self.persist()?;
+
+        Ok((
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain".to_string())],
+            "schema updated".to_string(),
+        ))
+    }
+
+    // checks a batch of records for ones left behind by schema changes, across all collections,
+    // and returns how many were checked, 0 once every collection is up to date
+    pub fn upgrade_records(&mut self) -> usize {
+        let collections = match self.db.collections() {
+            Ok(collections) => collections,
+            Err(err) => {
+                log::error!("couldnt list collections to upgrade: {err}");
+                return 0;
+            }
+        };
+
+        let mut checked = 0;
+
+        for collection in collections {
+            let result = self
+                .db
+                .use_collection(collection.as_bytes())
+                .and_then(|()| self.db.upgrade_records(UPGRADE_BATCH - checked));
+
+            match result {
+                Ok(count) => checked += count,
+                Err(err) => log::error!("couldnt upgrade records in {collection}: {err}"),
+            }
+
+            if checked == UPGRADE_BATCH {
+                break;
+            }
+        }
+
+        if checked > 0
+            && let Err(err) = self.persist()
+        {
+            log::error!("couldnt persist upgraded records: {err}");
+        }
+
+        checked
+    }
+
+    // indexes a field of the selected collection, e.g.
+    // {"operation": "CREATE_INDEX", "field": "cpr", "unique": true}
+    pub fn create_index(&mut self, payload: &Value) -> DatabaseResponse {
+        let Value::String(field) = &payload["field"] else {
+            return Err(bad_request("field must be a string"));
+        };
+
+        let unique = match &payload["unique"] {
+            Value::Null => false,
+            Value::Bool(unique) => *unique,
+            _ => return Err(bad_request("unique must be a boolean")),
+        };
+
+        self.db.create_index(field.as_bytes(), unique)?;
+        self.persist()?;
+
+        Ok((
+            StatusCode::CREATED,
+            [(header::CONTENT_TYPE, "text/plain".to_string())],
+            format!("indexed {field}"),
+        ))
+    }
+
+    // returns the records where an indexed field holds a value, or lies between two, e.g.
+    // {"operation": "FIND", "field": "age", "from": 18, "to": 65}. both bounds are included and
+    // a bound left out is open
+    pub fn find(&mut self, payload: &Value) -> DatabaseResponse {
+        let Value::String(field) = &payload["field"] else {
+            return Err(bad_request("field must be a string"));
+        };
+
+        let bound = |name: &str| match &payload[name] {
+            Value::Null => Ok(None),
+            value => json::to_field(Vec::new(), value, name).map(Some),
+        };
+
+        let records = match bound("value")? {
+            Some(value) => self.db.find_by(field.as_bytes(), &value)?,
+            None => {
+                let (from, to) = (bound("from")?, bound("to")?);
+
+                self.db.find_range(
+                    field.as_bytes(),
+                    from.as_ref().map_or(Bound::Unbounded, Bound::Included),
+                    to.as_ref().map_or(Bound::Unbounded, Bound::Included),
+                )?
+            }
+        };
+
+        json_response(
+            StatusCode::OK,
+            serde_json::to_string(&records).map_err(JsonError::from)?,
+        )
+    }
+
+    pub fn get_schema(&mut self) -> DatabaseResponse {
+        let schema = self.db.schema()?;
+
+        json_response(
+            StatusCode::OK,
+            schema
+                .map(|schema| schema.to_json())
+                .unwrap_or_default()
+                .to_string(),
+        )
+    }
+
+    pub fn get_record(&mut self, key: &str) -> DatabaseResponse {
+        let key = self.db.parse_key(key)?;
+
+        match self.db.get_json(&key)? {
+            Some(data) => data_response(StatusCode::OK, &data),
+            None => Err(DatabaseError::NotFound.into()),
+        }
+    }
+
+    // stores the body under the key, 201 if it created the record and 200 if it replaced one
+    pub fn put_record(&mut self, key: &str, body: &Value) -> DatabaseResponse {
+        let key = self.db.parse_key(key)?;
+
+        let result = self.db.put_json(&key, body)?;
+        self.persist()?;
+
+        match result {
+            (data, true) => data_response(StatusCode::CREATED, &data),
+            (data, false) => data_response(StatusCode::OK, &data),
+        }
+    }
+
+    pub fn patch_record(&mut self, key: &str, patch: &Value) -> DatabaseResponse {
+        let key = self.db.parse_key(key)?;
+
+        let data = self.db.patch_json(&key, patch)?;
+        self.persist()?;
+
+        data_response(StatusCode::OK, &data)
+    }
+
+    pub fn delete_record(&mut self, key: &str) -> DatabaseResponse {
+        let key = self.db.parse_key(key)?;
+
+        match self.db.delete_json(&key)? {
+            Some(data) => {
+                self.persist()?;
+                data_response(StatusCode::OK, &data)
+            }
+            None => Err(DatabaseError::NotFound.into()),
+        }
+    }
+
+    // lists records by key, from and to are keys like in the record routes and include every
+    // key starting with them
+    pub fn list_records(
+        &mut self,
+        from: Option<&str>,
+        to: Option<&str>,
+        limit: Option<&str>,
+    ) -> DatabaseResponse {
+        let limit = match limit.map(str::parse::<usize>) {
+            None => DEFAULT_LIMIT,
+            Some(Ok(limit)) if limit <= MAX_LIMIT => limit,
+            Some(_) => {
+                return Err(ApiError::BadRequest(format!(
+                    "limit must be a number up to {MAX_LIMIT}"
+                )));
+            }
+        };
+
+        let from = from.map(|from| self.db.parse_key(from)).transpose()?;
+        let to = to.map(|to| self.db.parse_key(to)).transpose()?;
+
+        let records = self.db.range_json(from.as_ref(), to.as_ref(), limit)?;
+
+        json_response(
+            StatusCode::OK,
+            serde_json::to_string(&records).map_err(JsonError::from)?,
+        )
+    }
+
+    // pages through the collection in key order. the cursor in a response picks up after its
+    // last record and holds that record's key, so the server keeps no state between pages
+    pub fn list(&mut self, limit: &Value, cursor: &Value) -> DatabaseResponse {
+        let limit = match limit {
+            Value::Null => DEFAULT_LIMIT,
+            Value::Number(limit) => match limit.as_u64() {
+                Some(limit @ 1..) if limit <= MAX_LIMIT as u64 => limit as usize,
+                _ => {
+                    return Err(ApiError::BadRequest(format!(
+                        "limit must be between 1 and {MAX_LIMIT}"
+                    )));
+                }
+            },
+            _ => return Err(bad_request("limit must be a number")),
+        };
+
+        let collection = self.db.selected().to_vec();
+
+        let after = match cursor {
+            Value::Null => None,
+            Value::String(cursor) => {
+                Some(read_cursor(&collection, cursor).ok_or(ApiError::InvalidCursor)?)
+            }
+            _ => return Err(bad_request("cursor must be a string")),
+        };
+
+        let (records, last) = self.db.list(after.as_deref(), limit)?;
+
+        let next_cursor = last.map(|key| make_cursor(&collection, &key));
+        let records = serde_json::to_value(&records).map_err(JsonError::from)?;
+
+        json_response(
+            StatusCode::OK,
+            json!({"records": records, "next_cursor": next_cursor}).to_string(),
+        )
+    }
+
+    // where a streamed scan starts and stops, from and to are keys like in list_records
+    pub fn scan_bounds(
+        &mut self,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<Bounds, ApiError> {
+        let from = from.map(|from| self.db.parse_key(from)).transpose()?;
+        let to = to.map(|to| self.db.parse_key(to)).transpose()?;
+
+        Ok(self.db.bounds_json(from.as_ref(), to.as_ref())?)
+    }
+
+    // reads the next chunk of a streamed scan as ndjson, with where the chunk after it starts
+    // if there is one
+    pub fn scan_chunk(
+        &mut self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(Vec<u8>, Option<Vec<u8>>), ApiError> {
+        let (records, last) = self.db.page(start, end, SCAN_CHUNK)?;
+
+        let mut chunk = Vec::new();
+
+        for data in records {
+            chunk.extend(data.json().map_err(JsonError::from)?.into_bytes());
+            chunk.push(b'\n');
+        }
+
+        Ok((chunk, last.map(|last| key::after(&last))))
+    }
+
+    // runs the items of a batch in order and reports each one's status and body. items use the
+    // collection of the batch unless they name their own. an atomic batch stops at the first
+    // item that fails, rolls back the writes before it and answers 409. a get of a missing
+    // record answers 404 in its result without failing the batch
+    pub fn batch(&mut self, payload: &Value) -> DatabaseResponse {
+        let Value::Array(items) = &payload["items"] else {
+            return Err(bad_request("missing items field"));
+        };
+
+        if items.len() > MAX_BATCH {
+            return Err(ApiError::BadRequest(format!(
+                "a batch holds at most {MAX_BATCH} items"
+            )));
+        }
+
+        let atomic = match &payload["atomic"] {
+            Value::Null => false,
+            Value::Bool(atomic) => *atomic,
+            _ => return Err(bad_request("atomic must be a boolean")),
+        };
+
+        if atomic {
+            self.db.begin();
+        }
+
+        let mut results = Vec::with_capacity(items.len());
+        let mut failed = false;
+
+        for item in items {
+            if failed {
+                results.push(json!({
+                    "status": StatusCode::FAILED_DEPENDENCY.as_u16(),
+                    "body": {
+                        "code": "not_run",
+                        "message": "not run, an earlier item failed",
+                        "details": null,
+                    },
+                }));
+                continue;
+            }
+
+            let collection = match &item["collection"] {
+                Value::Null => &payload["collection"],
+                collection => collection,
+            };
+
+            let result = self.select(collection).and_then(|()| self.batch_item(item));
+
+            let (status, body) = match result {
+                Ok((status, headers, body)) => match headers[0].1.as_str() {
+                    "application/json" => (
+                        status,
+                        serde_json::from_str(&body).unwrap_or(Value::String(body)),
+                    ),
+                    _ => (status, Value::String(body)),
+                },
+                Err(err) => {
+                    // a get that finds nothing wrote nothing, so it doesn't fail the batch
+                    let miss = err.status() == StatusCode::NOT_FOUND
+                        && item["operation"]
+                            .as_str()
+                            .is_some_and(|operation| operation.eq_ignore_ascii_case("GET"));
+
+                    failed = atomic && !miss;
+                    (err.status(), err.body())
+                }
+            };
+
+            results.push(json!({"status": status.as_u16(), "body": body}));
+        }
+
+        let status = if failed {
+            self.db.rollback()?;
+            StatusCode::CONFLICT
+        } else {
+            self.db.commit();
+            StatusCode::OK
+        };
+
+        self.persist()?;
+
+        json_response(
+            status,
+            json!({"committed": !failed, "results": results}).to_string(),
+        )
+    }
+
+    fn batch_item(&mut self, item: &Value) -> DatabaseResponse {
+        let Value::String(operation) = &item["operation"] else {
+            return Err(bad_request("missing operation field"));
+        };
+
+        match operation.to_uppercase().as_str() {
+            "GET" => self.get_data(&item["key"]),
+            "INSERT" => self.insert_data(&item["data"]),
+            "UPDATE" => self.update_data(&item["data"]),
+            "DELETE" => self.delete_data(&item["key"]),
+            _ => Err(bad_request("invalid batch operation")),
+        }
+    }
+
+    pub fn get_data(&mut self, key: &Value) -> DatabaseResponse {
+        match self.db.get_json(key)? {
+            Some(data) => data_response(StatusCode::OK, &data),
+            None => Err(DatabaseError::NotFound.into()),
+        }
+    }
+
+    pub fn insert_data(&mut self, data: &Value) -> DatabaseResponse {
+        let data = self.db.insert_json(data)?;
+        self.persist()?;
+
+        data_response(StatusCode::CREATED, &data)
+    }
+
+    pub fn update_data(&mut self, data: &Value) -> DatabaseResponse {
+        let data = self.db.update_json(data)?;
+        self.persist()?;
+
+        data_response(StatusCode::OK, &data)
+    }
+
+    pub fn delete_data(&mut self, key: &Value) -> DatabaseResponse {
+        match self.db.delete_json(key)? {
+            Some(data) => {
+                self.persist()?;
+                data_response(StatusCode::OK, &data)
+            }
+            None => Err(DatabaseError::NotFound.into()),
+        }
+    }
+}
+
+// the key settings only shape a new file, so the ones an existing file disagrees with are
+// pointed out rather than ignored without a word
+fn key_mismatch(config: &Config, keys: &[KeyField], generation: KeyGeneration) {
+    let describe = |keytype: KeyType, size: u16| match keytype {
+        KeyType::String => format!("string of {size} bytes"),
+        keytype => schema::type_name(keytype).to_string(),
+    };
+
+    let stored = keys
+        .iter()
+        .map(|key| {
+            format!(
+                "{} ({})",
+                String::from_utf8_lossy(&key.name),
+                describe(key.keytype, key.size)
             )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let configured = format!(
+        "{} ({})",
+        config.key_field,
+        describe(config.keytype.keytype(), config.keytype.size())
+    );
+
+    if stored != configured {
+        log::warn!(
+            "{} is keyed on {stored}, the configured key {configured} only applies to new files",
+            config.database.display()
+        );
+    }
+
+    if generation != config.generation {
+        log::warn!(
+            "{} generates keys with {}, the configured {} only applies to new files",
+            config.database.display(),
+            format!("{generation:?}").to_lowercase(),
+            format!("{:?}", config.generation).to_lowercase()
+        );
+    }
+}
+
+pub fn bad_request(message: &str) -> ApiError {
+    ApiError::BadRequest(message.to_string())
+}
+
+fn json_response(status: StatusCode, json: String) -> DatabaseResponse {
+    Ok((
+        status,
+        [(header::CONTENT_TYPE, "application/json".to_string())],
+        json,
+    ))
+}
+
+fn data_response(status: StatusCode, data: &Data) -> DatabaseResponse {
+    json_response(status, data.json().map_err(JsonError::from)?)
+}
+
+// a cursor is the collection name, its length first, and the key it continues after, so a
+// cursor can't be used to page through another collection
+fn make_cursor(collection: &[u8], key: &[u8]) -> String {
+    let mut cursor = vec![collection.len() as u8];
+    cursor.extend(collection);
+    cursor.extend(key);
+
+    BASE64_URL_SAFE_NO_PAD.encode(cursor)
+}
+
+fn read_cursor(collection: &[u8], cursor: &str) -> Option<Vec<u8>> {
+    let cursor = BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    let (len, rest) = cursor.split_first()?;
+
+    match rest.split_at_checked(*len as usize)? {
+        (name, key) if name == collection => Some(key.to_vec()),
+        _ => None,
+    }
+}
+
+type CollectionSpec = (String, Vec<(Vec<u8>, KeyTypeSize)>, KeyGeneration);
+
+fn collection_spec(payload: &Value) -> Result<CollectionSpec, String> {
+    let Value::String(name) = &payload["collection"] else {
+        return Err("collection must be a string".to_string());
+    };
+
+    let Value::String(key) = &payload["key"] else {
+        return Err("key must be the name of the key field".to_string());
+    };
+
+    let size = match payload["size"].as_u64().map(u16::try_from) {
+        Some(Ok(size)) => Some(size),
+        _ => None,
+    };
+
+    let keytype = keytype(payload["keytype"].as_str().unwrap_or_default(), size)?;
+
+    let generation = match payload["generate"].as_str() {
+        None => KeyGeneration::None,
+        Some(generate) => generation(generate)?,
+    };
+
+    Ok((
+        name.clone(),
+        vec![(key.as_bytes().to_vec(), keytype)],
+        generation,
+    ))
+}
+
+// reads a key type by name, string keys need a size
+pub fn keytype(name: &str, size: Option<u16>) -> Result<KeyTypeSize, String> {
+    match (name.to_lowercase().as_str(), size) {
+        ("uint64", _) => Ok(KeyTypeSize::UInt64),
+        ("timestamp", _) => Ok(KeyTypeSize::Timestamp),
+        ("string", Some(size)) => Ok(KeyTypeSize::String(size)),
+        ("string", None) => Err("string keys need a size".to_string()),
+        _ => Err("keytype must be uint64, timestamp or string".to_string()),
+    }
+}
+
+pub fn generation(name: &str) -> Result<KeyGeneration, String> {
+    match name.to_lowercase().as_str() {
+        "autoincrement" => Ok(KeyGeneration::AutoIncrement),
+        "ulid" => Ok(KeyGeneration::Ulid),
+        "none" => Ok(KeyGeneration::None),
+        _ => Err("generate must be autoincrement, ulid or none".to_string()),
+    }
+}
+
+pub fn lock(handler: &Mutex<DatabaseHandler>) -> Result<MutexGuard<'_, DatabaseHandler>, ApiError> {
+    let started = Instant::now();
+    let locked = handler.lock();
+    metrics::LOCK_WAIT.observe(started.elapsed());
+
+    match locked {
+        Ok(locked) if locked.broken => Err(ApiError::Unavailable),
+        Ok(locked) => Ok(locked),
+        Err(poisoned) => {
+            log::warn!("database lock was poisoned by a panicking request, recovering");
+
+            let mut locked = poisoned.into_inner();
+
+            match locked.recover() {
+                Ok(()) => {
+                    handler.clear_poison();
+                    log::info!("database recovered, resuming");
+                    Ok(locked)
+                }
+                Err(err) => {
+                    log::error!("database recovery failed, refusing requests until restart: {err}");
+                    locked.broken = true;
+                    handler.clear_poison();
+                    Err(ApiError::Unavailable)
+                }
+            }
         }
     }
 }
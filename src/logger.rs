@@ -0,0 +1,25 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+// writes log records at or above the configured level to stderr
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(level: LevelFilter) {
+    log::set_logger(&LOGGER).expect("couldnt set logger");
+    log::set_max_level(level);
+}
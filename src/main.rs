@@ -1,88 +1,565 @@
+mod apierror;
+mod config;
 mod database;
 mod databasehandler;
+mod logger;
+mod metrics;
 
-use crate::databasehandler::KeyType;
+use apierror::ApiError;
+use config::Config;
 use databasehandler::{DatabaseHandler, DatabaseResponse};
-use serde_json::Value;
+use serde_json::{Value, json};
 
 use axum::{
     Json, Router,
-    extract::State,
-    http::{StatusCode, header},
+    body::Body,
+    extract::{Path, Query, State, rejection::JsonRejection},
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
+use futures_util::stream;
+use std::collections::HashMap;
+use std::future::IntoFuture;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tower_http::cors::{Any, CorsLayer};
+use tokio::sync::Notify;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 
 #[tokio::main]
 async fn main() {
-    println!("hosting on localhost:8000");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
+
+    logger::init(config.log_level);
+
+    let database = match DatabaseHandler::open(&config) {
+        Ok(handler) => Arc::new(Mutex::new(handler)),
+        Err(err) => {
+            log::error!("couldnt open {}: {err}", config.database.display());
+            std::process::exit(1);
+        }
+    };
+
+    tokio::spawn(upgrade_records(database.clone()));
+
+    let app = router(database.clone(), &config.cors_origins);
+
+    let listener = match TcpListener::bind(&config.bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("couldnt bind {}: {err}", config.bind);
+            std::process::exit(1);
+        }
+    };
+
+    log::info!("hosting {} on {}", config.database.display(), config.bind);
+
+    let signalled = Arc::new(Notify::new());
+
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(signalled.clone()))
+        .into_future();
+
+    // the server stops accepting connections on the signal and finishes the running requests,
+    // unless they take longer than the timeout
+    tokio::select! {
+        result = server => {
+            if let Err(err) = result {
+                log::error!("server failed: {err}");
+            }
+        }
+        () = drain_timeout(&signalled, config.shutdown_timeout) => {
+            log::warn!(
+                "requests still running after {} seconds, shutting down anyway",
+                config.shutdown_timeout.as_secs()
+            );
+        }
+    }
 
-    // let database = Arc::new(Mutex::new(DatabaseHandler::new_u64()));
-    let database = Arc::new(Mutex::new(DatabaseHandler::new_string()));
-    let address = "localhost:8000".to_string();
+    // writes happen under the lock, so none is left half done once it is taken
+    match databasehandler::lock(&database).map(|mut locked| locked.flush()) {
+        Ok(Ok(())) => log::info!("database flushed, shut down cleanly"),
+        Ok(Err(err)) => log::error!("couldnt flush database: {err}"),
+        Err(err) => log::error!("couldnt flush database: {err}"),
+    }
+}
 
-    let cors = CorsLayer::new().allow_origin(Any);
+fn router(database: Arc<Mutex<DatabaseHandler>>, cors_origins: &[String]) -> Router {
+    let cors = CorsLayer::new().allow_origin(allowed_origins(cors_origins));
 
-    let app = Router::new()
+    Router::new()
         .route("/", post(operation))
         .route("/", get("Hello from bgldb!\n"))
+        .route("/healthz", get(health))
+        .route("/readyz", get(ready))
+        .route("/stats", get(stats))
+        .route("/metrics", get(export_metrics))
+        .route("/records", get(list_records))
+        .route("/scan", get(scan_records))
+        .route(
+            "/records/{key}",
+            get(get_record)
+                .put(put_record)
+                .patch(patch_record)
+                .delete(delete_record),
+        )
         .with_state(database)
-        .layer(cors);
+        .layer(cors)
+}
+
+// resolves on ctrl-c or a terminate signal, like the one docker sends on stop
+async fn shutdown_signal(signalled: Arc<Notify>) {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("couldnt listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("couldnt listen for terminate signal")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
+
+    log::info!("shutting down, waiting for running requests");
+    signalled.notify_one();
+}
+
+async fn drain_timeout(signalled: &Notify, timeout: Duration) {
+    signalled.notified().await;
+    tokio::time::sleep(timeout).await;
+}
+
+// "*" lets any origin in, otherwise only the listed ones
+fn allowed_origins(origins: &[String]) -> AllowOrigin {
+    if origins.iter().any(|origin| origin == "*") {
+        return Any.into();
+    }
+
+    let origins = origins
+        .iter()
+        .map(|origin| match HeaderValue::from_str(origin) {
+            Ok(value) => value,
+            Err(_) => {
+                log::error!("{origin} is not a valid cors origin");
+                std::process::exit(2);
+            }
+        });
+
+    AllowOrigin::list(origins)
+}
+
+type Handler = State<Arc<Mutex<DatabaseHandler>>>;
+type Params = Query<HashMap<String, String>>;
+
+// runs a request against a collection, null is the default one
+fn in_collection(
+    handler: &Mutex<DatabaseHandler>,
+    collection: &Value,
+    request: impl FnOnce(&mut DatabaseHandler) -> DatabaseResponse,
+) -> DatabaseResponse {
+    let mut locked = databasehandler::lock(handler)?;
+    locked.select(collection)?;
+
+    request(&mut locked)
+}
+
+// like in_collection, with the collection named in the query
+fn with_collection(
+    handler: &Mutex<DatabaseHandler>,
+    params: &HashMap<String, String>,
+    request: impl FnOnce(&mut DatabaseHandler) -> DatabaseResponse,
+) -> DatabaseResponse {
+    in_collection(handler, &collection_param(params), request)
+}
+
+fn collection_param(params: &HashMap<String, String>) -> Value {
+    params
+        .get("collection")
+        .map_or(Value::Null, |collection| Value::from(collection.as_str()))
+}
+
+// answers as long as the process is up, without touching the database
+async fn health() -> Json<Value> {
+    Json(json!({"status": "ok"}))
+}
+
+// answers once the database is open and its header reads, and not while it is unavailable
+async fn ready(State(handler): Handler) -> (StatusCode, Json<Value>) {
+    let ready = databasehandler::lock(&handler)
+        .and_then(|mut locked| locked.ready().map_err(ApiError::from));
+
+    match ready {
+        Ok(()) => (StatusCode::OK, Json(json!({"status": "ready"}))),
+        Err(err) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"status": "unavailable", "reason": err.to_string()})),
+        ),
+    }
+}
+
+async fn stats(State(handler): Handler) -> DatabaseResponse {
+    databasehandler::lock(&handler)?.stats()
+}
+
+// counters in the prometheus text format, without the storage gauges while the database is
+// unavailable
+async fn export_metrics(State(handler): Handler) -> Response {
+    let storage = databasehandler::lock(&handler)
+        .ok()
+        .and_then(|mut locked| locked.storage().ok());
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(storage.as_ref()),
+    )
+        .into_response()
+}
+
+async fn get_record(
+    State(handler): Handler,
+    Path(key): Path<String>,
+    Query(params): Params,
+) -> DatabaseResponse {
+    metrics::timed("get_record", || {
+        with_collection(&handler, &params, |locked| locked.get_record(&key))
+    })
+}
+
+async fn put_record(
+    State(handler): Handler,
+    Path(key): Path<String>,
+    Query(params): Params,
+    body: Result<Json<Value>, JsonRejection>,
+) -> DatabaseResponse {
+    metrics::timed("put_record", || {
+        let Json(body) = body?;
+
+        with_collection(&handler, &params, |locked| locked.put_record(&key, &body))
+    })
+}
+
+async fn patch_record(
+    State(handler): Handler,
+    Path(key): Path<String>,
+    Query(params): Params,
+    patch: Result<Json<Value>, JsonRejection>,
+) -> DatabaseResponse {
+    metrics::timed("patch_record", || {
+        let Json(patch) = patch?;
+
+        with_collection(&handler, &params, |locked| {
+            locked.patch_record(&key, &patch)
+        })
+    })
+}
+
+async fn delete_record(
+    State(handler): Handler,
+    Path(key): Path<String>,
+    Query(params): Params,
+) -> DatabaseResponse {
+    metrics::timed("delete_record", || {
+        with_collection(&handler, &params, |locked| locked.delete_record(&key))
+    })
+}
+
+async fn list_records(State(handler): Handler, Query(params): Params) -> DatabaseResponse {
+    metrics::timed("list_records", || {
+        with_collection(&handler, &params, |locked| {
+            locked.list_records(
+                params.get("from").map(String::as_str),
+                params.get("to").map(String::as_str),
+                params.get("limit").map(String::as_str),
+            )
+        })
+    })
+}
+
+// streams the records between from and to, or the whole collection, as ndjson. each chunk is
+// only read once the client has taken the one before it, and the lock is let go in between, so
+// other requests get in and memory stays the same for any number of records
+async fn scan_records(State(handler): Handler, Query(params): Params) -> Response {
+    let collection = collection_param(&params);
+
+    // only reading the bounds is timed, the chunks are read as the client takes them
+    let bounds = metrics::timed("scan", || {
+        databasehandler::lock(&handler).and_then(|mut locked| {
+            locked.select(&collection)?;
+            locked.scan_bounds(
+                params.get("from").map(String::as_str),
+                params.get("to").map(String::as_str),
+            )
+        })
+    });
+
+    let (start, end) = match bounds {
+        Ok(bounds) => bounds,
+        Err(err) => return err.into_response(),
+    };
+
+    // the state is where the next chunk starts, none once the scan is done
+    let chunks = stream::try_unfold(Some(start), move |start| {
+        let handler = handler.clone();
+        let collection = collection.clone();
+        let end = end.clone();
+
+        async move {
+            let Some(start) = start else {
+                return Ok(None);
+            };
+
+            // the collection can be dropped while a scan is between chunks, the body ends early
+            // then as the status is already out
+            let chunk = databasehandler::lock(&handler).and_then(|mut locked| {
+                locked.select(&collection)?;
+                locked.scan_chunk(start.as_deref(), end.as_deref())
+            });
+
+            match chunk {
+                Ok((chunk, Some(next))) => Ok(Some((chunk, Some(Some(next))))),
+                Ok((chunk, None)) => Ok(Some((chunk, None))),
+                Err(err) => Err(std::io::Error::other(err.to_string())),
+            }
+        }
+    });
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(chunks),
+    )
+        .into_response()
+}
+
+// brings records up to date with schema changes in the background, a batch at a time so requests
+// only ever wait for one batch
+async fn upgrade_records(handler: Arc<Mutex<DatabaseHandler>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
 
-    let listener = TcpListener::bind(address).await.unwrap();
+    loop {
+        interval.tick().await;
 
-    axum::serve(listener, app).await.unwrap();
+        loop {
+            let upgraded = match databasehandler::lock(&handler) {
+                Ok(mut locked) => locked.upgrade_records(),
+                Err(_) => 0,
+            };
+
+            if upgraded == 0 {
+                break;
+            }
+
+            tokio::task::yield_now().await;
+        }
+    }
 }
 
 async fn operation(
     State(handler): State<Arc<Mutex<DatabaseHandler>>>,
-    Json(payload): Json<Value>,
+    payload: Result<Json<Value>, JsonRejection>,
 ) -> DatabaseResponse {
-    if let Value::String(operation) = &payload["operation"] {
-        match operation.to_uppercase().as_str() {
-            "GET" => {
-                if let Ok(mut locked) = handler.lock() {
-                    match (&payload["key"], locked.get_keytype()) {
-                        (Value::String(key), KeyType::String) => locked.get_data(key.as_bytes()),
-                        (Value::Number(key), KeyType::UInt64) => {
-                            if let Some(key) = key.as_u64() {
-                                locked.get_data(&key.to_le_bytes())
-                            } else {
-                                (
-                                    StatusCode::BAD_REQUEST,
-                                    [(header::CONTENT_TYPE, "text/plain".to_string())],
-                                    "expected unsigned integer, found negative number".to_string(),
-                                )
-                            }
-                        }
-                        _ => (
-                            StatusCode::BAD_REQUEST,
-                            [(header::CONTENT_TYPE, "text/plain".to_string())],
-                            "wrong keytype".to_string(),
-                        ),
-                    }
-                } else {
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        [(header::CONTENT_TYPE, "text/plain".to_string())],
-                        "fatal database error".to_string(),
-                    )
-                }
-            }
-            "INSERT" => todo!(),
-            _ => (
-                StatusCode::BAD_REQUEST,
-                [(header::CONTENT_TYPE, "text/plain".to_string())],
-                "invalid operation".to_string(),
-            ),
+    let name = match &payload {
+        Ok(Json(payload)) => payload["operation"]
+            .as_str()
+            .unwrap_or_default()
+            .to_lowercase(),
+        Err(_) => String::new(),
+    };
+
+    metrics::timed(&name, || run_operation(&handler, payload))
+}
+
+fn run_operation(
+    handler: &Mutex<DatabaseHandler>,
+    payload: Result<Json<Value>, JsonRejection>,
+) -> DatabaseResponse {
+    let Json(payload) = payload?;
+
+    let Value::String(operation) = &payload["operation"] else {
+        return Err(databasehandler::bad_request("missing operation field"));
+    };
+
+    let collection = &payload["collection"];
+
+    match operation.to_uppercase().as_str() {
+        "GET" => in_collection(handler, collection, |locked| {
+            locked.get_data(&payload["key"])
+        }),
+        "INSERT" => in_collection(handler, collection, |locked| {
+            locked.insert_data(&payload["data"])
+        }),
+        "UPDATE" => in_collection(handler, collection, |locked| {
+            locked.update_data(&payload["data"])
+        }),
+        "DELETE" => in_collection(handler, collection, |locked| {
+            locked.delete_data(&payload["key"])
+        }),
+        "LIST" => in_collection(handler, collection, |locked| {
+            locked.list(&payload["limit"], &payload["cursor"])
+        }),
+        "SET_SCHEMA" => in_collection(handler, collection, |locked| {
+            locked.set_schema(&payload["schema"])
+        }),
+        "GET_SCHEMA" => in_collection(handler, collection, |locked| locked.get_schema()),
+        "CREATE_INDEX" => {
+            in_collection(handler, collection, |locked| locked.create_index(&payload))
         }
-    } else {
-        (
-            StatusCode::BAD_REQUEST,
-            [(header::CONTENT_TYPE, "text/plain".to_string())],
-            "missing operation field".to_string(),
-        )
+        "FIND" => in_collection(handler, collection, |locked| locked.find(&payload)),
+        op @ ("ADD_FIELD" | "DROP_FIELD" | "RENAME_FIELD") => {
+            in_collection(handler, collection, |locked| locked.alter(op, &payload))
+        }
+        "BATCH" => databasehandler::lock(handler)?.batch(&payload),
+        "CREATE_COLLECTION" => databasehandler::lock(handler)?.create_collection(&payload),
+        "DROP_COLLECTION" => databasehandler::lock(handler)?.drop_collection(collection),
+        "LIST_COLLECTIONS" => databasehandler::lock(handler)?.list_collections(),
+        _ => Err(databasehandler::bad_request("invalid operation")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Durability, IndexConfig};
+    use crate::database::KeyTypeSize;
+    use crate::database::page::KeyGeneration;
+    use axum::http::Request;
+    use log::LevelFilter;
+    use tower::ServiceExt;
+
+    // a config for a fresh database file of its own, so tests can run side by side
+    fn config(name: &str) -> Config {
+        let path = std::env::temp_dir().join(format!("bgldb-{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        Config {
+            bind: String::new(),
+            database: path,
+            key_field: "id".to_string(),
+            keytype: KeyTypeSize::UInt64,
+            generation: KeyGeneration::AutoIncrement,
+            keys_given: true,
+            cache_size: 16,
+            durability: Durability::Relaxed,
+            shutdown_timeout: Duration::from_secs(1),
+            cors_origins: vec!["*".to_string()],
+            log_level: LevelFilter::Off,
+            indexes: Vec::new(),
+        }
+    }
+
+    fn post(payload: Value) -> Request<Body> {
+        Request::post("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(payload.to_string()))
+            .unwrap()
+    }
+
+    async fn body(response: Response) -> Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unique_index() {
+        let mut config = config("unique");
+        config.indexes.push(IndexConfig {
+            collection: None,
+            field: "cpr".to_string(),
+            unique: true,
+        });
+
+        let database = Arc::new(Mutex::new(DatabaseHandler::open(&config).unwrap()));
+        let app = router(database.clone(), &config.cors_origins);
+
+        let insert = |cpr: &str| post(json!({"operation": "INSERT", "data": {"cpr": cpr}}));
+
+        let response = app.clone().oneshot(insert("0101009999")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app.clone().oneshot(insert("0101009999")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let body = body(response).await;
+        assert_eq!(body["code"], "unique_violation");
+        assert_eq!(body["details"]["field"], "cpr");
+
+        let put = Request::put("/records/9")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({"cpr": "0101009999"}).to_string()))
+            .unwrap();
+        let response = app.oneshot(put).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // the declared index already exists when the file is opened again
+        drop(database);
+        assert!(DatabaseHandler::open(&config).is_ok());
+
+        std::fs::remove_file(&config.database).unwrap();
+    }
+
+    #[tokio::test]
+    async fn atomic_batch() {
+        let config = config("batch");
+        let database = Arc::new(Mutex::new(DatabaseHandler::open(&config).unwrap()));
+        let app = router(database, &config.cors_origins);
+
+        let batch =
+            |items: Value| post(json!({"operation": "BATCH", "atomic": true, "items": items}));
+
+        // looking up a record that isn't there doesn't undo the rest
+        let response = app
+            .clone()
+            .oneshot(batch(json!([
+                {"operation": "INSERT", "data": {"id": 1}},
+                {"operation": "GET", "key": 2},
+                {"operation": "INSERT", "data": {"id": 3}},
+            ])))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body(response).await;
+        assert_eq!(body["committed"], true);
+        assert_eq!(body["results"][1]["status"], 404);
+
+        // a write that fails does
+        let response = app
+            .clone()
+            .oneshot(batch(json!([
+                {"operation": "INSERT", "data": {"id": 4}},
+                {"operation": "DELETE", "key": 2},
+            ])))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let get = Request::get("/records/4").body(Body::empty()).unwrap();
+        assert_eq!(
+            app.oneshot(get).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        std::fs::remove_file(&config.database).unwrap();
     }
 }
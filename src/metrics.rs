@@ -0,0 +1,356 @@
+use crate::database::stats::Stats;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// NOTE: metrics are plain atomic counters bumped where things happen and only read when
+// /metrics renders them in the prometheus text format. histograms count into fixed buckets and
+// are made cumulative when rendered. storage gauges come from the catalog at render time, with
+// the free page count cached by the database handler as it takes a walk of the free list
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// upper bounds in seconds, from a cached page read to a slow fsync
+const BUCKETS: [f64; 14] = [
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+
+        // observations above the last bucket only show up in +Inf, which is the count
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.nanos.fetch_add(
+            elapsed.as_nanos().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+pub struct Operation {
+    name: &'static str,
+    requests: Counter,
+    errors: Counter,
+    latency: Histogram,
+}
+
+const fn operation(name: &'static str) -> Operation {
+    Operation {
+        name,
+        requests: Counter::new(),
+        errors: Counter::new(),
+        latency: Histogram::new(),
+    }
+}
+
+// the operations of the operation endpoint and the rest routes, anything else counts as invalid
+static OPERATIONS: [Operation; 23] = [
+    operation("get"),
+    operation("insert"),
+    operation("update"),
+    operation("delete"),
+    operation("list"),
+    operation("set_schema"),
+    operation("get_schema"),
+    operation("add_field"),
+    operation("drop_field"),
+    operation("rename_field"),
+    operation("create_index"),
+    operation("find"),
+    operation("batch"),
+    operation("create_collection"),
+    operation("drop_collection"),
+    operation("list_collections"),
+    operation("get_record"),
+    operation("put_record"),
+    operation("patch_record"),
+    operation("delete_record"),
+    operation("list_records"),
+    operation("scan"),
+    operation("invalid"),
+];
+
+pub static PAGE_READS: Counter = Counter::new();
+pub static PAGE_WRITES: Counter = Counter::new();
+pub static CACHE_HITS: Counter = Counter::new();
+pub static CACHE_MISSES: Counter = Counter::new();
+pub static SPLITS: Counter = Counter::new();
+pub static MERGES: Counter = Counter::new();
+pub static LOCK_WAIT: Histogram = Histogram::new();
+pub static FSYNC: Histogram = Histogram::new();
+
+fn find(name: &str) -> &'static Operation {
+    OPERATIONS
+        .iter()
+        .find(|operation| operation.name == name)
+        .unwrap_or(&OPERATIONS[OPERATIONS.len() - 1])
+}
+
+// runs a request and counts it, with how long it took and whether it failed, under its operation
+pub fn timed<T, E>(name: &str, request: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let operation = find(name);
+    let started = Instant::now();
+
+    let result = request();
+
+    operation.requests.inc();
+    operation.latency.observe(started.elapsed());
+
+    if result.is_err() {
+        operation.errors.inc();
+    }
+
+    result
+}
+
+pub fn render(storage: Option<&Stats>) -> String {
+    let mut out = String::new();
+    let seen: Vec<&Operation> = OPERATIONS
+        .iter()
+        .filter(|operation| operation.requests.get() > 0)
+        .collect();
+
+    header(
+        &mut out,
+        "bgldb_requests_total",
+        "requests handled by operation",
+        "counter",
+    );
+    for operation in &seen {
+        let labels = format!("operation=\"{}\"", operation.name);
+        sample(
+            &mut out,
+            "bgldb_requests_total",
+            &labels,
+            operation.requests.get(),
+        );
+    }
+
+    header(
+        &mut out,
+        "bgldb_request_errors_total",
+        "requests answered with an error by operation",
+        "counter",
+    );
+    for operation in &seen {
+        let labels = format!("operation=\"{}\"", operation.name);
+        sample(
+            &mut out,
+            "bgldb_request_errors_total",
+            &labels,
+            operation.errors.get(),
+        );
+    }
+
+    header(
+        &mut out,
+        "bgldb_request_duration_seconds",
+        "time spent handling requests by operation",
+        "histogram",
+    );
+    for operation in &seen {
+        let labels = format!("operation=\"{}\"", operation.name);
+        histogram(
+            &mut out,
+            "bgldb_request_duration_seconds",
+            &labels,
+            &operation.latency,
+        );
+    }
+
+    let counters = [
+        (
+            "bgldb_page_reads_total",
+            "pages read from the file",
+            &PAGE_READS,
+        ),
+        (
+            "bgldb_page_writes_total",
+            "pages written to the file",
+            &PAGE_WRITES,
+        ),
+        (
+            "bgldb_cache_hits_total",
+            "page reads answered by the cache",
+            &CACHE_HITS,
+        ),
+        (
+            "bgldb_cache_misses_total",
+            "page reads that went to the file",
+            &CACHE_MISSES,
+        ),
+        ("bgldb_splits_total", "pages split by inserts", &SPLITS),
+        ("bgldb_merges_total", "pages merged by deletes", &MERGES),
+    ];
+
+    for (name, help, counter) in counters {
+        header(&mut out, name, help, "counter");
+        sample(&mut out, name, "", counter.get());
+    }
+
+    header(
+        &mut out,
+        "bgldb_lock_wait_seconds",
+        "time requests waited for the database lock",
+        "histogram",
+    );
+    histogram(&mut out, "bgldb_lock_wait_seconds", "", &LOCK_WAIT);
+
+    header(
+        &mut out,
+        "bgldb_fsync_duration_seconds",
+        "time spent syncing the file to disk",
+        "histogram",
+    );
+    histogram(&mut out, "bgldb_fsync_duration_seconds", "", &FSYNC);
+
+    // there is no wal size gauge as there is no write-ahead log: writes go straight to the file
+    // and atomic batches are undone from a journal kept in memory
+    if let Some(stats) = storage {
+        let gauges = [
+            (
+                "bgldb_file_size_bytes",
+                "size of the database file",
+                stats.file_size,
+            ),
+            (
+                "bgldb_pages",
+                "pages in the file, free ones included",
+                stats.pages,
+            ),
+            (
+                "bgldb_free_pages",
+                "pages on the free list, counted at most a minute ago",
+                stats.free_pages,
+            ),
+        ];
+
+        for (name, help, value) in gauges {
+            header(&mut out, name, help, "gauge");
+            sample(&mut out, name, "", value);
+        }
+
+        header(&mut out, "bgldb_records", "records by collection", "gauge");
+        for collection in &stats.collections {
+            let labels = format!("collection=\"{}\"", escape(&collection.name));
+            sample(&mut out, "bgldb_records", &labels, collection.records);
+        }
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {name} {help}").expect("couldnt write metrics");
+    writeln!(out, "# TYPE {name} {kind}").expect("couldnt write metrics");
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    match labels {
+        "" => writeln!(out, "{name} {value}"),
+        labels => writeln!(out, "{name}{{{labels}}} {value}"),
+    }
+    .expect("couldnt write metrics");
+}
+
+fn histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let separator = if labels.is_empty() { "" } else { "," };
+    let mut cumulative = 0;
+
+    for (bound, bucket) in BUCKETS.iter().zip(&histogram.buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        let labels = format!("{labels}{separator}le=\"{bound}\"");
+        sample(out, &format!("{name}_bucket"), &labels, cumulative);
+    }
+
+    let count = histogram.count.load(Ordering::Relaxed);
+    let seconds = histogram.nanos.load(Ordering::Relaxed) as f64 / 1e9;
+
+    let inf = format!("{labels}{separator}le=\"+Inf\"");
+    sample(out, &format!("{name}_bucket"), &inf, count);
+    sample(out, &format!("{name}_sum"), labels, seconds);
+    sample(out, &format!("{name}_count"), labels, count);
+}
+
+// label values are quoted, so quotes, backslashes and newlines in them are escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let latency = Histogram::new();
+
+        latency.observe(Duration::from_micros(5));
+        latency.observe(Duration::from_millis(3));
+        latency.observe(Duration::from_secs(2));
+
+        let mut out = String::new();
+        super::histogram(&mut out, "latency", "operation=\"get\"", &latency);
+
+        // buckets are cumulative and +Inf holds everything
+        assert!(out.contains("latency_bucket{operation=\"get\",le=\"0.00001\"} 1\n"));
+        assert!(out.contains("latency_bucket{operation=\"get\",le=\"0.0025\"} 1\n"));
+        assert!(out.contains("latency_bucket{operation=\"get\",le=\"0.005\"} 2\n"));
+        assert!(out.contains("latency_bucket{operation=\"get\",le=\"1\"} 2\n"));
+        assert!(out.contains("latency_bucket{operation=\"get\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_count{operation=\"get\"} 3\n"));
+        assert!(out.contains("latency_sum{operation=\"get\"} 2.003005\n"));
+    }
+
+    #[test]
+    fn timed() {
+        let before = find("drop_field").requests.get();
+        let errors = find("drop_field").errors.get();
+
+        let _ = super::timed("drop_field", || Ok::<(), ()>(()));
+        let _ = super::timed("drop_field", || Err::<(), ()>(()));
+
+        assert_eq!(find("drop_field").requests.get(), before + 2);
+        assert_eq!(find("drop_field").errors.get(), errors + 1);
+        assert_eq!(find("no such operation").name, "invalid");
+
+        assert!(render(None).contains("bgldb_requests_total{operation=\"drop_field\"}"));
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}